@@ -0,0 +1,297 @@
+use anyhow::Result;
+use rocksdb::{Options, SstFileWriter};
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Bulk loading errors
+#[derive(Error, Debug)]
+pub enum BulkLoadError {
+    /// The records were announced as sorted but are not
+    #[error("records are not sorted by key, {key} came after a greater key")]
+    UnsortedInput { key: Value },
+    /// The records were announced as sorted but a key is repeated
+    #[error("records are not sorted by key, {key} is repeated")]
+    DuplicateKey { key: Value },
+    /// Loaded records share indexed values with other records in a unique index
+    #[error("records {keys:?} share indexed values in unique index {index_name}")]
+    UniqueViolation { index_name: String, keys: Vec<Value> },
+}
+
+/// Options for bulk loading
+#[derive(Debug, Clone)]
+pub struct BulkLoadOptions {
+    /// The records are already sorted by serialized key without duplicates, so the data does not need to be sorted again
+    pub sorted: bool,
+    /// The number of bytes each sort keeps in memory before spilling a sorted run to disk
+    pub memory_budget: usize,
+}
+
+impl Default for BulkLoadOptions {
+    fn default() -> Self {
+        BulkLoadOptions {
+            sorted: false,
+            memory_budget: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A key value pair of raw bytes
+type KV = (Vec<u8>, Vec<u8>);
+
+/// Sorts key value pairs, spilling sorted runs to disk once the memory budget is exceeded
+/// When the same key is pushed several times, the last value wins
+pub(crate) struct ExternalSorter {
+    /// The folder where runs are spilled
+    dir: PathBuf,
+    /// The prefix for run file names
+    name: String,
+    /// The memory budget in bytes
+    budget: usize,
+    /// The entries in memory
+    entries: Vec<KV>,
+    /// The size in bytes of the entries in memory
+    size: usize,
+    /// The runs spilled to disk, in order
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    /// Creates a new sorter
+    /// # Arguments
+    /// * `dir` - the folder where runs are spilled
+    /// * `name` - the prefix for run file names, must be unique in the folder
+    /// * `budget` - the memory budget in bytes
+    pub(crate) fn new<P: AsRef<Path>, N: Into<String>>(dir: P, name: N, budget: usize) -> Self {
+        ExternalSorter {
+            dir: dir.as_ref().to_path_buf(),
+            name: name.into(),
+            budget,
+            entries: vec![],
+            size: 0,
+            runs: vec![],
+        }
+    }
+
+    /// Adds a key value pair
+    pub(crate) fn push(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.size += key.len() + value.len();
+        self.entries.push((key, value));
+        if self.size > self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the entries in memory as a sorted run
+    fn spill(&mut self) -> Result<()> {
+        let entries = sort_entries(std::mem::take(&mut self.entries));
+        self.size = 0;
+        let path = self
+            .dir
+            .join(format!("{}_{}.run", self.name, self.runs.len()));
+        let mut w = BufWriter::new(File::create(&path)?);
+        for (k, v) in entries.iter() {
+            write_bytes(&mut w, k)?;
+            write_bytes(&mut w, v)?;
+        }
+        w.flush()?;
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// Returns an iterator over all entries, sorted by key and without duplicate keys
+    pub(crate) fn into_sorted(self) -> Result<SortedIter> {
+        let mut readers = vec![];
+        for p in self.runs.iter() {
+            readers.push(RunReader::File(BufReader::new(File::open(p)?)));
+        }
+        readers.push(RunReader::Memory(sort_entries(self.entries).into_iter()));
+        SortedIter::new(readers, self.runs)
+    }
+}
+
+/// Sorts entries by key, keeping only the last value pushed for each key
+fn sort_entries(mut entries: Vec<KV>) -> Vec<KV> {
+    // stable sort keeps the push order of equal keys
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut v: Vec<KV> = Vec::with_capacity(entries.len());
+    for e in entries {
+        match v.last_mut() {
+            Some(l) if l.0 == e.0 => *l = e,
+            _ => v.push(e),
+        }
+    }
+    v
+}
+
+/// Writes a length prefixed byte array
+pub(crate) fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> Result<()> {
+    w.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+/// Reads a length prefixed byte array, None at the end of the input
+pub(crate) fn read_bytes<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut l = [0u8; 4];
+    if let Err(e) = r.read_exact(&mut l) {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e.into());
+    }
+    let mut v = vec![0u8; u32::from_le_bytes(l) as usize];
+    r.read_exact(&mut v)?;
+    Ok(Some(v))
+}
+
+/// A sorted run, either spilled or still in memory
+enum RunReader {
+    File(BufReader<File>),
+    Memory(std::vec::IntoIter<KV>),
+}
+
+impl RunReader {
+    fn next_entry(&mut self) -> Result<Option<KV>> {
+        match self {
+            RunReader::File(r) => match read_bytes(r)? {
+                Some(k) => Ok(read_bytes(r)?.map(|v| (k, v))),
+                None => Ok(None),
+            },
+            RunReader::Memory(it) => Ok(it.next()),
+        }
+    }
+}
+
+/// Merges sorted runs, later runs winning on duplicate keys
+pub(crate) struct SortedIter {
+    readers: Vec<RunReader>,
+    heads: Vec<Option<KV>>,
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    files: Vec<PathBuf>,
+}
+
+impl SortedIter {
+    fn new(mut readers: Vec<RunReader>, files: Vec<PathBuf>) -> Result<Self> {
+        let mut heads = vec![];
+        let mut heap = BinaryHeap::new();
+        for (ix, r) in readers.iter_mut().enumerate() {
+            let head = r.next_entry()?;
+            if let Some((k, _)) = &head {
+                heap.push(Reverse((k.clone(), ix)));
+            }
+            heads.push(head);
+        }
+        Ok(SortedIter {
+            readers,
+            heads,
+            heap,
+            files,
+        })
+    }
+
+    /// Takes the head of the given run and reads its next entry
+    fn advance(&mut self, ix: usize) -> Result<Option<KV>> {
+        let head = self.heads[ix].take();
+        let next = self.readers[ix].next_entry()?;
+        if let Some((k, _)) = &next {
+            self.heap.push(Reverse((k.clone(), ix)));
+        }
+        self.heads[ix] = next;
+        Ok(head)
+    }
+
+    /// Returns the next entry
+    pub(crate) fn next_entry(&mut self) -> Result<Option<KV>> {
+        if let Some(Reverse((k, ix))) = self.heap.pop() {
+            let mut last = ix;
+            let mut entry = self.advance(ix)?;
+            while let Some(Reverse((k2, ix2))) = self.heap.peek() {
+                if *k2 != k {
+                    break;
+                }
+                let ix2 = *ix2;
+                self.heap.pop();
+                let e2 = self.advance(ix2)?;
+                if ix2 > last {
+                    last = ix2;
+                    entry = e2;
+                }
+            }
+            return Ok(entry);
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for SortedIter {
+    fn drop(&mut self) {
+        for f in self.files.iter() {
+            let _ = fs::remove_file(f);
+        }
+    }
+}
+
+/// Writes sorted entries into a SST file, returning None if there was nothing to write
+/// # Arguments
+/// * `path` - the SST file path
+/// * `entries` - the sorted entries
+/// * `inspect` - called on each entry before it is written
+pub(crate) fn write_sst<P, F>(path: P, entries: &mut SortedIter, mut inspect: F) -> Result<Option<PathBuf>>
+where
+    P: AsRef<Path>,
+    F: FnMut(&[u8], &[u8]) -> Result<()>,
+{
+    let mut next = entries.next_entry()?;
+    if next.is_none() {
+        return Ok(None);
+    }
+    let opts = Options::default();
+    let mut writer = SstFileWriter::create(&opts);
+    writer.open(path.as_ref())?;
+    while let Some((k, v)) = next {
+        inspect(&k, &v)?;
+        writer.put(k, v)?;
+        next = entries.next_entry()?;
+    }
+    writer.finish()?;
+    Ok(Some(path.as_ref().to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_sort() -> Result<()> {
+        let dir = Path::new("test_external_sort.tmp");
+        fs::create_dir_all(dir)?;
+        let mut sorter = ExternalSorter::new(dir, "test", 64);
+        for i in (0..100u32).rev() {
+            sorter.push(format!("{:03}", i % 50).into_bytes(), i.to_le_bytes().to_vec())?;
+        }
+        assert_eq!(true, sorter.runs.len() > 1);
+        let mut it = sorter.into_sorted()?;
+        let mut v = vec![];
+        while let Some(e) = it.next_entry()? {
+            v.push(e);
+        }
+        drop(it);
+        assert_eq!(50, v.len());
+        for (i, (k, val)) in v.iter().enumerate() {
+            assert_eq!(format!("{:03}", i).into_bytes(), *k);
+            // the last pushed value wins
+            assert_eq!((i as u32).to_le_bytes().to_vec(), *val);
+        }
+        assert_eq!(0, fs::read_dir(dir)?.count());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
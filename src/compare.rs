@@ -0,0 +1,581 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::{cmp::Ordering, convert::TryFrom};
+
+/// A function giving a total order on JSON values
+pub type ValueComparator<'a> = Box<dyn Fn(&Value, &Value) -> Ordering + 'a>;
+
+/// The rank of each JSON type: null < booleans < numbers < strings < arrays < objects
+fn type_rank(v: &Value) -> u8 {
+    match v {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+/// Compares numbers by value, so that 1 and 1.0 are equal and 2 comes before 10
+fn compare_numbers(a: &Number, b: &Number) -> Ordering {
+    if let (Some(i1), Some(i2)) = (a.as_i64(), b.as_i64()) {
+        return i1.cmp(&i2);
+    }
+    if let (Some(u1), Some(u2)) = (a.as_u64(), b.as_u64()) {
+        return u1.cmp(&u2);
+    }
+    if a.is_f64() || b.is_f64() {
+        // JSON numbers are always finite
+        let f1 = a.as_f64().unwrap_or_default();
+        let f2 = b.as_f64().unwrap_or_default();
+        return f1.partial_cmp(&f2).unwrap_or(Ordering::Equal);
+    }
+    // one negative integer and one integer too big for i64
+    if a.is_i64() {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+/// Compares two JSON values with the default order: values of different types are ordered by type,
+/// numbers by value, strings by their bytes, arrays element by element, and objects by their entries sorted by key
+/// # Arguments
+/// * `a` - the first value
+/// * `b` - the second value
+pub fn compare_values(a: &Value, b: &Value) -> Ordering {
+    compare_values_with(a, b, &|s1: &str, s2: &str| s1.cmp(s2))
+}
+
+/// Compares two JSON values ignoring the case of strings
+/// # Arguments
+/// * `a` - the first value
+/// * `b` - the second value
+pub fn compare_case_insensitive(a: &Value, b: &Value) -> Ordering {
+    compare_values_with(a, b, &|s1: &str, s2: &str| s1.to_lowercase().cmp(&s2.to_lowercase()))
+}
+
+/// Compares two JSON values like `compare_values`, but with a custom order for strings, for example to use
+/// a locale-aware collation. Object keys are always compared by their bytes
+/// # Arguments
+/// * `a` - the first value
+/// * `b` - the second value
+/// * `strings` - the function comparing strings
+pub fn compare_values_with<F>(a: &Value, b: &Value, strings: &F) -> Ordering
+where
+    F: Fn(&str, &str) -> Ordering,
+{
+    match (a, b) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Bool(b1), Value::Bool(b2)) => b1.cmp(b2),
+        (Value::Number(n1), Value::Number(n2)) => compare_numbers(n1, n2),
+        (Value::String(s1), Value::String(s2)) => strings(s1, s2),
+        (Value::Array(a1), Value::Array(a2)) => {
+            for (v1, v2) in a1.iter().zip(a2) {
+                let o = compare_values_with(v1, v2, strings);
+                if o != Ordering::Equal {
+                    return o;
+                }
+            }
+            a1.len().cmp(&a2.len())
+        }
+        (Value::Object(m1), Value::Object(m2)) => {
+            let mut e1: Vec<(&String, &Value)> = m1.iter().collect();
+            let mut e2: Vec<(&String, &Value)> = m2.iter().collect();
+            e1.sort_by(|x, y| x.0.cmp(y.0));
+            e2.sort_by(|x, y| x.0.cmp(y.0));
+            for ((k1, v1), (k2, v2)) in e1.iter().zip(&e2) {
+                let o = k1.cmp(k2).then_with(|| compare_values_with(v1, v2, strings));
+                if o != Ordering::Equal {
+                    return o;
+                }
+            }
+            e1.len().cmp(&e2.len())
+        }
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+/// The predefined orders, usable from scripts
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Collation {
+    /// The default order of `compare_values`
+    Binary,
+    /// Strings are compared ignoring case
+    CaseInsensitive,
+}
+
+impl Collation {
+    /// Returns the comparator for this collation
+    pub fn comparator<'a>(self) -> ValueComparator<'a> {
+        match self {
+            Collation::Binary => Box::new(compare_values),
+            Collation::CaseInsensitive => Box::new(compare_case_insensitive),
+        }
+    }
+}
+
+/// A canonical form of a JSON value, to hash values and test them for equality quickly
+/// Objects are equal whatever the order of their fields. Integers and floats are different numbers, unless
+/// numbers are matched by value, in which case 1 and 1.0 are equal
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CanonicalValue(Vec<u8>);
+
+impl CanonicalValue {
+    /// Builds the canonical form of a value
+    /// # Arguments
+    /// * `value` - the value
+    /// * `numbers_by_value` - true to match numbers by value whatever their type
+    pub fn new(value: &Value, numbers_by_value: bool) -> Self {
+        let mut bytes = vec![];
+        write_canonical(value, numbers_by_value, &mut bytes);
+        CanonicalValue(bytes)
+    }
+
+    /// Rebuilds a canonical form from its bytes
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> Self {
+        CanonicalValue(bytes)
+    }
+
+    /// Returns the bytes of the canonical form
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Writes a length, so that nested values cannot be confused
+fn write_len(len: usize, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(len as u64).to_be_bytes());
+}
+
+/// Writes the canonical form of a number
+fn write_number(n: &Number, numbers_by_value: bool, out: &mut Vec<u8>) {
+    if let Some(i) = n.as_i64() {
+        out.push(b'i');
+        out.extend_from_slice(&i.to_be_bytes());
+    } else if let Some(u) = n.as_u64() {
+        out.push(b'u');
+        out.extend_from_slice(&u.to_be_bytes());
+    } else {
+        let f = n.as_f64().unwrap_or_default();
+        if numbers_by_value && f.fract() == 0.0 {
+            if f >= i64::MIN as f64 && f < i64::MAX as f64 {
+                out.push(b'i');
+                out.extend_from_slice(&(f as i64).to_be_bytes());
+                return;
+            }
+            if f >= 0.0 && f < u64::MAX as f64 {
+                out.push(b'u');
+                out.extend_from_slice(&(f as u64).to_be_bytes());
+                return;
+            }
+        }
+        // 0.0 and -0.0 are equal
+        let f = if f == 0.0 { 0.0 } else { f };
+        out.push(b'f');
+        out.extend_from_slice(&f.to_bits().to_be_bytes());
+    }
+}
+
+/// Writes the canonical form of a value: a type tag, then the content, with lengths for strings and containers
+fn write_canonical(value: &Value, numbers_by_value: bool, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0),
+        Value::Bool(b) => {
+            out.push(1);
+            out.push(*b as u8);
+        }
+        Value::Number(n) => {
+            out.push(2);
+            write_number(n, numbers_by_value, out);
+        }
+        Value::String(s) => {
+            out.push(3);
+            write_len(s.len(), out);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(a) => {
+            out.push(4);
+            write_len(a.len(), out);
+            for v in a {
+                write_canonical(v, numbers_by_value, out);
+            }
+        }
+        Value::Object(m) => {
+            out.push(5);
+            write_len(m.len(), out);
+            let mut entries: Vec<(&String, &Value)> = m.iter().collect();
+            entries.sort_by(|x, y| x.0.cmp(y.0));
+            for (k, v) in entries {
+                write_len(k.len(), out);
+                out.extend_from_slice(k.as_bytes());
+                write_canonical(v, numbers_by_value, out);
+            }
+        }
+    }
+}
+
+/// The tags of the order-preserving encoding, ordered like the types in `compare_values`
+/// `ORDERED_END` closes arrays and objects, so that a container sorts before any longer container it starts
+const ORDERED_END: u8 = 1;
+const ORDERED_NULL: u8 = 2;
+const ORDERED_FALSE: u8 = 3;
+const ORDERED_TRUE: u8 = 4;
+const ORDERED_NUMBER: u8 = 5;
+const ORDERED_STRING: u8 = 6;
+const ORDERED_ARRAY: u8 = 7;
+const ORDERED_OBJECT: u8 = 8;
+/// Starts each entry of an object, after the object tag
+const ORDERED_ENTRY: u8 = 2;
+/// The kinds of the numbers of a value, in the trailer of its encoding
+const ORDERED_INTEGER: u8 = 2;
+const ORDERED_FLOAT: u8 = 3;
+/// Comes after any trailer, so that `encoding + ORDERED_AFTER` follows the encodings of all the equal values
+pub(crate) const ORDERED_AFTER: u8 = 4;
+
+/// Encodes a value so that the encodings of two values compare byte by byte like `compare_values` compares the
+/// values, except that integers too big for a float are ordered exactly. The encoding never contains a 0 byte, so
+/// encoded values can be separated by 0 and still sort by their first value, then by the next one, and so on
+/// The encoding ends with a trailer telling which numbers are floats, so that 1 and 1.0 keep their type: values
+/// equal for `compare_values` only differ by their trailer, and start with the encoding of `encode_ordered_bound`
+/// # Arguments
+/// * `value` - the value
+pub(crate) fn encode_ordered(value: &Value) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut kinds = vec![];
+    write_ordered(value, &mut bytes, &mut kinds);
+    bytes.extend(kinds);
+    bytes
+}
+
+/// Encodes a value without its trailer, which sorts before the encodings of all the values equal to it
+/// # Arguments
+/// * `value` - the value
+pub(crate) fn encode_ordered_bound(value: &Value) -> Vec<u8> {
+    let mut bytes = vec![];
+    write_ordered(value, &mut bytes, &mut vec![]);
+    bytes
+}
+
+/// Decodes a value encoded by `encode_ordered`, returning None if the bytes are not a valid encoding
+/// # Arguments
+/// * `bytes` - the encoded value
+pub(crate) fn decode_ordered(bytes: &[u8]) -> Option<Value> {
+    let mut reader = OrderedReader { bytes, pos: 0 };
+    let mut value = reader.value()?;
+    let mut kinds = bytes[reader.pos..].iter();
+    if set_number_kinds(&mut value, &mut kinds) && kinds.next().is_none() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Turns the numbers of a decoded value into floats according to the trailer, in the order they were written
+/// Returns false if the trailer is too short or invalid
+fn set_number_kinds<'a, I: Iterator<Item = &'a u8>>(value: &mut Value, kinds: &mut I) -> bool {
+    match value {
+        Value::Number(n) => match kinds.next() {
+            Some(&ORDERED_INTEGER) => true,
+            Some(&ORDERED_FLOAT) => {
+                if let Some(f) = n.as_f64().and_then(Number::from_f64) {
+                    *n = f;
+                }
+                true
+            }
+            _ => false,
+        },
+        Value::Array(a) => a.iter_mut().all(|v| set_number_kinds(v, kinds)),
+        Value::Object(m) => {
+            let mut entries: Vec<(&String, &mut Value)> = m.iter_mut().collect();
+            entries.sort_by(|x, y| x.0.cmp(y.0));
+            entries.into_iter().all(|(_, v)| set_number_kinds(v, kinds))
+        }
+        _ => true,
+    }
+}
+
+/// Writes bytes, escaping the 0 and 1 bytes so that the escaped bytes keep their order and the `[1, 1]`
+/// terminator sorts before any of them
+fn write_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+    for b in bytes {
+        match b {
+            0 => out.extend_from_slice(&[1, 2]),
+            1 => out.extend_from_slice(&[1, 3]),
+            b => out.push(*b),
+        }
+    }
+}
+
+/// Writes a string, followed by a terminator
+fn write_ordered_string(s: &str, out: &mut Vec<u8>) {
+    write_escaped(s.as_bytes(), out);
+    out.extend_from_slice(&[1, 1]);
+}
+
+/// The exact value of an integral number, when it fits in 128 bits
+fn integral_value(n: &Number, f: f64) -> Option<i128> {
+    if let Some(i) = n.as_i64() {
+        Some(i128::from(i))
+    } else if let Some(u) = n.as_u64() {
+        Some(i128::from(u))
+    } else if f.fract() == 0.0 && f.abs() < 2f64.powi(127) {
+        Some(f as i128)
+    } else {
+        None
+    }
+}
+
+/// Writes a number: its value as a float, with the sign bit flipped for positive numbers and all bits flipped for
+/// negative numbers so that the bytes sort like the floats. Integral numbers are followed by their exact value,
+/// which orders integers sharing the same float
+fn write_ordered_number(n: &Number, out: &mut Vec<u8>, kinds: &mut Vec<u8>) {
+    kinds.push(if n.is_f64() { ORDERED_FLOAT } else { ORDERED_INTEGER });
+    let f = n.as_f64().unwrap_or_default();
+    // 0.0 and -0.0 are equal
+    let f = if f == 0.0 { 0.0 } else { f };
+    let bits = f.to_bits();
+    let bits = if bits >> 63 == 0 { bits ^ (1 << 63) } else { !bits };
+    write_escaped(&bits.to_be_bytes(), out);
+    if let Some(i) = integral_value(n, f) {
+        write_escaped(&((i as u128) ^ (1 << 127)).to_be_bytes(), out);
+    }
+}
+
+/// Writes the order-preserving encoding of a value: a type tag, then the content
+/// The kind of each number is added to `kinds`, to write in the trailer
+fn write_ordered(value: &Value, out: &mut Vec<u8>, kinds: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(ORDERED_NULL),
+        Value::Bool(false) => out.push(ORDERED_FALSE),
+        Value::Bool(true) => out.push(ORDERED_TRUE),
+        Value::Number(n) => {
+            out.push(ORDERED_NUMBER);
+            write_ordered_number(n, out, kinds);
+        }
+        Value::String(s) => {
+            out.push(ORDERED_STRING);
+            write_ordered_string(s, out);
+        }
+        Value::Array(a) => {
+            out.push(ORDERED_ARRAY);
+            for v in a {
+                write_ordered(v, out, kinds);
+            }
+            out.push(ORDERED_END);
+        }
+        Value::Object(m) => {
+            out.push(ORDERED_OBJECT);
+            let mut entries: Vec<(&String, &Value)> = m.iter().collect();
+            entries.sort_by(|x, y| x.0.cmp(y.0));
+            for (k, v) in entries {
+                out.push(ORDERED_ENTRY);
+                write_ordered_string(k, out);
+                write_ordered(v, out, kinds);
+            }
+            out.push(ORDERED_END);
+        }
+    }
+}
+
+/// Reads values written by `write_ordered`
+struct OrderedReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> OrderedReader<'a> {
+    /// Reads a raw byte
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    /// Reads the next raw byte without consuming it
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    /// Reads escaped bytes up to a terminator, or up to the given length
+    fn escaped(&mut self, len: Option<usize>) -> Option<Vec<u8>> {
+        let mut bytes = vec![];
+        while len != Some(bytes.len()) {
+            match self.byte()? {
+                1 => match self.byte()? {
+                    1 if len.is_none() => return Some(bytes),
+                    2 => bytes.push(0),
+                    3 => bytes.push(1),
+                    _ => return None,
+                },
+                b => bytes.push(b),
+            }
+        }
+        Some(bytes)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.escaped(None)?).ok()
+    }
+
+    fn number(&mut self) -> Option<Number> {
+        let mut bits = [0; 8];
+        bits.copy_from_slice(&self.escaped(Some(8))?);
+        let bits = u64::from_be_bytes(bits);
+        let bits = if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits };
+        let f = f64::from_bits(bits);
+        if f.fract() == 0.0 && f.abs() < 2f64.powi(127) {
+            let mut exact = [0; 16];
+            exact.copy_from_slice(&self.escaped(Some(16))?);
+            let i = (u128::from_be_bytes(exact) ^ (1 << 127)) as i128;
+            if let Ok(i) = i64::try_from(i) {
+                return Some(Number::from(i));
+            }
+            if let Ok(u) = u64::try_from(i) {
+                return Some(Number::from(u));
+            }
+        }
+        Number::from_f64(f)
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        match self.byte()? {
+            ORDERED_NULL => Some(Value::Null),
+            ORDERED_FALSE => Some(Value::Bool(false)),
+            ORDERED_TRUE => Some(Value::Bool(true)),
+            ORDERED_NUMBER => self.number().map(Value::Number),
+            ORDERED_STRING => self.string().map(Value::String),
+            ORDERED_ARRAY => {
+                let mut a = vec![];
+                while self.peek()? != ORDERED_END {
+                    a.push(self.value()?);
+                }
+                self.pos += 1;
+                Some(Value::Array(a))
+            }
+            ORDERED_OBJECT => {
+                let mut m = Map::new();
+                while self.byte()? == ORDERED_ENTRY {
+                    let k = self.string()?;
+                    m.insert(k, self.value()?);
+                }
+                Some(Value::Object(m))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(Ordering::Less, compare_values(&json!(2), &json!(10)));
+        assert_eq!(Ordering::Equal, compare_values(&json!(1), &json!(1.0)));
+        assert_eq!(Ordering::Less, compare_values(&json!(-1), &json!(u64::MAX)));
+        assert_eq!(Ordering::Less, compare_values(&json!(1.5), &json!(2)));
+        assert_eq!(Ordering::Less, compare_values(&json!(null), &json!(false)));
+        assert_eq!(Ordering::Less, compare_values(&json!(10), &json!("1")));
+        assert_eq!(Ordering::Less, compare_values(&json!(["a", 2]), &json!(["a", 10])));
+        assert_eq!(Ordering::Less, compare_values(&json!(["a"]), &json!(["a", 1])));
+        assert_eq!(
+            Ordering::Equal,
+            compare_values(&json!({"b": 1, "a": 2}), &json!({"a": 2.0, "b": 1}))
+        );
+        assert_eq!(Ordering::Less, compare_values(&json!("B"), &json!("a")));
+        assert_eq!(Ordering::Greater, compare_case_insensitive(&json!("B"), &json!("a")));
+        assert_eq!(
+            Ordering::Less,
+            compare_case_insensitive(&json!(["a", "B"]), &json!(["A", "c"]))
+        );
+    }
+
+    #[test]
+    fn test_canonical() {
+        let c = |v: Value| CanonicalValue::new(&v, false);
+        let n = |v: Value| CanonicalValue::new(&v, true);
+        assert_ne!(c(json!(1)), c(json!(1.0)));
+        assert_eq!(n(json!(1)), n(json!(1.0)));
+        assert_eq!(n(json!([1, {"a": -2}])), n(json!([1.0, {"a": -2.0}])));
+        assert_ne!(n(json!(1)), n(json!(1.5)));
+        assert_eq!(c(json!(0.0)), c(json!(-0.0)));
+        assert_eq!(
+            c(serde_json::from_str("{\"b\": 1, \"a\": [2]}").unwrap()),
+            c(serde_json::from_str("{\"a\": [2], \"b\": 1}").unwrap())
+        );
+        assert_ne!(c(json!(["ab", "c"])), c(json!(["a", "bc"])));
+        assert_ne!(c(json!("1")), c(json!(1)));
+    }
+
+    #[test]
+    fn test_ordered() {
+        let values = vec![
+            json!(null),
+            json!(false),
+            json!(true),
+            json!(-1e300),
+            json!(i64::MIN),
+            json!(-1.5),
+            json!(-1),
+            json!(0),
+            json!(0.5),
+            json!(1),
+            json!(1.0),
+            json!(2),
+            json!(10),
+            json!(9007199254740992u64),
+            json!(9007199254740993u64),
+            json!(i64::MAX),
+            json!(u64::MAX),
+            json!(1e300),
+            json!(""),
+            json!("\u{0}"),
+            json!("\u{1}"),
+            json!("a"),
+            json!("a\u{0}"),
+            json!("ab"),
+            json!("b"),
+            json!([]),
+            json!([null]),
+            json!([1]),
+            json!([1, 2]),
+            json!([1.0, 2]),
+            json!([2]),
+            json!(["a"]),
+            json!({}),
+            json!({"a": 1}),
+            json!({"a": 2}),
+            json!({"a": 2, "b": 0}),
+            json!({"b": 0}),
+        ];
+        for v in values.iter() {
+            let e = encode_ordered(v);
+            assert_eq!(false, e.contains(&0));
+            assert_eq!(Some(v.clone()), decode_ordered(&e));
+        }
+        for w in values.windows(2) {
+            assert_eq!(Ordering::Less, encode_ordered(&w[0]).cmp(&encode_ordered(&w[1])), "{} < {}", w[0], w[1]);
+        }
+        // equal values only differ by their trailer
+        let bound = encode_ordered_bound(&json!([1, {"a": 2}]));
+        let mut after = bound.clone();
+        after.push(ORDERED_AFTER);
+        for v in [json!([1, {"a": 2}]), json!([1.0, {"a": 2.0}])].iter() {
+            assert_eq!(true, encode_ordered(v).starts_with(&bound));
+            assert_eq!(Ordering::Less, encode_ordered(v).cmp(&after));
+        }
+        assert_eq!(encode_ordered(&json!(0.0)), encode_ordered(&json!(-0.0)));
+        assert_eq!(Some(json!(3.0)), decode_ordered(&encode_ordered(&json!(3.0))));
+        assert_eq!(Some(json!(u64::MAX)), decode_ordered(&encode_ordered(&json!(u64::MAX))));
+        // a value followed by the 0 separator sorts before any value it is a prefix of
+        let mut short = encode_ordered(&json!("a"));
+        short.push(0);
+        assert_eq!(Ordering::Less, short.cmp(&encode_ordered(&json!("a\u{0}"))));
+        assert_eq!(None, decode_ordered(&[ORDERED_STRING, b'a']));
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::Result;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Tag byte written in front of encrypted values, distinct from the CBOR tag and from any JSON text
+const ENCRYPTED_TAG: u8 = 0xC2;
+
+/// The size in bytes of keys
+const KEY_SIZE: usize = 32;
+
+/// The size in bytes of nonces
+const NONCE_SIZE: usize = 12;
+
+/// Encryption errors
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    /// An encrypted record type is used without a key provider
+    #[error("no key provider to encrypt or decrypt values of record type {rec_type}")]
+    NoKeyProvider { rec_type: String },
+    /// The key provider returned a key of the wrong size, or a key id longer than 255 bytes
+    #[error("key {key_id} is not {size} bytes long or its id is too long", size = KEY_SIZE)]
+    InvalidKey { key_id: String },
+    /// The key provider does not know the key
+    #[error("unknown key {key_id}")]
+    UnknownKey { key_id: String },
+    /// The value could not be encrypted or decrypted with its key, or was tampered with
+    #[error("cannot encrypt or decrypt a value of record type {rec_type}")]
+    Cipher { rec_type: String },
+    /// The stored value is too short to be an encrypted value
+    #[error("malformed encrypted value in record type {rec_type}")]
+    Malformed { rec_type: String },
+    /// Indices are forbidden on the record type
+    #[error("index keys are forbidden on encrypted record type {rec_type}")]
+    ForbiddenIndex { rec_type: String },
+    /// Covering indices would store values in clear next to hashed index keys
+    #[error("covering indices are not supported on record type {rec_type}, as its index keys are hashed")]
+    IncludedValues { rec_type: String },
+    /// Existing index entries would not match the new index protection
+    #[error("record type {rec_type} already has indices, delete them before changing its index protection")]
+    ExistingIndices { rec_type: String },
+}
+
+/// Provides the keys used to encrypt values, so that keys never need to be stored with the data
+pub trait KeyProvider {
+    /// Returns the id of the key to use to encrypt new values of a record type
+    /// # Arguments
+    /// * `rec_type` - the record type
+    fn current_key_id(&self, rec_type: &str) -> Result<String>;
+
+    /// Returns the 32 bytes key with the given id
+    /// # Arguments
+    /// * `key_id` - the key id
+    fn key(&self, key_id: &str) -> Result<Vec<u8>>;
+}
+
+/// A key provider holding its keys in memory
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKeyProvider {
+    /// The id of the key used to encrypt new values
+    pub current: String,
+    /// The keys by id
+    pub keys: HashMap<String, Vec<u8>>,
+}
+
+impl KeyProvider for MemoryKeyProvider {
+    fn current_key_id(&self, _rec_type: &str) -> Result<String> {
+        Ok(self.current.clone())
+    }
+
+    fn key(&self, key_id: &str) -> Result<Vec<u8>> {
+        self.keys.get(key_id).cloned().ok_or_else(|| {
+            EncryptionError::UnknownKey {
+                key_id: String::from(key_id),
+            }
+            .into()
+        })
+    }
+}
+
+/// How the index keys of an encrypted record type are stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum IndexProtection {
+    /// Indexed values are stored in clear, so index lookups work as usual but leak the indexed values
+    Plaintext,
+    /// Indexed values are replaced by a keyed hash, so only exact lookups work and the values returned from
+    /// the index keys are the hashes
+    KeyedHash { key_id: String },
+    /// No index can be created
+    Forbidden,
+}
+
+/// The encryption settings of a record type
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    /// How index keys are stored
+    pub index_keys: IndexProtection,
+}
+
+/// Returns true if a stored value is encrypted
+pub(crate) fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&ENCRYPTED_TAG)
+}
+
+/// Builds a cipher from the key with the given id
+fn cipher(provider: &dyn KeyProvider, key_id: &str) -> Result<ChaCha20Poly1305> {
+    let key = provider.key(key_id)?;
+    if key.len() != KEY_SIZE || key_id.len() > u8::MAX as usize {
+        return Err(EncryptionError::InvalidKey {
+            key_id: String::from(key_id),
+        }
+        .into());
+    }
+    let mut k = [0u8; KEY_SIZE];
+    k.copy_from_slice(&key);
+    Ok(ChaCha20Poly1305::new(&Key::from(k)))
+}
+
+/// The associated data binding a value to its record, so that encrypted values cannot be swapped between records
+fn associated_data(rec_type: &str, kv: &[u8]) -> Vec<u8> {
+    let mut aad = rec_type.as_bytes().to_vec();
+    aad.push(0);
+    aad.extend_from_slice(kv);
+    aad
+}
+
+/// Encrypts an encoded value with the current key of its record type
+/// The result is the tag, the key id length and the key id, the nonce, then the ciphertext
+/// # Arguments
+/// * `provider` - the key provider
+/// * `rec_type` - the record type
+/// * `kv` - the serialized key
+/// * `plain` - the encoded value
+pub(crate) fn encrypt(provider: &dyn KeyProvider, rec_type: &str, kv: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+    let key_id = provider.current_key_id(rec_type)?;
+    let cipher = cipher(provider, &key_id)?;
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce)?;
+    let aad = associated_data(rec_type, kv);
+    let ct = cipher
+        .encrypt(&Nonce::from(nonce), Payload { msg: plain, aad: &aad })
+        .map_err(|_| EncryptionError::Cipher {
+            rec_type: String::from(rec_type),
+        })?;
+    let mut v = vec![ENCRYPTED_TAG, key_id.len() as u8];
+    v.extend_from_slice(key_id.as_bytes());
+    v.extend_from_slice(&nonce);
+    v.extend_from_slice(&ct);
+    Ok(v)
+}
+
+/// Decrypts a value written by `encrypt`, returning the encoded value
+/// # Arguments
+/// * `provider` - the key provider
+/// * `rec_type` - the record type
+/// * `kv` - the serialized key
+/// * `stored` - the stored value
+pub(crate) fn decrypt(provider: &dyn KeyProvider, rec_type: &str, kv: &[u8], stored: &[u8]) -> Result<Vec<u8>> {
+    let malformed = || EncryptionError::Malformed {
+        rec_type: String::from(rec_type),
+    };
+    let id_len = *stored.get(1).ok_or_else(malformed)? as usize;
+    if stored.len() < 2 + id_len + NONCE_SIZE {
+        return Err(malformed().into());
+    }
+    let key_id = std::str::from_utf8(&stored[2..2 + id_len]).map_err(|_| malformed())?;
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce.copy_from_slice(&stored[2 + id_len..2 + id_len + NONCE_SIZE]);
+    let cipher = cipher(provider, key_id)?;
+    let aad = associated_data(rec_type, kv);
+    Ok(cipher
+        .decrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: &stored[2 + id_len + NONCE_SIZE..],
+                aad: &aad,
+            },
+        )
+        .map_err(|_| EncryptionError::Cipher {
+            rec_type: String::from(rec_type),
+        })?)
+}
+
+/// Computes the keyed hash of some bytes, as a hexadecimal string
+/// # Arguments
+/// * `key` - the hash key
+/// * `data` - the bytes to hash
+pub(crate) fn keyed_hash(key: &[u8], data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> MemoryKeyProvider {
+        let mut keys = HashMap::new();
+        keys.insert(String::from("k1"), vec![1u8; KEY_SIZE]);
+        keys.insert(String::from("k2"), vec![2u8; KEY_SIZE]);
+        MemoryKeyProvider {
+            current: String::from("k1"),
+            keys,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let mut p = provider();
+        let stored = encrypt(&p, "type1", b"\"key1\"", b"{\"name\":\"John Doe\"}")?;
+        assert_eq!(true, is_encrypted(&stored));
+        p.current = String::from("k2");
+        // old values are still read with the key they were written with
+        assert_eq!(
+            b"{\"name\":\"John Doe\"}".to_vec(),
+            decrypt(&p, "type1", b"\"key1\"", &stored)?
+        );
+        // values are bound to their record
+        assert_eq!(true, decrypt(&p, "type1", b"\"key2\"", &stored).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyed_hash() {
+        assert_eq!(keyed_hash(b"k", b"value"), keyed_hash(b"k", b"value"));
+        assert_ne!(keyed_hash(b"k", b"value"), keyed_hash(b"k2", b"value"));
+        assert_eq!(64, keyed_hash(b"k", b"value").len());
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Tag byte written in front of CBOR values. It can never start a JSON text, so values
+/// written before formats were introduced are still read as JSON
+const CBOR_TAG: u8 = 0xC1;
+
+/// The format used to store the values of a record type
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueFormat {
+    /// JSON text
+    #[default]
+    Json,
+    /// Binary CBOR, more compact and faster to decode for numeric-heavy documents
+    Cbor,
+}
+
+impl ValueFormat {
+    /// Encodes a value in this format
+    /// # Arguments
+    /// * `value` - the value to encode
+    pub fn encode(self, value: &Value) -> Result<Vec<u8>> {
+        match self {
+            ValueFormat::Json => Ok(serde_json::to_vec(value)?),
+            ValueFormat::Cbor => {
+                let mut v = vec![CBOR_TAG];
+                serde_cbor::to_writer(&mut v, value)?;
+                Ok(v)
+            }
+        }
+    }
+
+    /// Returns the format a stored value was written in
+    /// # Arguments
+    /// * `bytes` - the stored value
+    pub fn of(bytes: &[u8]) -> ValueFormat {
+        match bytes.first() {
+            Some(&CBOR_TAG) => ValueFormat::Cbor,
+            _ => ValueFormat::Json,
+        }
+    }
+}
+
+/// Decodes a stored value, whatever the format it was written in
+/// # Arguments
+/// * `bytes` - the stored value
+pub fn decode_value(bytes: &[u8]) -> Result<Value> {
+    match ValueFormat::of(bytes) {
+        ValueFormat::Json => Ok(serde_json::from_slice(bytes)?),
+        ValueFormat::Cbor => Ok(serde_cbor::from_slice(&bytes[1..])?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let john = json!({
+            "name": "John Doe",
+            "age": 43,
+            "weight": 72.5,
+            "phones": [
+                "+44 1234567",
+                "+44 2345678"
+            ],
+            "address": null
+        });
+        for f in [ValueFormat::Json, ValueFormat::Cbor].iter() {
+            let bs = f.encode(&john)?;
+            assert_eq!(*f, ValueFormat::of(&bs));
+            assert_eq!(john, decode_value(&bs)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cbor_smaller() -> Result<()> {
+        let v = json!({"values":[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20]});
+        assert_eq!(
+            true,
+            ValueFormat::Cbor.encode(&v)?.len() < ValueFormat::Json.encode(&v)?.len()
+        );
+        Ok(())
+    }
+}
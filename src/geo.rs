@@ -0,0 +1,251 @@
+use serde_json::Value;
+use thiserror::Error;
+
+/// The length of the geohashes of indexed points, cells of a few centimeters
+pub(crate) const GEOHASH_PRECISION: usize = 12;
+
+/// The maximum number of cells covering a searched area: the finest precision staying under it is used
+const MAX_COVERING_CELLS: usize = 32;
+
+/// The mean radius of the Earth in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// The geohash alphabet
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Errors on geospatial indices and queries
+#[derive(Error, Debug)]
+pub enum GeoError {
+    /// A geospatial index indexes a latitude and a longitude
+    #[error("geospatial index {index_name} on record type {rec_type} needs two components, the latitude and the longitude")]
+    Components { rec_type: String, index_name: String },
+    /// A geospatial index cannot be unique, full-text, include values, or hash its values as cells would not sort
+    #[error("geospatial index {index_name} on record type {rec_type} cannot be unique, full-text, include values or hash them")]
+    InvalidIndex { rec_type: String, index_name: String },
+    /// The index is not a geospatial index
+    #[error("index {index_name} on record type {rec_type} is not a geospatial index")]
+    NotGeoIndex { rec_type: String, index_name: String },
+    /// A latitude is not between -90 and 90, or a longitude between -180 and 180
+    #[error("invalid coordinates: latitude {lat}, longitude {lon}")]
+    InvalidCoordinates { lat: f64, lon: f64 },
+    /// The southern edge of a box is north of its northern edge
+    #[error("the minimum latitude {min_lat} of a box is greater than its maximum latitude {max_lat}")]
+    InvalidBox { min_lat: f64, max_lat: f64 },
+    /// A radius is negative
+    #[error("invalid radius {radius}")]
+    InvalidRadius { radius: f64 },
+}
+
+/// Checks that a latitude and a longitude are valid
+pub(crate) fn check_coordinates(lat: f64, lon: f64) -> Result<(), GeoError> {
+    if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+        Ok(())
+    } else {
+        Err(GeoError::InvalidCoordinates { lat, lon })
+    }
+}
+
+/// Returns the coordinates of a record from the values of the latitude and longitude components of an index,
+/// none if they are missing, not numbers or invalid
+pub(crate) fn coordinates(values: &[Vec<Value>]) -> Option<(f64, f64)> {
+    let lat = values.first()?.first()?.as_f64()?;
+    let lon = values.get(1)?.first()?.as_f64()?;
+    check_coordinates(lat, lon).ok().map(|_| (lat, lon))
+}
+
+/// Encodes a point as a geohash: the longitude and latitude ranges are halved in turn, each half giving a bit,
+/// so that points sharing a prefix are in the same cell
+/// # Arguments
+/// * `lat` - the latitude
+/// * `lon` - the longitude
+/// * `precision` - the number of characters
+pub(crate) fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+    let mut hash = String::with_capacity(precision);
+    let mut bits = 0;
+    let mut ch = 0;
+    let mut even = true;
+    while hash.len() < precision {
+        let (range, v) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        ch <<= 1;
+        if v >= mid {
+            ch |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[ch] as char);
+            bits = 0;
+            ch = 0;
+        }
+    }
+    hash
+}
+
+/// The size in degrees of the geohash cells of a precision, latitude first
+fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision as i32;
+    (180.0 / 2f64.powi(bits / 2), 360.0 / 2f64.powi(bits - bits / 2))
+}
+
+/// The first and last index of the cells of a size covering a range
+fn cell_span(min: f64, max: f64, origin: f64, size: f64) -> (i64, i64) {
+    let last = ((-2.0 * origin) / size) as i64 - 1;
+    let index = |v: f64| (((v - origin) / size).floor() as i64).max(0).min(last);
+    (index(min), index(max))
+}
+
+/// Returns the geohash cells covering a box, as few as possible without going over `MAX_COVERING_CELLS`
+/// A box whose western edge is east of its eastern edge crosses the antimeridian
+/// # Arguments
+/// * `south` - the minimum latitude
+/// * `west` - the minimum longitude
+/// * `north` - the maximum latitude
+/// * `east` - the maximum longitude
+pub(crate) fn covering_cells(south: f64, west: f64, north: f64, east: f64) -> Vec<String> {
+    let lon_ranges = if west > east {
+        vec![(west, 180.0), (-180.0, east)]
+    } else {
+        vec![(west, east)]
+    };
+    let spans = |precision: usize| {
+        let (lat_size, lon_size) = cell_size(precision);
+        let lats = cell_span(south, north, -90.0, lat_size);
+        let lons: Vec<(i64, i64)> = lon_ranges
+            .iter()
+            .map(|(w, e)| cell_span(*w, *e, -180.0, lon_size))
+            .collect();
+        (lat_size, lon_size, lats, lons)
+    };
+    let count = |precision: usize| {
+        let (_, _, (s, n), lons) = spans(precision);
+        lons.iter().map(|(w, e)| (e - w + 1) as usize).sum::<usize>() * (n - s + 1) as usize
+    };
+    let precision = (2..=GEOHASH_PRECISION)
+        .take_while(|p| count(*p) <= MAX_COVERING_CELLS)
+        .last()
+        .unwrap_or(1);
+    let (lat_size, lon_size, (s, n), lons) = spans(precision);
+    let mut cells = vec![];
+    for (w, e) in lons {
+        for i in s..=n {
+            for j in w..=e {
+                let lat = -90.0 + (i as f64 + 0.5) * lat_size;
+                let lon = -180.0 + (j as f64 + 0.5) * lon_size;
+                cells.push(geohash(lat, lon, precision));
+            }
+        }
+    }
+    cells.sort();
+    cells.dedup();
+    cells
+}
+
+/// Returns true if a point is in a box, which crosses the antimeridian if its western edge is east of its eastern edge
+pub(crate) fn in_box(lat: f64, lon: f64, south: f64, west: f64, north: f64, east: f64) -> bool {
+    let in_lon = if west > east {
+        lon >= west || lon <= east
+    } else {
+        lon >= west && lon <= east
+    };
+    lat >= south && lat <= north && in_lon
+}
+
+/// The smallest box holding the points within a distance of a point, as south, west, north and east edges
+/// # Arguments
+/// * `lat` - the latitude of the center
+/// * `lon` - the longitude of the center
+/// * `radius` - the distance in meters
+pub(crate) fn radius_box(lat: f64, lon: f64, radius: f64) -> (f64, f64, f64, f64) {
+    let dlat = (radius / EARTH_RADIUS).to_degrees();
+    let (south, north) = (lat - dlat, lat + dlat);
+    if south <= -90.0 || north >= 90.0 {
+        // a pole is in the circle, which spans all longitudes
+        return (south.max(-90.0), -180.0, north.min(90.0), 180.0);
+    }
+    let dlon = ((radius / EARTH_RADIUS).sin() / lat.to_radians().cos()).min(1.0).asin().to_degrees();
+    let wrap = |l: f64| {
+        if l < -180.0 {
+            l + 360.0
+        } else if l > 180.0 {
+            l - 360.0
+        } else {
+            l
+        }
+    };
+    (south, wrap(lon - dlon), north, wrap(lon + dlon))
+}
+
+/// The great-circle distance in meters between two points, with the haversine formula
+pub(crate) fn distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_geohash() {
+        assert_eq!("u4pruydqqvj", geohash(57.64911, 10.40744, 11));
+        assert_eq!(true, geohash(51.5074, -0.1278, 12).starts_with(&geohash(51.5074, -0.1278, 5)));
+        assert_eq!("zzz", geohash(90.0, 180.0, 3));
+        assert_eq!("000", geohash(-90.0, -180.0, 3));
+    }
+
+    #[test]
+    fn test_covering_cells() {
+        let cells = covering_cells(51.50, -0.13, 51.51, -0.12);
+        assert_eq!(true, cells.len() <= MAX_COVERING_CELLS);
+        assert_eq!(true, cells.iter().any(|c| geohash(51.5074, -0.1278, 12).starts_with(c)));
+        assert_eq!(true, cells.iter().any(|c| geohash(51.50, -0.13, 12).starts_with(c)));
+        assert_eq!(true, cells.iter().any(|c| geohash(51.51, -0.12, 12).starts_with(c)));
+        // the whole world at the first precision
+        assert_eq!(32, covering_cells(-90.0, -180.0, 90.0, 180.0).len());
+        // across the antimeridian
+        let cells = covering_cells(-18.0, 179.0, -17.0, -179.0);
+        assert_eq!(true, cells.iter().any(|c| geohash(-17.5, 179.5, 12).starts_with(c)));
+        assert_eq!(true, cells.iter().any(|c| geohash(-17.5, -179.5, 12).starts_with(c)));
+        assert_eq!(false, cells.iter().any(|c| geohash(-17.5, 0.0, 12).starts_with(c)));
+    }
+
+    #[test]
+    fn test_distance() {
+        // London to Paris
+        let d = distance(51.5074, -0.1278, 48.8566, 2.3522);
+        assert_eq!(true, (d - 343_500.0).abs() < 1_000.0);
+        assert_eq!(0.0, distance(10.0, 10.0, 10.0, 10.0));
+        let (south, west, north, east) = radius_box(51.5074, -0.1278, 10_000.0);
+        for (lat, lon) in [(south, -0.1278), (north, -0.1278), (51.5074, west), (51.5074, east)].iter() {
+            assert_eq!(true, distance(51.5074, -0.1278, *lat, *lon) >= 9_999.0);
+        }
+        let (_, west, _, east) = radius_box(0.0, 179.99, 10_000.0);
+        assert_eq!(true, west > east);
+        assert_eq!((-90.0, -180.0), {
+            let (s, w, _, _) = radius_box(-89.99, 0.0, 10_000.0);
+            (s, w)
+        });
+    }
+
+    #[test]
+    fn test_coordinates() {
+        assert_eq!(Some((51.5, -0.1)), coordinates(&[vec![json!(51.5)], vec![json!(-0.1)]]));
+        assert_eq!(Some((10.0, 20.0)), coordinates(&[vec![json!(10)], vec![json!(20)]]));
+        assert_eq!(None, coordinates(&[vec![json!(91)], vec![json!(0)]]));
+        assert_eq!(None, coordinates(&[vec![json!("51.5")], vec![json!(0)]]));
+        assert_eq!(None, coordinates(&[vec![Value::Null], vec![json!(0)]]));
+    }
+}
@@ -0,0 +1,496 @@
+use crate::compare::{encode_ordered_bound, ORDERED_AFTER};
+use crate::text::TextOptions;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{collections::HashSet, ops::Bound};
+use thiserror::Error;
+
+/// Index errors
+#[derive(Error, Debug)]
+pub enum IndexError {
+    /// A record has the same indexed values as another record in a unique index
+    #[error("record {key} of type {rec_type} has the same values as record {existing} in unique index {index_name}")]
+    UniqueViolation {
+        rec_type: String,
+        index_name: String,
+        key: Value,
+        existing: Value,
+    },
+    /// A unique index cannot be created because existing records share indexed values
+    #[error("cannot create unique index {index_name} on record type {rec_type}, records {keys:?} share indexed values")]
+    DuplicateValues {
+        rec_type: String,
+        index_name: String,
+        keys: Vec<Value>,
+    },
+    /// The filter of a partial index failed to run or did not return a boolean
+    #[error("cannot evaluate the filter of index {index_name} on record type {rec_type}: {message}")]
+    Filter {
+        rec_type: String,
+        index_name: String,
+        message: String,
+    },
+    /// An index expression failed to run or returned a value that cannot be converted to JSON
+    #[error("cannot evaluate expression {expression} of index {index_name} on record type {rec_type}: {message}")]
+    Expression {
+        rec_type: String,
+        index_name: String,
+        expression: String,
+        message: String,
+    },
+    /// A value included in a covering index is not designated by a JSON pointer
+    #[error("included value {include} of index {index_name} on record type {rec_type} is not a JSON pointer")]
+    InvalidInclude {
+        rec_type: String,
+        index_name: String,
+        include: String,
+    },
+}
+
+/// The options of an index
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexOptions {
+    /// No two records can have the same indexed values. Records with a missing or null indexed value are not checked
+    #[serde(default)]
+    pub unique: bool,
+    /// Only the records matching the filter are indexed, which keeps the index small
+    #[serde(default)]
+    pub filter: Option<IndexFilter>,
+    /// The pointers of the values stored in the index entries, so that index lookups return them without reading
+    /// the records. They keep their place in the record: `/address/city` gives `{"address": {"city": ...}}`
+    /// Like indexed values, included values are stored in clear on encrypted record types with plaintext index keys
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Makes a full-text index, indexing each term of the strings found by the index components with its positions,
+    /// to search with `text_search`
+    #[serde(default)]
+    pub text: Option<TextOptions>,
+    /// Makes a geospatial index on two components, the latitude and the longitude, indexing the geohash of each record
+    /// with its coordinates, to search with `geo_within_box` and `geo_within_radius`
+    #[serde(default)]
+    pub geo: bool,
+}
+
+/// The condition records must meet to be in a partial index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum IndexFilter {
+    /// The value at the pointer is equal to the given value, numbers being compared by value
+    Equals { pointer: String, value: Value },
+    /// A Rhai expression returning a boolean, with the record available as `rec`
+    Script(String),
+}
+
+/// A value to look up in an index component
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum IndexValue {
+    /// Any value, including null and missing values
+    Any,
+    /// The given value, null only matching null and missing values
+    Equals(Value),
+}
+
+impl From<Value> for IndexValue {
+    fn from(v: Value) -> Self {
+        IndexValue::Equals(v)
+    }
+}
+
+/// The range of values of an index component, in the order of `compare_values`
+/// An unbounded side includes the values of every type, a lower bound of 30 leaves out strings but an upper bound
+/// of 40 keeps nulls
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexRange {
+    pub lower: Bound<Value>,
+    pub upper: Bound<Value>,
+}
+
+impl IndexRange {
+    /// All the values
+    pub fn all() -> Self {
+        IndexRange {
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        }
+    }
+
+    /// The values between two values, both included
+    pub fn between(lower: Value, upper: Value) -> Self {
+        IndexRange {
+            lower: Bound::Included(lower),
+            upper: Bound::Included(upper),
+        }
+    }
+
+    /// The values greater than or equal to a value
+    pub fn at_least(lower: Value) -> Self {
+        IndexRange {
+            lower: Bound::Included(lower),
+            upper: Bound::Unbounded,
+        }
+    }
+
+    /// The values strictly greater than a value
+    pub fn greater_than(lower: Value) -> Self {
+        IndexRange {
+            lower: Bound::Excluded(lower),
+            upper: Bound::Unbounded,
+        }
+    }
+
+    /// The values less than or equal to a value
+    pub fn at_most(upper: Value) -> Self {
+        IndexRange {
+            lower: Bound::Unbounded,
+            upper: Bound::Included(upper),
+        }
+    }
+
+    /// The values strictly less than a value
+    pub fn less_than(upper: Value) -> Self {
+        IndexRange {
+            lower: Bound::Unbounded,
+            upper: Bound::Excluded(upper),
+        }
+    }
+
+    /// Returns true if the range has a bound
+    pub fn is_bounded(&self) -> bool {
+        self.lower != Bound::Unbounded || self.upper != Bound::Unbounded
+    }
+
+    /// Encodes the bounds like the components of index keys
+    /// The encodings of the values equal to a bound start with `encode_ordered_bound` and sort before it followed
+    /// by `ORDERED_AFTER`, so each bound becomes one of these two
+    pub(crate) fn encode(&self) -> EncodedRange {
+        let encode = |v: &Value, after: bool| {
+            let mut e = encode_ordered_bound(v);
+            if after {
+                e.push(ORDERED_AFTER);
+            }
+            e
+        };
+        EncodedRange {
+            lower: match &self.lower {
+                Bound::Included(v) => Some(encode(v, false)),
+                Bound::Excluded(v) => Some(encode(v, true)),
+                Bound::Unbounded => None,
+            },
+            upper: match &self.upper {
+                Bound::Included(v) => Some(encode(v, true)),
+                Bound::Excluded(v) => Some(encode(v, false)),
+                Bound::Unbounded => None,
+            },
+        }
+    }
+}
+
+/// A range with encoded bounds, which compare with encoded index key components byte by byte
+pub(crate) struct EncodedRange {
+    /// The first encoding included
+    lower: Option<Vec<u8>>,
+    /// The first encoding excluded
+    upper: Option<Vec<u8>>,
+}
+
+impl EncodedRange {
+    /// The range of all the components
+    pub(crate) fn all() -> Self {
+        EncodedRange {
+            lower: None,
+            upper: None,
+        }
+    }
+
+    /// The range holding a single encoded component
+    /// Encodings are never a prefix of one another, so no other component sorts between it and it followed by 1
+    pub(crate) fn equal(mut component: Vec<u8>) -> Self {
+        let lower = component.clone();
+        component.push(1);
+        EncodedRange {
+            lower: Some(lower),
+            upper: Some(component),
+        }
+    }
+
+    /// Returns true if an encoded component is in the range
+    pub(crate) fn contains(&self, component: &[u8]) -> bool {
+        !self.is_after(component) && self.upper.as_ref().map(|u| component < u.as_slice()).unwrap_or(true)
+    }
+
+    /// Returns true if an encoded component comes before the range
+    pub(crate) fn is_after(&self, component: &[u8]) -> bool {
+        self.lower.as_ref().map(|l| component < l.as_slice()).unwrap_or(false)
+    }
+
+    /// Returns the first index key included and the first index key excluded, none for the end of the index,
+    /// for the entries starting with the given components and with their next component in the range
+    /// The 0 separator after a component sorts before any byte of an encoding, so the bounds work on whole keys
+    /// # Arguments
+    /// * `prefix` - the encoded components, each followed by 0
+    pub(crate) fn keys(&self, prefix: &[u8]) -> (Vec<u8>, Option<Vec<u8>>) {
+        let with = |v: &[u8]| {
+            let mut k = prefix.to_vec();
+            k.extend_from_slice(v);
+            k
+        };
+        let lower = self.lower.as_deref().map(with).unwrap_or_else(|| prefix.to_vec());
+        let upper = match &self.upper {
+            Some(u) => Some(with(u)),
+            // the prefix ends with a 0 separator
+            None => prefix.split_last().map(|(_, p)| {
+                let mut k = p.to_vec();
+                k.push(1);
+                k
+            }),
+        };
+        (lower, upper)
+    }
+}
+
+/// Returns true if an index component is a JSON pointer, and false if it is a Rhai expression over the record `rec`
+/// # Arguments
+/// * `component` - the index component
+pub(crate) fn is_pointer(component: &str) -> bool {
+    component.is_empty() || component.starts_with('/')
+}
+
+/// Returns the values a pointer designates in a value, for index keys
+/// A `*` segment stands for every element of an array, so `/phones/*` or `/lines/*/product_id` give one value
+/// per element, and no value if the array is empty or missing. Without wildcard, a missing value is null
+/// An object key made of a single `*` is escaped as `~2`, like `~0` and `~1` escape `~` and `/`
+/// # Arguments
+/// * `value` - the record value
+/// * `pointer` - the JSON pointer, possibly with `*` segments
+pub(crate) fn pointer_values<'v>(value: &'v Value, pointer: &str) -> Vec<&'v Value> {
+    let wildcard = pointer
+        .match_indices("/*")
+        .map(|(i, _)| i)
+        .find(|i| matches!(pointer.as_bytes().get(i + 2), None | Some(b'/')));
+    // a `~` is always followed by 0, 1 or 2, so `~2` can only be the escape
+    let unescape = |p: &str| p.replace("~2", "*");
+    match wildcard {
+        Some(i) => match value.pointer(&unescape(&pointer[..i])) {
+            Some(Value::Array(a)) => a
+                .iter()
+                .flat_map(|e| pointer_values(e, &pointer[i + 2..]))
+                .collect(),
+            _ => vec![],
+        },
+        None => vec![value.pointer(&unescape(pointer)).unwrap_or(&Value::Null)],
+    }
+}
+
+/// Builds the values stored in the entries of a covering index, nested like in the record
+/// Missing values are left out
+/// # Arguments
+/// * `value` - the record value
+/// * `include` - the pointers of the included values
+pub(crate) fn included_values(value: &Value, include: &[String]) -> Value {
+    let mut included = Value::Object(Map::new());
+    for pointer in include {
+        let v = match value.pointer(pointer) {
+            Some(v) => v.clone(),
+            None => continue,
+        };
+        if pointer.is_empty() {
+            return v;
+        }
+        let segments: Vec<String> = pointer[1..]
+            .split('/')
+            .map(|s| s.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        let mut current = &mut included;
+        for segment in &segments[..segments.len() - 1] {
+            if !current.is_object() {
+                *current = Value::Object(Map::new());
+            }
+            current = current
+                .as_object_mut()
+                .unwrap()
+                .entry(segment.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+        if let Some(m) = current.as_object_mut() {
+            m.insert(segments[segments.len() - 1].clone(), v);
+        }
+    }
+    included
+}
+
+/// Builds the value of an index entry: the record key, followed for covering indices by a 0 byte and the
+/// included values. Serialized keys never contain a 0 byte
+pub(crate) fn entry_value(kv: &[u8], included: Option<&Value>) -> Vec<u8> {
+    let mut v = kv.to_vec();
+    if let Some(included) = included {
+        v.push(0);
+        v.extend(serde_json::to_vec(included).unwrap());
+    }
+    v
+}
+
+/// Splits the value of an index entry into the record key and the included values, if any
+pub(crate) fn split_entry_value(v: &[u8]) -> (&[u8], Option<Value>) {
+    match v.iter().position(|b| *b == 0) {
+        Some(i) => (&v[..i], serde_json::from_slice(&v[i + 1..]).ok()),
+        None => (v, None),
+    }
+}
+
+/// Finds the records sharing indexed values with another record, from index entries given in key order
+/// Entries with a missing or null indexed value are ignored
+pub(crate) struct DuplicateFinder {
+    /// The number of indexed values in an index key
+    components: usize,
+    /// The encoded null value
+    null: Vec<u8>,
+    /// The indexed values and record key of the previous entry
+    previous: Option<(Vec<u8>, Vec<u8>)>,
+    /// True when the previous entry shares its values with the one before it
+    in_run: bool,
+    /// The keys of the records found so far, maybe repeated
+    keys: Vec<Value>,
+}
+
+impl DuplicateFinder {
+    /// Creates a new finder
+    /// # Arguments
+    /// * `components` - the number of indexed values in an index key
+    /// * `null` - the encoded null value
+    pub(crate) fn new(components: usize, null: Vec<u8>) -> Self {
+        DuplicateFinder {
+            components,
+            null,
+            previous: None,
+            in_run: false,
+            keys: vec![],
+        }
+    }
+
+    /// Adds an index entry
+    /// # Arguments
+    /// * `k` - the index key, without location prefix
+    /// * `v` - the entry value
+    pub(crate) fn push(&mut self, k: &[u8], v: &[u8]) -> Result<()> {
+        let (kv, _) = split_entry_value(v);
+        let values = k[..k.len() - kv.len()].to_vec();
+        if values.split(|b| *b == 0).take(self.components).any(|c| c == self.null.as_slice()) {
+            return Ok(());
+        }
+        match &self.previous {
+            Some((pv, pkv)) if *pv == values => {
+                if !self.in_run {
+                    self.keys.push(serde_json::from_slice(pkv)?);
+                    self.in_run = true;
+                }
+                self.keys.push(serde_json::from_slice(kv)?);
+            }
+            _ => self.in_run = false,
+        }
+        self.previous = Some((values, kv.to_vec()));
+        Ok(())
+    }
+
+    /// Returns the distinct keys of the records sharing values with another record
+    pub(crate) fn finish(mut self) -> Vec<Value> {
+        let mut seen = HashSet::new();
+        self.keys.retain(|k| seen.insert(k.to_string()));
+        self.keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_pointer_values() {
+        let v = json!({"name": "John", "phones": ["123", "456"], "lines": [{"product_id": 1}, {"product_id": 2}, {}]});
+        assert_eq!(vec![&json!("John")], pointer_values(&v, "/name"));
+        assert_eq!(vec![&Value::Null], pointer_values(&v, "/age"));
+        assert_eq!(vec![&json!("123"), &json!("456")], pointer_values(&v, "/phones/*"));
+        assert_eq!(
+            vec![&json!(1), &json!(2), &Value::Null],
+            pointer_values(&v, "/lines/*/product_id")
+        );
+        assert_eq!(true, pointer_values(&v, "/emails/*").is_empty());
+        assert_eq!(vec![&json!("456")], pointer_values(&v, "/phones/1"));
+        assert_eq!(vec![&json!(1)], pointer_values(&json!({"*a": [{"b": 1}]}), "/*a/*/b"));
+        // a `*` key is escaped
+        let v = json!({"*": {"a": 1}, "stars": {"*": [2, 3]}, "~2": 4});
+        assert_eq!(vec![&json!(1)], pointer_values(&v, "/~2/a"));
+        assert_eq!(vec![&json!(2), &json!(3)], pointer_values(&v, "/stars/~2/*"));
+        assert_eq!(vec![&json!(4)], pointer_values(&v, "/~02"));
+        assert_eq!(true, pointer_values(&v, "/*/a").is_empty());
+    }
+
+    #[test]
+    fn test_is_pointer() {
+        assert_eq!(true, is_pointer("/name"));
+        assert_eq!(true, is_pointer(""));
+        assert_eq!(false, is_pointer("rec.value.email.to_lower()"));
+    }
+
+    #[test]
+    fn test_range_keys() {
+        use crate::compare::encode_ordered;
+        let prefix = {
+            let mut p = encode_ordered(&json!("UK"));
+            p.push(0);
+            p
+        };
+        let key = |age: Value| {
+            let mut k = prefix.clone();
+            k.extend(encode_ordered(&age));
+            k.push(0);
+            k.extend(b"1");
+            k
+        };
+        let in_keys = |r: &IndexRange, age: Value| {
+            let (lower, upper) = r.encode().keys(&prefix);
+            let k = key(age);
+            k >= lower && upper.map(|u| k < u).unwrap_or(true)
+        };
+        let r = IndexRange::between(json!(30), json!(40));
+        assert_eq!(true, in_keys(&r, json!(30)));
+        assert_eq!(true, in_keys(&r, json!(40)));
+        assert_eq!(true, in_keys(&r, json!(30.0)));
+        assert_eq!(true, in_keys(&r, json!(40.0)));
+        assert_eq!(true, in_keys(&r, json!(35.5)));
+        assert_eq!(false, in_keys(&r, json!(41)));
+        assert_eq!(false, in_keys(&r, json!("35")));
+        let r = IndexRange {
+            lower: Bound::Excluded(json!(30)),
+            upper: Bound::Excluded(json!(40)),
+        };
+        assert_eq!(false, in_keys(&r, json!(30)));
+        assert_eq!(false, in_keys(&r, json!(40)));
+        assert_eq!(false, in_keys(&r, json!(30.0)));
+        assert_eq!(false, in_keys(&r, json!(40.0)));
+        assert_eq!(true, in_keys(&r, json!(31)));
+        assert_eq!(true, in_keys(&IndexRange::all(), json!(null)));
+        assert_eq!(true, in_keys(&IndexRange::less_than(json!(40)), json!(null)));
+        assert_eq!(false, in_keys(&IndexRange::at_least(json!(30)), json!(null)));
+        assert_eq!(true, r.encode().contains(&encode_ordered(&json!(35))));
+        assert_eq!(false, r.encode().contains(&encode_ordered(&json!(40))));
+        assert_eq!(true, IndexRange::at_most(json!(40)).encode().contains(&encode_ordered(&json!(40.0))));
+        assert_eq!((vec![], None), IndexRange::all().encode().keys(&[]));
+    }
+
+    #[test]
+    fn test_included_values() {
+        let v = json!({"name": "John", "address": {"city": "Paris", "zip": "75001"}, "a/b": 1});
+        let include = |ps: &[&str]| -> Vec<String> { ps.iter().map(|p| String::from(*p)).collect() };
+        assert_eq!(
+            json!({"name": "John", "address": {"city": "Paris"}}),
+            included_values(&v, &include(&["/name", "/address/city", "/age"]))
+        );
+        assert_eq!(json!({"a/b": 1}), included_values(&v, &include(&["/a~1b"])));
+
+        let kv = serde_json::to_vec(&json!(["k", 1])).unwrap();
+        assert_eq!((kv.as_slice(), None), split_entry_value(&entry_value(&kv, None)));
+        let e = entry_value(&kv, Some(&json!({"name": "John"})));
+        assert_eq!((kv.as_slice(), Some(json!({"name": "John"}))), split_entry_value(&e));
+    }
+}
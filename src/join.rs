@@ -0,0 +1,392 @@
+use crate::bulk::{read_bytes, write_bytes};
+use crate::compare::CanonicalValue;
+use crate::EQLRecord;
+use anyhow::Result;
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The default memory budget in bytes for the build side of a hash join
+pub const DEFAULT_JOIN_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+/// Options for hash joins
+#[derive(Debug, Clone)]
+pub struct HashJoinOptions {
+    /// The memory budget in bytes for the build side, past which both sides are partitioned on disk
+    pub memory_budget: usize,
+    /// Match numbers by value, so that 1 and 1.0 are equal, instead of requiring the same number type
+    pub numbers_by_value: bool,
+}
+
+impl Default for HashJoinOptions {
+    fn default() -> Self {
+        HashJoinOptions {
+            memory_budget: DEFAULT_JOIN_MEMORY_BUDGET,
+            numbers_by_value: false,
+        }
+    }
+}
+
+/// The number of partitions each spill creates
+const PARTITIONS: u64 = 16;
+
+/// How many times a partition that is still too big is partitioned again. Beyond that the partition is
+/// loaded in memory whatever its size, as it is made of a few very frequent keys
+const MAX_DEPTH: usize = 4;
+
+/// Makes the spill folder of each join unique, even for joins nested in each other
+static JOIN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A record with the canonical form of its join value
+type HashedRecord = (CanonicalValue, EQLRecord);
+
+/// The join function
+type JoinFn<'a> = dyn Fn((Option<&EQLRecord>, EQLRecord)) -> Result<Option<EQLRecord>> + 'a;
+
+/// A stream of hashed records, either an input or a partition file
+type HashedRecords<'a> = Box<dyn Iterator<Item = Result<HashedRecord>> + 'a>;
+
+/// Estimates the memory used by a value, without serializing it
+fn approximate_size(v: &Value) -> usize {
+    match v {
+        Value::String(s) => 24 + s.len(),
+        Value::Array(a) => 24 + a.iter().map(approximate_size).sum::<usize>(),
+        Value::Object(m) => {
+            24 + m
+                .iter()
+                .map(|(k, v)| 24 + k.len() + approximate_size(v))
+                .sum::<usize>()
+        }
+        _ => 16,
+    }
+}
+
+/// Estimates the memory used by a hashed record in the build table
+fn record_size((h, rec): &HashedRecord) -> usize {
+    48 + h.as_bytes().len() + approximate_size(&rec.key) + approximate_size(&rec.value)
+}
+
+/// A Grace hash join: the build side is kept in memory while it fits in the budget, otherwise build and probe
+/// records are spilled to partition files by hash, and each pair of partitions is joined in turn
+pub(crate) struct GraceHashJoin<'a> {
+    /// The folder of the database, spill files go in a sub folder
+    parent: &'a Path,
+    /// The spill folder, created on first spill
+    dir: Option<PathBuf>,
+    /// The memory budget in bytes for the build side
+    budget: usize,
+    /// The join function
+    join: Box<JoinFn<'a>>,
+    /// The number of partition sets created, to name files
+    sets: usize,
+}
+
+impl<'a> GraceHashJoin<'a> {
+    /// Creates a new join
+    /// # Arguments
+    /// * `parent` - the folder under which spill files are written
+    /// * `budget` - the memory budget in bytes for the build side
+    /// * `join` - the join function
+    pub(crate) fn new(parent: &'a Path, budget: usize, join: Box<JoinFn<'a>>) -> Self {
+        GraceHashJoin {
+            parent,
+            dir: None,
+            budget,
+            join,
+            sets: 0,
+        }
+    }
+
+    /// Joins the build and probe records, returning an iterator on the joined records
+    /// Each probe record is joined with every build record sharing its hash
+    /// The build side is read, and spilled with the probe side if it exceeds the budget, before returning. The joined
+    /// records are then produced as they are read: the probe records one by one, or partition by partition instead of in
+    /// probe order when the build side spills, so only one partition of the build side is in memory at a time
+    pub(crate) fn run<B, P>(self, build: B, probe: P) -> Result<HashJoinIter<'a>>
+    where
+        B: Iterator<Item = Result<HashedRecord>>,
+        P: Iterator<Item = Result<HashedRecord>> + 'a,
+    {
+        let mut it = HashJoinIter {
+            join: self,
+            table: HashMap::new(),
+            probe: None,
+            partitions: vec![],
+            out: VecDeque::new(),
+        };
+        it.load(build, Box::new(probe), 0)?;
+        Ok(it)
+    }
+
+    /// Joins a probe record with each of its matching build records, or with None if there is none
+    fn probe(&self, matches: Option<&Vec<EQLRecord>>, rec: EQLRecord, out: &mut VecDeque<EQLRecord>) -> Result<()> {
+        match matches.and_then(|m| m.split_last()) {
+            None => {
+                if let Some(rec2) = (self.join)((None, rec))? {
+                    out.push_back(rec2);
+                }
+            }
+            Some((last, others)) => {
+                for b in others {
+                    if let Some(rec2) = (self.join)((Some(b), rec.clone()))? {
+                        out.push_back(rec2);
+                    }
+                }
+                if let Some(rec2) = (self.join)((Some(last), rec))? {
+                    out.push_back(rec2);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a new set of partition files
+    fn partitions(&mut self, depth: usize) -> Result<Partitions> {
+        let dir = match &self.dir {
+            Some(d) => d.clone(),
+            None => {
+                let d = self.parent.join(format!(
+                    "hash_join_{}_{}",
+                    std::process::id(),
+                    JOIN_COUNTER.fetch_add(1, Ordering::SeqCst)
+                ));
+                fs::create_dir_all(&d)?;
+                self.dir = Some(d.clone());
+                d
+            }
+        };
+        self.sets += 1;
+        Partitions::create(&dir, self.sets, depth)
+    }
+}
+
+/// The joined records of a hash join, removing the spill folder when dropped
+pub(crate) struct HashJoinIter<'a> {
+    join: GraceHashJoin<'a>,
+    /// The build records of the partition being probed, by hash
+    table: HashMap<CanonicalValue, Vec<EQLRecord>>,
+    /// The probe records of the partition being probed
+    probe: Option<HashedRecords<'a>>,
+    /// The build and probe partition files left to join, with their depth, the next one last
+    partitions: Vec<(PathBuf, PathBuf, usize)>,
+    /// The joined records of the current probe record not returned yet
+    out: VecDeque<EQLRecord>,
+}
+
+impl<'a> HashJoinIter<'a> {
+    /// Loads one partition, the whole input at depth 0, either in memory to probe it or by spilling it to new partitions
+    fn load<B>(&mut self, build: B, probe: HashedRecords<'a>, depth: usize) -> Result<()>
+    where
+        B: Iterator<Item = Result<HashedRecord>>,
+    {
+        let mut table: HashMap<CanonicalValue, Vec<EQLRecord>> = HashMap::new();
+        let mut size = 0;
+        let mut spilled: Option<Partitions> = None;
+        for r in build {
+            let hr = r?;
+            if let Some(parts) = spilled.as_mut() {
+                parts.push(&hr)?;
+                continue;
+            }
+            size += record_size(&hr);
+            table.entry(hr.0).or_default().push(hr.1);
+            if size > self.join.budget && depth < MAX_DEPTH {
+                let mut parts = self.join.partitions(depth)?;
+                for (h, recs) in table.drain() {
+                    for rec in recs {
+                        parts.push(&(h.clone(), rec))?;
+                    }
+                }
+                spilled = Some(parts);
+            }
+        }
+        match spilled {
+            None => {
+                self.table = table;
+                self.probe = Some(probe);
+            }
+            Some(build_parts) => {
+                let mut probe_parts = self.join.partitions(depth)?;
+                for r in probe {
+                    probe_parts.push(&r?)?;
+                }
+                let pairs = build_parts.finish()?.into_iter().zip(probe_parts.finish()?);
+                // the partitions are joined in order, before the remaining partitions of the upper level
+                self.partitions.extend(pairs.map(|(b, p)| (b, p, depth + 1)).rev());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for HashJoinIter<'_> {
+    type Item = Result<EQLRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(rec) = self.out.pop_front() {
+                return Some(Ok(rec));
+            }
+            if let Some(probe) = self.probe.as_mut() {
+                match probe.next() {
+                    Some(Ok((h, rec))) => {
+                        if let Err(e) = self.join.probe(self.table.get(&h), rec, &mut self.out) {
+                            return Some(Err(e));
+                        }
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {
+                        self.probe = None;
+                        self.table.clear();
+                    }
+                }
+                continue;
+            }
+            let (b, p, depth) = self.partitions.pop()?;
+            let r = PartitionReader::open(b)
+                .and_then(|b| PartitionReader::open(p).map(|p| (b, p)))
+                .and_then(|(b, p)| self.load(b, Box::new(p), depth));
+            if let Err(e) = r {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+impl Drop for HashJoinIter<'_> {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.join.dir {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// A set of partition files being written
+struct Partitions {
+    /// The seed of the partitioning hash, so that each level splits records differently
+    seed: usize,
+    paths: Vec<PathBuf>,
+    writers: Vec<BufWriter<File>>,
+}
+
+impl Partitions {
+    fn create(dir: &Path, set: usize, seed: usize) -> Result<Self> {
+        let mut paths = vec![];
+        let mut writers = vec![];
+        for i in 0..PARTITIONS {
+            let p = dir.join(format!("{}_{}.part", set, i));
+            writers.push(BufWriter::new(File::create(&p)?));
+            paths.push(p);
+        }
+        Ok(Partitions {
+            seed,
+            paths,
+            writers,
+        })
+    }
+
+    /// Writes a record in the partition of its hash
+    fn push(&mut self, (h, rec): &HashedRecord) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        self.seed.hash(&mut hasher);
+        h.hash(&mut hasher);
+        let w = &mut self.writers[(hasher.finish() % PARTITIONS) as usize];
+        write_bytes(w, h.as_bytes())?;
+        write_bytes(w, &serde_json::to_vec(rec)?)?;
+        Ok(())
+    }
+
+    /// Flushes the partitions and returns their paths
+    fn finish(self) -> Result<Vec<PathBuf>> {
+        for mut w in self.writers {
+            w.flush()?;
+        }
+        Ok(self.paths)
+    }
+}
+
+/// Reads the records of a partition file, deleting the file once done
+struct PartitionReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl PartitionReader {
+    fn open(path: PathBuf) -> Result<Self> {
+        let reader = BufReader::new(File::open(&path)?);
+        Ok(PartitionReader { path, reader })
+    }
+
+    fn read_record(&mut self) -> Result<Option<HashedRecord>> {
+        match read_bytes(&mut self.reader)? {
+            Some(h) => match read_bytes(&mut self.reader)? {
+                Some(r) => Ok(Some((CanonicalValue::from_bytes(h), serde_json::from_slice(&r)?))),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl Iterator for PartitionReader {
+    type Item = Result<HashedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+impl Drop for PartitionReader {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hashed(i: u64) -> Result<HashedRecord> {
+        Ok((
+            CanonicalValue::new(&json!(i % 100), false),
+            EQLRecord::new(json!(i), json!({ "id": i % 100, "payload": "some text to fill memory" })),
+        ))
+    }
+
+    #[test]
+    fn test_spill() -> Result<()> {
+        let dir = Path::new("test_grace_join.tmp");
+        fs::create_dir_all(dir)?;
+        let join = |(b, p): (Option<&EQLRecord>, EQLRecord)| {
+            Ok(b.map(|b| EQLRecord::new(p.key, b.key.clone())))
+        };
+        let mut in_memory = GraceHashJoin::new(dir, DEFAULT_JOIN_MEMORY_BUDGET, Box::new(join))
+            .run((0..1000).map(hashed), (0..150).map(hashed))?
+            .collect::<Result<Vec<_>>>()?;
+        let mut spilled = GraceHashJoin::new(dir, 1024, Box::new(join))
+            .run((0..1000).map(hashed), (0..150).map(hashed))?
+            .collect::<Result<Vec<_>>>()?;
+        // each probe record is joined with the 10 build records sharing its hash
+        assert_eq!(1500, in_memory.len());
+        let sort_key = |r: &EQLRecord| (r.key.as_u64(), r.value.as_u64());
+        in_memory.sort_by_key(sort_key);
+        spilled.sort_by_key(sort_key);
+        assert_eq!(in_memory, spilled);
+        assert_eq!(0, fs::read_dir(dir)?.count());
+        // the spill folder is removed even if the joined records are not all read
+        let mut partial = GraceHashJoin::new(dir, 1024, Box::new(join)).run((0..1000).map(hashed), (0..150).map(hashed))?;
+        assert!(partial.next().is_some());
+        assert_eq!(1, fs::read_dir(dir)?.count());
+        drop(partial);
+        assert_eq!(0, fs::read_dir(dir)?.count());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
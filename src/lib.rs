@@ -96,18 +96,21 @@ eql.execute(hash_join(
 
 */
 
-use rhai::Engine;
+use rhai::{
+    serde::{from_dynamic, to_dynamic},
+    Dynamic, Engine, Scope, AST,
+};
 use rocksdb::{
-    ColumnFamilyDescriptor, Direction, IteratorMode, Options, ReadOptions, WriteBatch, DB,
+    ColumnFamily, ColumnFamilyDescriptor, Options, ReadOptions, SstFileWriter, WriteBatch, DB,
 };
 
 use serde_json::{Map, Value};
 use std::{io::{BufReader, BufWriter}};
+use std::cmp::Ordering;
 use std::path::{Path, PathBuf};
 use std::{
-    cmp::Ordering,
-    collections::BTreeMap,
-    fs::{File, OpenOptions},
+    collections::{BTreeMap, BTreeSet},
+    fs::{create_dir_all, remove_dir_all, File, OpenOptions},
 };
 use std::{
     collections::{HashMap, HashSet},
@@ -127,6 +130,55 @@ pub use parse::*;
 mod script;
 pub use script::*;
 
+mod format;
+pub use format::*;
+
+mod bulk;
+pub use bulk::*;
+
+mod sequence;
+pub use sequence::*;
+use sequence::{decode_counter, SEQUENCE_CF};
+
+mod storage;
+pub use storage::*;
+use storage::{Location, SHARED_CF};
+
+mod crypt;
+pub use crypt::*;
+
+mod maintenance;
+pub use maintenance::*;
+
+mod join;
+pub use join::{HashJoinOptions, DEFAULT_JOIN_MEMORY_BUDGET};
+use join::GraceHashJoin;
+
+mod merge;
+use merge::merge_join;
+
+mod skip;
+use skip::SkipScan;
+
+mod compare;
+pub use compare::*;
+use compare::{decode_ordered, encode_ordered};
+
+mod index;
+pub use index::*;
+
+mod text;
+pub use text::*;
+use text::Postings;
+
+mod geo;
+pub use geo::GeoError;
+use geo::{check_coordinates, coordinates, covering_cells, distance, geohash, in_box, radius_box, GEOHASH_PRECISION};
+use index::{entry_value, included_values, is_pointer, pointer_values, split_entry_value, DuplicateFinder, EncodedRange};
+use maintenance::{cf_usage, db_stats, location_usage, IndexCheck};
+use crypt::{decrypt, encrypt, is_encrypted, keyed_hash};
+use bulk::{write_sst, ExternalSorter};
+
 use nom::Finish;
 
 /// Metadata errors
@@ -138,15 +190,124 @@ pub enum MetadataError {
         rec_type: String,
         index_name: String,
     },
+    /// The record type already exists with another storage
+    #[error("record type {rec_type} already exists with storage {storage:?}")]
+    StorageMismatch { rec_type: String, storage: Storage },
+    /// The record type does not exist
+    #[error("unknown record type {rec_type}")]
+    UnknownType { rec_type: String },
+    /// The index does not exist
+    #[error("unknown index {index_name} for record type {rec_type}")]
+    UnknownIndex {
+        rec_type: String,
+        index_name: String,
+    },
+}
+
+/// Conditional write errors
+#[derive(Error, Debug)]
+pub enum ConflictError {
+    /// The record was expected to be absent
+    #[error("record {key} of type {rec_type} already exists with value {found}")]
+    Exists {
+        rec_type: String,
+        key: Value,
+        found: Value,
+    },
+    /// The record does not have the expected value or version, found is None if the record does not exist
+    #[error("record {key} of type {rec_type} does not match the expected state, found {found:?}")]
+    Mismatch {
+        rec_type: String,
+        key: Value,
+        found: Option<Value>,
+    },
+}
+
+/// The state a record is expected to be in for a conditional write to apply
+/// Conditional writes borrow the database mutably, so no other write can happen between the check and the write
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    /// The record has this value
+    Value(Value),
+    /// The record has this version, as returned by `get_versioned`
+    Version(u64),
 }
 
 
 /// A batch of operations, in the same transaction
+/// Nothing is changed in the database, including its schema, until the batch is written
 #[derive(Default)]
 pub struct EQLBatch {
+    /// The writes on existing record types
     batch: WriteBatch,
+    /// The inserts on record types that don't exist yet, applied once the record types are created at write time
+    pending: Vec<(String, Vec<u8>, Value)>,
+    /// The number of operations per record type
+    counts: BTreeMap<String, BatchCounts>,
+    /// The last value written by the batch for each record of a type with indices, None if deleted
+    written: HashMap<(String, Vec<u8>), Option<Value>>,
+    /// The record key owning indexed values in the batch, by record type, unique index and indexed values
+    unique_values: HashMap<(String, String, Vec<u8>), Vec<u8>>,
+}
+
+/// The number of operations in a batch for a record type
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchCounts {
+    /// The number of inserts
+    pub inserts: usize,
+    /// The number of deletes
+    pub deletes: usize,
+}
+
+impl EQLBatch {
+    /// The number of operations per record type
+    pub fn counts(&self) -> &BTreeMap<String, BatchCounts> {
+        &self.counts
+    }
+
+    /// The number of operations for a record type
+    /// # Arguments
+    /// * `rec_type` - The record type
+    pub fn count<T: AsRef<str>>(&self, rec_type: T) -> BatchCounts {
+        self.counts
+            .get(rec_type.as_ref())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The total number of operations
+    pub fn len(&self) -> usize {
+        self.counts.values().map(|c| c.inserts + c.deletes).sum()
+    }
+
+    /// Is the batch empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The record types that will be created when the batch is written
+    pub fn new_types(&self) -> BTreeSet<&str> {
+        self.pending.iter().map(|(t, _, _)| t.as_str()).collect()
+    }
+
+    /// Rolls back the batch, discarding all its operations and pending schema changes
+    pub fn clear(&mut self) {
+        self.batch.clear();
+        self.pending.clear();
+        self.counts.clear();
+        self.written.clear();
+        self.unique_values.clear();
+    }
+
+    /// Get the counts for a record type, to update them
+    fn counts_mut(&mut self, rec_type: &str) -> &mut BatchCounts {
+        self.counts.entry(String::from(rec_type)).or_default()
+    }
 }
 
+/// The version of the encoding of index keys
+const INDEX_KEY_VERSION: u32 = 1;
+
 /// The database structure
 pub struct EQLDB {
     /// The underlying database
@@ -157,6 +318,12 @@ pub struct EQLDB {
     pub metadata: Metadata,
     /// The scripting engine
     pub scripting_engine: Engine,
+    /// The provider of encryption keys, needed to use encrypted record types
+    key_provider: Option<Box<dyn KeyProvider>>,
+    /// The compiled script filters of partial indices, by record type and index name
+    index_filters: HashMap<(String, String), AST>,
+    /// The compiled expressions of index components that are not pointers, by expression
+    index_scripts: HashMap<String, AST>,
 }
 
 impl EQLDB {
@@ -173,8 +340,14 @@ impl EQLDB {
             Metadata::default()
         };
 
-        let mut cfs = vec![];
+        let mut cfs = vec![
+            ColumnFamilyDescriptor::new(SEQUENCE_CF, Options::default()),
+            ColumnFamilyDescriptor::new(SHARED_CF, Options::default()),
+        ];
         for (rec_type, indices) in metadata.indices.iter() {
+            if metadata.storage.get(rec_type) == Some(&Storage::Shared) {
+                continue;
+            }
             let mut cf_opts = Options::default();
             cf_opts.set_max_write_buffer_number(16);
             cfs.push(ColumnFamilyDescriptor::new(rec_type, cf_opts));
@@ -191,12 +364,72 @@ impl EQLDB {
         db_opts.create_if_missing(true);
 
         let db = DB::open_cf_descriptors(&db_opts, path, cfs).unwrap();
-        Ok(EQLDB {
+        let scripting_engine = eql_engine();
+        let mut index_filters = HashMap::new();
+        for (rec_type, options) in metadata.index_options.iter() {
+            for (idx_name, o) in options.iter() {
+                if let Some(IndexFilter::Script(script)) = &o.filter {
+                    index_filters.insert(
+                        (rec_type.clone(), idx_name.clone()),
+                        scripting_engine.compile(script)?,
+                    );
+                }
+            }
+        }
+        let mut index_scripts = HashMap::new();
+        for on in metadata.indices.values().flat_map(|idxs| idxs.values()).flatten() {
+            if !is_pointer(on) && !index_scripts.contains_key(on) {
+                index_scripts.insert(on.clone(), scripting_engine.compile_expression(on)?);
+            }
+        }
+        let mut eql = EQLDB {
             db,
             metadata_path: mdp,
             metadata,
-            scripting_engine:eql_engine(),
-        })
+            scripting_engine,
+            key_provider: None,
+            index_filters,
+            index_scripts,
+        };
+        eql.upgrade_index_keys()?;
+        Ok(eql)
+    }
+
+    /// Rewrites the index keys written with an older encoding
+    /// Version 0 stored indexed values as JSON, which does not sort by value, version 1 uses `encode_ordered`
+    fn upgrade_index_keys(&mut self) -> Result<()> {
+        if self.metadata.index_key_version >= INDEX_KEY_VERSION {
+            return Ok(());
+        }
+        for (rec_type, idxs) in self.metadata.indices.iter() {
+            for (idx_name, on) in idxs.iter() {
+                if let Some(loc) = self.index_location(rec_type, idx_name) {
+                    let mut b = WriteBatch::default();
+                    for (k, v) in loc.iter(&self.db, &[], ReadOptions::default()) {
+                        let mut parts = k.splitn(on.len() + 1, |b| *b == 0);
+                        let mut upgraded = vec![];
+                        for part in parts.by_ref().take(on.len()) {
+                            upgraded.extend(encode_ordered(&serde_json::from_slice(part)?));
+                            upgraded.push(0);
+                        }
+                        upgraded.extend_from_slice(parts.next().unwrap_or_default());
+                        b.delete_cf(loc.cf, loc.key(&k));
+                        b.put_cf(loc.cf, loc.key(upgraded), v);
+                        if b.len() > 1000 {
+                            self.db.write(b)?;
+                            b = WriteBatch::default();
+                        }
+                    }
+                    self.db.write(b)?;
+                }
+            }
+        }
+        let upgraded = !self.metadata.indices.is_empty();
+        self.metadata.index_key_version = INDEX_KEY_VERSION;
+        if upgraded {
+            self.save_metadata()?;
+        }
+        Ok(())
     }
 
      /// Opens the database
@@ -207,182 +440,1488 @@ impl EQLDB {
         Self::open(path)
     }
 
-    /// Destroys fully a database, removing the folder and metadata
-    /// # Arguments
-    /// * `path` - The folder where the database and metadata reside
-    pub fn destroy<P: AsRef<Path>>(path: P) -> Result<()> {
-        let mdp = path.as_ref().join("metadata.json");
-        if mdp.is_file() {
-            remove_file(mdp)?;
+    /// Destroys fully a database, removing the folder and metadata
+    /// # Arguments
+    /// * `path` - The folder where the database and metadata reside
+    pub fn destroy<P: AsRef<Path>>(path: P) -> Result<()> {
+        let mdp = path.as_ref().join("metadata.json");
+        if mdp.is_file() {
+            remove_file(mdp)?;
+        }
+        DB::destroy(&Options::default(), path)?;
+        Ok(())
+    }
+
+    /// Adds an index
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name, must be unique for a given record type
+    /// * `on` - The list of JSON pointers to apply to values and index, a `*` segment indexes each element of an array and `~2` escapes a `*` key, or of Rhai expressions computing the indexed value from the record `rec`
+    pub fn add_index<T: AsRef<str>, IT: AsRef<str>, OT: AsRef<str>>(
+        &mut self,
+        rec_type: T,
+        idx_name: IT,
+        on: Vec<OT>,
+    ) -> Result<()> {
+        self.add_index_with(rec_type, idx_name, on, IndexOptions::default())
+    }
+
+    /// Adds a unique index: inserting a record with the same indexed values as another record fails
+    /// with `IndexError::UniqueViolation`, and the index cannot be created if existing records share indexed values
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name, must be unique for a given record type
+    /// * `on` - The list of JSON pointers to apply to values and index, a `*` segment indexes each element of an array and `~2` escapes a `*` key, or of Rhai expressions computing the indexed value from the record `rec`
+    pub fn add_unique_index<T: AsRef<str>, IT: AsRef<str>, OT: AsRef<str>>(
+        &mut self,
+        rec_type: T,
+        idx_name: IT,
+        on: Vec<OT>,
+    ) -> Result<()> {
+        self.add_index_with(
+            rec_type,
+            idx_name,
+            on,
+            IndexOptions {
+                unique: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Adds a full-text index, to search the records with `text_search`
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name, must be unique for a given record type
+    /// * `on` - The list of JSON pointers or Rhai expressions giving the strings to index, other values being ignored
+    /// * `text` - How to split the strings into terms
+    pub fn add_text_index<T: AsRef<str>, IT: AsRef<str>, OT: AsRef<str>>(
+        &mut self,
+        rec_type: T,
+        idx_name: IT,
+        on: Vec<OT>,
+        text: TextOptions,
+    ) -> Result<()> {
+        self.add_index_with(
+            rec_type,
+            idx_name,
+            on,
+            IndexOptions {
+                text: Some(text),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Adds a geospatial index, to search the records with `geo_within_box` and `geo_within_radius`
+    /// Records without valid numeric coordinates are not indexed
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name, must be unique for a given record type
+    /// * `lat` - The JSON pointer or Rhai expression giving the latitude
+    /// * `lon` - The JSON pointer or Rhai expression giving the longitude
+    pub fn add_geo_index<T: AsRef<str>, IT: AsRef<str>, OT: AsRef<str>>(
+        &mut self,
+        rec_type: T,
+        idx_name: IT,
+        lat: OT,
+        lon: OT,
+    ) -> Result<()> {
+        self.add_index_with(
+            rec_type,
+            idx_name,
+            vec![lat, lon],
+            IndexOptions {
+                geo: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Adds an index with options
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name, must be unique for a given record type
+    /// * `on` - The list of JSON pointers to apply to values and index, a `*` segment indexes each element of an array and `~2` escapes a `*` key, or of Rhai expressions computing the indexed value from the record `rec`
+    /// * `options` - The index options
+    pub fn add_index_with<T: AsRef<str>, IT: AsRef<str>, OT: AsRef<str>>(
+        &mut self,
+        rec_type: T,
+        idx_name: IT,
+        on: Vec<OT>,
+        options: IndexOptions,
+    ) -> Result<()> {
+        let ref_type = String::from(rec_type.as_ref());
+        let ref_idx = String::from(idx_name.as_ref());
+        if let Some(IndexProtection::Forbidden) = self.index_protection(&ref_type) {
+            return Err(EncryptionError::ForbiddenIndex { rec_type: ref_type }.into());
+        }
+        let hash_key = self.index_hash_key(&ref_type)?;
+        let filter_ast = match &options.filter {
+            Some(IndexFilter::Script(script)) => Some(self.scripting_engine.compile(script)?),
+            _ => None,
+        };
+        if let Some(include) = options.include.iter().find(|i| !is_pointer(i)) {
+            return Err(IndexError::InvalidInclude {
+                rec_type: ref_type,
+                index_name: ref_idx,
+                include: include.clone(),
+            }
+            .into());
+        }
+        if hash_key.is_some() && !options.include.is_empty() {
+            return Err(EncryptionError::IncludedValues { rec_type: ref_type }.into());
+        }
+        if options.geo {
+            if on.len() != 2 {
+                return Err(GeoError::Components {
+                    rec_type: ref_type,
+                    index_name: ref_idx,
+                }
+                .into());
+            }
+            if options.unique || !options.include.is_empty() || options.text.is_some() || hash_key.is_some() {
+                return Err(GeoError::InvalidIndex {
+                    rec_type: ref_type,
+                    index_name: ref_idx,
+                }
+                .into());
+            }
+        }
+        if options.text.is_some() && (options.unique || !options.include.is_empty()) {
+            return Err(TextError::InvalidIndex {
+                rec_type: ref_type,
+                index_name: ref_idx,
+            }
+            .into());
+        }
+        let mut scripts = vec![];
+        for o in on.iter().map(|o| o.as_ref()).filter(|o| !is_pointer(o)) {
+            scripts.push((String::from(o), self.scripting_engine.compile_expression(o)?));
+        }
+        let m = self
+            .metadata
+            .indices
+            .entry(ref_type.clone())
+            .or_insert_with(HashMap::new);
+        if m.contains_key(&ref_idx) {
+            return Err(MetadataError::DuplicateIndex {
+                rec_type: ref_type,
+                index_name: ref_idx,
+            }
+            .into());
+        }
+        m.insert(
+            ref_idx.clone(),
+            on.iter().map(|s| String::from(s.as_ref())).collect(),
+        );
+        if options != IndexOptions::default() {
+            self.metadata
+                .index_options
+                .entry(ref_type.clone())
+                .or_default()
+                .insert(ref_idx.clone(), options.clone());
+        }
+        if let Some(ast) = filter_ast {
+            self.index_filters
+                .insert((ref_type.clone(), ref_idx.clone()), ast);
+        }
+        self.index_scripts.extend(scripts);
+        if !self.is_shared(&ref_type) {
+            let idx_cf = index_cf_name(&ref_type, &ref_idx);
+            self.db.create_cf(&idx_cf, &Options::default())?;
+        }
+        let loc = self.index_location(&ref_type, &ref_idx).unwrap();
+
+        self.save_metadata()?;
+
+        let built = self.execute(scan(rec_type.as_ref())).and_then(|mut it| {
+            let b = it.try_fold(WriteBatch::default(), |mut b, rec| {
+                let kv = serde_json::to_vec(&rec.key).unwrap();
+                for (ix_key, ev) in self.index_entries(&ref_type, &ref_idx, &on, &kv, &rec.value, hash_key.as_deref())? {
+                    b.put_cf(loc.cf, loc.key(ix_key), ev);
+                }
+                if b.len() > 1000 {
+                    self.db.write(b)?;
+                    return Ok(WriteBatch::default());
+                }
+                let r: Result<WriteBatch> = Ok(b);
+                r
+            })?;
+            Ok(self.db.write(b)?)
+        });
+        if let Err(e) = built {
+            // a filter failing on existing records must not leave a partial index behind
+            self.delete_index(&ref_type, &ref_idx)?;
+            return Err(e);
+        }
+
+        if options.unique {
+            let keys = self.duplicate_index_keys(&ref_type, &ref_idx, on.len(), hash_key.as_deref())?;
+            if !keys.is_empty() {
+                self.delete_index(&ref_type, &ref_idx)?;
+                return Err(IndexError::DuplicateValues {
+                    rec_type: ref_type,
+                    index_name: ref_idx,
+                    keys,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if a record belongs to an index, which is always the case unless the index is partial
+    fn index_matches(&self, ref_type: &str, idx_name: &str, kv: &[u8], value: &Value) -> Result<bool> {
+        let filter = self
+            .metadata
+            .index_options
+            .get(ref_type)
+            .and_then(|o| o.get(idx_name))
+            .and_then(|o| o.filter.as_ref());
+        match filter {
+            None => Ok(true),
+            Some(IndexFilter::Equals {
+                pointer,
+                value: expected,
+            }) => Ok(value
+                .pointer(pointer)
+                .map(|v| compare_values(v, expected).is_eq())
+                .unwrap_or_default()),
+            Some(IndexFilter::Script(_)) => {
+                let ast = &self.index_filters[&(String::from(ref_type), String::from(idx_name))];
+                let matches = self.eval_on_record(ast, kv, value).and_then(|d| {
+                    d.as_bool()
+                        .map_err(|t| format!("the filter returned {} instead of a boolean", t))
+                });
+                Ok(matches.map_err(|message| IndexError::Filter {
+                    rec_type: String::from(ref_type),
+                    index_name: String::from(idx_name),
+                    message,
+                })?)
+            }
+        }
+    }
+
+    /// Evaluates a compiled script with the record available as `rec`
+    fn eval_on_record(&self, ast: &AST, kv: &[u8], value: &Value) -> std::result::Result<Dynamic, String> {
+        let key: Value = serde_json::from_slice(kv).map_err(|e| e.to_string())?;
+        let rec = to_dynamic(EQLRecord::new(key, value.clone())).map_err(|e| e.to_string())?;
+        let mut scope = Scope::new();
+        scope.push_constant_dynamic("rec", rec);
+        self.scripting_engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Computes the values of each index component for a record: the values designated by a pointer,
+    /// or the result of an expression, an expression returning `()` giving null
+    fn component_values<T: AsRef<str>>(
+        &self,
+        ref_type: &str,
+        idx_name: &str,
+        on: &[T],
+        kv: &[u8],
+        value: &Value,
+    ) -> Result<Vec<Vec<Value>>> {
+        on.iter()
+            .map(|o| {
+                let o = o.as_ref();
+                if is_pointer(o) {
+                    return Ok(pointer_values(value, o).into_iter().cloned().collect());
+                }
+                let computed = self
+                    .eval_on_record(&self.index_scripts[o], kv, value)
+                    .and_then(|d| from_dynamic::<Value>(&d).map_err(|e| e.to_string()));
+                match computed {
+                    Ok(v) => Ok(vec![v]),
+                    Err(message) => Err(IndexError::Expression {
+                        rec_type: String::from(ref_type),
+                        index_name: String::from(idx_name),
+                        expression: String::from(o),
+                        message,
+                    }
+                    .into()),
+                }
+            })
+            .collect()
+    }
+
+    /// Builds the index entries of a record, keys and values, none if the record does not belong to the index
+    /// A full-text index gets an entry per term of the strings found by its components, holding the positions of the term
+    fn index_entries<T: AsRef<str>>(
+        &self,
+        ref_type: &str,
+        idx_name: &str,
+        on: &[T],
+        kv: &[u8],
+        value: &Value,
+        hash_key: Option<&[u8]>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if !self.index_matches(ref_type, idx_name, kv, value)? {
+            return Ok(vec![]);
+        }
+        let values = self.component_values(ref_type, idx_name, on, kv, value)?;
+        if self.is_geo_index(ref_type, idx_name) {
+            return Ok(match coordinates(&values) {
+                Some((lat, lon)) => {
+                    let ev = entry_value(kv, Some(&serde_json::json!({ "lat": lat, "lon": lon })));
+                    index_keys(&[vec![Value::String(geohash(lat, lon, GEOHASH_PRECISION))]], &kv, hash_key)
+                        .into_iter()
+                        .map(|k| (k, ev.clone()))
+                        .collect()
+                }
+                None => vec![],
+            });
+        }
+        if let Some(text) = self.text_options(ref_type, idx_name) {
+            let texts: Vec<&str> = values.iter().flatten().filter_map(|v| v.as_str()).collect();
+            return Ok(text
+                .postings(&texts)
+                .into_iter()
+                .flat_map(|(term, positions)| {
+                    let ev = entry_value(kv, Some(&serde_json::json!({ "positions": positions })));
+                    index_keys(&[vec![Value::String(term)]], &kv, hash_key)
+                        .into_iter()
+                        .map(move |k| (k, ev.clone()))
+                })
+                .collect());
+        }
+        let ev = self.index_entry_value(ref_type, idx_name, kv, value);
+        Ok(index_keys(&values, &kv, hash_key)
+            .into_iter()
+            .map(|k| (k, ev.clone()))
+            .collect())
+    }
+
+    /// Returns true if an index is a geospatial index
+    fn is_geo_index(&self, ref_type: &str, idx_name: &str) -> bool {
+        self.metadata
+            .index_options
+            .get(ref_type)
+            .and_then(|o| o.get(idx_name))
+            .map(|o| o.geo)
+            .unwrap_or_default()
+    }
+
+    /// Searches a geospatial index: reads the entries of the cells covering a box, keeping the points matching
+    /// a filter that returns their distance, and sorts them by distance
+    /// # Arguments
+    /// * `ref_type` - the record type
+    /// * `idx_name` - the index name
+    /// * `area` - the box covering the searched area: south, west, north and east edges
+    /// * `filter` - returns the distance of a point in the searched area, none for other points
+    fn geo_search<F: Fn(f64, f64) -> Option<f64>>(
+        &self,
+        ref_type: &str,
+        idx_name: &str,
+        area: (f64, f64, f64, f64),
+        filter: F,
+    ) -> Result<Vec<EQLRecord>> {
+        let loc = match self.index_location(ref_type, idx_name) {
+            Some(loc) => loc,
+            None => return Ok(vec![]),
+        };
+        if !self.is_geo_index(ref_type, idx_name) {
+            return Err(GeoError::NotGeoIndex {
+                rec_type: String::from(ref_type),
+                index_name: String::from(idx_name),
+            }
+            .into());
+        }
+        let (south, west, north, east) = area;
+        let mut found = vec![];
+        for cell in covering_cells(south, west, north, east) {
+            // the encoding of a string without its terminator is a prefix of the encodings of the strings it starts
+            let mut p = encode_ordered(&Value::String(cell));
+            p.truncate(p.len() - 2);
+            for (k, v) in loc.iter(&self.db, &p, ReadOptions::default()) {
+                if !k.starts_with(&p) {
+                    break;
+                }
+                let (kv, point) = split_entry_value(&v);
+                let lat = point.as_ref().and_then(|p| p.get("lat")?.as_f64());
+                let lon = point.as_ref().and_then(|p| p.get("lon")?.as_f64());
+                if let Some(d) = lat.zip(lon).and_then(|(lat, lon)| filter(lat, lon)) {
+                    found.push((d, kv.to_vec()));
+                }
+            }
+        }
+        found.sort_by(|(d1, k1), (d2, k2)| d1.partial_cmp(d2).unwrap_or(Ordering::Equal).then_with(|| k1.cmp(k2)));
+        Ok(found
+            .into_iter()
+            .map(|(d, kv)| EQLRecord::new(serde_json::from_slice(&kv).unwrap(), serde_json::json!({ "distance": d })))
+            .collect())
+    }
+
+    /// Returns the options of a full-text index, none for other indices
+    fn text_options(&self, ref_type: &str, idx_name: &str) -> Option<&TextOptions> {
+        self.metadata
+            .index_options
+            .get(ref_type)
+            .and_then(|o| o.get(idx_name))
+            .and_then(|o| o.text.as_ref())
+    }
+
+    /// Builds the value of the index entries of a record: its key, and the included values for a covering index
+    fn index_entry_value(&self, ref_type: &str, idx_name: &str, kv: &[u8], value: &Value) -> Vec<u8> {
+        let include = self
+            .metadata
+            .index_options
+            .get(ref_type)
+            .and_then(|o| o.get(idx_name))
+            .map(|o| &o.include)
+            .filter(|include| !include.is_empty());
+        entry_value(kv, include.map(|include| included_values(value, include)).as_ref())
+    }
+
+    /// Returns the keys of the records sharing indexed values with another record, ignoring missing or null values
+    /// Index entries are sorted by indexed values, so records sharing values are next to each other
+    fn duplicate_index_keys(
+        &self,
+        ref_type: &str,
+        idx_name: &str,
+        components: usize,
+        hash_key: Option<&[u8]>,
+    ) -> Result<Vec<Value>> {
+        let loc = self.index_location(ref_type, idx_name).unwrap();
+        let mut finder = DuplicateFinder::new(components, index_component(&Value::Null, hash_key));
+        for (k, v) in loc.iter(&self.db, &[], ReadOptions::default()) {
+            finder.push(&k, &v)?;
+        }
+        Ok(finder.finish())
+    }
+
+    /// Returns true if an index is unique
+    fn is_unique_index(&self, ref_type: &str, idx_name: &str) -> bool {
+        self.metadata
+            .index_options
+            .get(ref_type)
+            .and_then(|o| o.get(idx_name))
+            .map(|o| o.unique)
+            .unwrap_or_default()
+    }
+
+    /// Checks that a record does not break the unique indices of its record type, in the database or in the batch,
+    /// and records its indexed values in the batch
+    /// Index entries of records changed by the batch are ignored, as the batch knows their new values
+    fn check_unique(&self, batch: &mut EQLBatch, ref_type: &str, kv: &[u8], value: &Value) -> Result<()> {
+        let (idxs, options) = match (
+            self.metadata.indices.get(ref_type),
+            self.metadata.index_options.get(ref_type),
+        ) {
+            (Some(idxs), Some(options)) => (idxs, options),
+            _ => return Ok(()),
+        };
+        let hash_key = self.index_hash_key(ref_type)?;
+        for (idx_name, on) in idxs.iter() {
+            if !options.get(idx_name).map(|o| o.unique).unwrap_or_default() {
+                continue;
+            }
+            let prefixes_of = |k: &[u8], v: &Value| -> Result<Vec<Vec<u8>>> {
+                if self.index_matches(ref_type, idx_name, k, v)? {
+                    let values = self.component_values(ref_type, idx_name, on, k, v)?;
+                    Ok(index_prefixes(&values, hash_key.as_deref(), true))
+                } else {
+                    Ok(vec![])
+                }
+            };
+            for prefix in prefixes_of(kv, value)? {
+                let violation = |existing: &[u8]| -> Result<()> {
+                    Err(IndexError::UniqueViolation {
+                        rec_type: String::from(ref_type),
+                        index_name: idx_name.clone(),
+                        key: serde_json::from_slice(kv)?,
+                        existing: serde_json::from_slice(existing)?,
+                    }
+                    .into())
+                };
+                if let (Some(loc), Some(idx_loc)) = (self.location(ref_type), self.index_location(ref_type, idx_name)) {
+                    for (k, v) in idx_loc.iter(&self.db, &prefix, ReadOptions::default()) {
+                        if !k.starts_with(&prefix) {
+                            break;
+                        }
+                        let (kv2, _) = split_entry_value(&v);
+                        if kv2 == kv || batch.written.contains_key(&(String::from(ref_type), kv2.to_vec())) {
+                            continue;
+                        }
+                        if let Some(stored) = self.db.get_cf(loc.cf, loc.key(kv2))? {
+                            let v2 = self.decode_stored(ref_type, kv2, &stored)?;
+                            if prefixes_of(kv2, &v2)?.contains(&prefix) {
+                                return violation(kv2);
+                            }
+                        }
+                    }
+                }
+                let ukey = (String::from(ref_type), idx_name.clone(), prefix);
+                if let Some(kv2) = batch.unique_values.get(&ukey) {
+                    if kv2.as_slice() != kv {
+                        if let Some(Some(v2)) = batch.written.get(&(String::from(ref_type), kv2.clone())) {
+                            if prefixes_of(kv2, v2)?.contains(&ukey.2) {
+                                return violation(kv2);
+                            }
+                        }
+                    }
+                }
+                batch.unique_values.insert(ukey, kv.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the metadata to a file
+    fn save_metadata(&self) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.metadata_path.clone())?;
+        serde_json::to_writer(BufWriter::new(file), &self.metadata)?;
+        Ok(())
+    }
+
+    /// Deletes an index
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name, must be unique for a given record type
+    pub fn delete_index<T: AsRef<str>, IT: AsRef<str>>(
+        &mut self,
+        rec_type: T,
+        idx_name: IT,
+    ) -> Result<()> {
+        if let Some(m) = self.metadata.indices.get_mut(rec_type.as_ref()) {
+            if m.remove(idx_name.as_ref()).is_some() {
+                if let Some(o) = self.metadata.index_options.get_mut(rec_type.as_ref()) {
+                    o.remove(idx_name.as_ref());
+                }
+                self.index_filters.remove(&(
+                    String::from(rec_type.as_ref()),
+                    String::from(idx_name.as_ref()),
+                ));
+                if self.metadata.storage.get(rec_type.as_ref()) == Some(&Storage::Shared) {
+                    let cf = self.db.cf_handle(SHARED_CF).unwrap();
+                    let loc = Location::shared(cf, &index_cf_name(rec_type.as_ref(), idx_name.as_ref()));
+                    let mut b = WriteBatch::default();
+                    loc.delete_all(&mut b);
+                    self.db.write(b)?;
+                } else {
+                    let idx_cf = index_cf_name(rec_type.as_ref(), idx_name.as_ref());
+                    self.db.drop_cf(&idx_cf)?;
+                }
+            }
+
+            self.save_metadata()?;
+        }
+        Ok(())
+    }
+
+    /// Compacts the records of a record type
+    /// # Arguments
+    /// * `rec_type` - The record type
+    pub fn compact_type<T: AsRef<str>>(&self, rec_type: T) -> Result<()> {
+        let ref_type = rec_type.as_ref();
+        let loc = self.location(ref_type).ok_or_else(|| MetadataError::UnknownType {
+            rec_type: String::from(ref_type),
+        })?;
+        loc.compact(&self.db);
+        Ok(())
+    }
+
+    /// Compacts the entries of an index
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name
+    pub fn compact_index<T: AsRef<str>, IT: AsRef<str>>(&self, rec_type: T, idx_name: IT) -> Result<()> {
+        let loc = self
+            .index_location(rec_type.as_ref(), idx_name.as_ref())
+            .ok_or_else(|| MetadataError::UnknownIndex {
+                rec_type: String::from(rec_type.as_ref()),
+                index_name: String::from(idx_name.as_ref()),
+            })?;
+        loc.compact(&self.db);
+        Ok(())
+    }
+
+    /// Compares an index with the entries its records give, reporting the missing and orphaned entries
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name
+    pub fn verify_index<T: AsRef<str>, IT: AsRef<str>>(&self, rec_type: T, idx_name: IT) -> Result<IndexReport> {
+        self.check_index(rec_type.as_ref(), idx_name.as_ref(), IndexCheck::Verify)
+    }
+
+    /// Fixes an index in place: writes the missing entries and deletes the orphaned ones, leaving the correct entries
+    /// untouched. Returns the report of what was fixed
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name
+    pub fn repair_index<T: AsRef<str>, IT: AsRef<str>>(&mut self, rec_type: T, idx_name: IT) -> Result<IndexReport> {
+        self.check_index(rec_type.as_ref(), idx_name.as_ref(), IndexCheck::Repair)
+    }
+
+    /// Rebuilds an index from its records, rewriting all the entries they give and deleting the others
+    /// The entries are sorted on disk if they do not fit in memory, then written with the deletions to a single SST
+    /// file ingested at once, so that readers see either the old or the new index
+    /// Returns the number of entries written
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `idx_name` - The index name
+    pub fn rebuild_index<T: AsRef<str>, IT: AsRef<str>>(&mut self, rec_type: T, idx_name: IT) -> Result<usize> {
+        let report = self.check_index(rec_type.as_ref(), idx_name.as_ref(), IndexCheck::Rebuild)?;
+        Ok(report.entries)
+    }
+
+    /// Returns the components and location of an index
+    fn index_parts(&self, ref_type: &str, idx_name: &str) -> Result<(&Vec<String>, Location<'_>)> {
+        let on = self.metadata.indices.get(ref_type).and_then(|idxs| idxs.get(idx_name));
+        match (on, self.index_location(ref_type, idx_name)) {
+            (Some(on), Some(loc)) => Ok((on, loc)),
+            _ => Err(MetadataError::UnknownIndex {
+                rec_type: String::from(ref_type),
+                index_name: String::from(idx_name),
+            }
+            .into()),
+        }
+    }
+
+    /// Compares an index with the entries its records give, fixing the differences if asked to
+    /// The expected entries are sorted on disk if they do not fit in memory, then merged with the index entries
+    /// A repair writes the changes in batches, a rebuild writes them to a SST file ingested at the end
+    fn check_index(&self, ref_type: &str, idx_name: &str, check: IndexCheck) -> Result<IndexReport> {
+        let (on, loc) = self.index_parts(ref_type, idx_name)?;
+        let hash_key = self.index_hash_key(ref_type)?;
+        let dir = self.db.path().join(format!("check{}", index_cf_name(ref_type, idx_name)));
+        create_dir_all(&dir)?;
+        let mut expected = ExternalSorter::new(&dir, "expected", BulkLoadOptions::default().memory_budget);
+        let mut report = IndexReport::default();
+        let sorted = self.execute(scan(ref_type)).and_then(|it| {
+            for rec in it {
+                let kv = serde_json::to_vec(&rec.key)?;
+                for (ix_key, ev) in self.index_entries(ref_type, idx_name, on, &kv, &rec.value, hash_key.as_deref())? {
+                    expected.push(ix_key, ev)?;
+                    report.entries += 1;
+                }
+                report.records += 1;
+            }
+            expected.into_sorted()
+        });
+        let r = sorted.and_then(|mut expected| {
+            let record_key = |v: &[u8]| serde_json::from_slice(split_entry_value(v).0).unwrap_or(Value::Null);
+            let (mut missing, mut orphaned) = (HashSet::new(), HashSet::new());
+            let mut actual = loc.iter(&self.db, &[], ReadOptions::default()).peekable();
+            let mut next = expected.next_entry()?;
+            let mut b = WriteBatch::default();
+            let sst = dir.join("rebuild.sst");
+            let opts = Options::default();
+            let mut writer = SstFileWriter::create(&opts);
+            let mut sst_entries = 0;
+            // keys come in order, as the SST file requires
+            let mut to_sst = |k: Vec<u8>, v: Option<Vec<u8>>| -> Result<()> {
+                if sst_entries == 0 {
+                    writer.open(&sst)?;
+                }
+                match v {
+                    Some(v) => writer.put(k, v)?,
+                    None => writer.delete(k)?,
+                }
+                sst_entries += 1;
+                Ok(())
+            };
+            loop {
+                let ord = match (&next, actual.peek()) {
+                    (None, None) => break,
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (Some((ek, _)), Some((ak, _))) => ek.as_slice().cmp(ak),
+                };
+                match ord {
+                    Ordering::Less | Ordering::Equal => {
+                        let (k, v) = next.take().unwrap();
+                        let stored = if ord == Ordering::Equal { actual.next() } else { None };
+                        if stored.map(|(_, sv)| *sv != *v).unwrap_or(true) {
+                            report.add_missing(record_key(&v), &mut missing);
+                            if check == IndexCheck::Repair {
+                                b.put_cf(loc.cf, loc.key(&k), &v);
+                            }
+                        }
+                        if check == IndexCheck::Rebuild {
+                            to_sst(loc.key(k), Some(v))?;
+                        }
+                        next = expected.next_entry()?;
+                    }
+                    Ordering::Greater => {
+                        let (k, v) = actual.next().unwrap();
+                        report.add_orphaned(record_key(&v), &mut orphaned);
+                        match check {
+                            IndexCheck::Verify => (),
+                            IndexCheck::Repair => b.delete_cf(loc.cf, loc.key(&k)),
+                            IndexCheck::Rebuild => to_sst(loc.key(&k), None)?,
+                        }
+                    }
+                }
+                if b.len() > 1000 {
+                    self.db.write(b)?;
+                    b = WriteBatch::default();
+                }
+            }
+            self.db.write(b)?;
+            if sst_entries > 0 {
+                writer.finish()?;
+                self.db.ingest_external_file_cf(loc.cf, vec![sst])?;
+            }
+            Ok(report)
+        });
+        // a cleanup error is only reported if the check itself succeeded
+        let cleanup = remove_dir_all(&dir);
+        let report = r?;
+        cleanup?;
+        Ok(report)
+    }
+
+    /// Reports the disk usage and number of keys of each record type and index
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        let mut usage = DiskUsage {
+            shared: cf_usage(&self.db, self.db.cf_handle(SHARED_CF).unwrap())?,
+            ..Default::default()
+        };
+        for (rec_type, idxs) in self.metadata.indices.iter() {
+            let mut tu = TypeUsage::default();
+            if let Some(loc) = self.location(rec_type) {
+                tu.data = location_usage(&self.db, &loc)?;
+            }
+            for idx_name in idxs.keys() {
+                if let Some(loc) = self.index_location(rec_type, idx_name) {
+                    tu.indices
+                        .insert(idx_name.clone(), location_usage(&self.db, &loc)?);
+                }
+            }
+            usage.types.insert(rec_type.clone(), tu);
+        }
+        Ok(usage)
+    }
+
+    /// Returns a snapshot of the memtable, cache and compaction counters of the database
+    pub fn stats(&self) -> Result<Stats> {
+        let mut cfs = vec![];
+        for name in [SEQUENCE_CF, SHARED_CF].iter() {
+            cfs.extend(self.db.cf_handle(name));
+        }
+        for (rec_type, idxs) in self.metadata.indices.iter() {
+            if !self.is_shared(rec_type) {
+                cfs.extend(self.db.cf_handle(rec_type));
+                for idx_name in idxs.keys() {
+                    cfs.extend(self.db.cf_handle(&index_cf_name(rec_type, idx_name)));
+                }
+            }
+        }
+        db_stats(&self.db, &cfs)
+    }
+
+    /// Sets the format used to store new values of a record type. Existing values keep their format
+    /// and are still read transparently, use `convert_format` to rewrite them
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `format` - The value format
+    pub fn set_format<T: AsRef<str>>(&mut self, rec_type: T, format: ValueFormat) -> Result<()> {
+        self.metadata
+            .formats
+            .insert(String::from(rec_type.as_ref()), format);
+        self.save_metadata()
+    }
+
+    /// Sets the format of a record type and rewrites all existing values in that format
+    /// Returns the number of values that were rewritten
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `format` - The value format
+    pub fn convert_format<T: AsRef<str>>(
+        &mut self,
+        rec_type: T,
+        format: ValueFormat,
+    ) -> Result<usize> {
+        let ref_type = rec_type.as_ref();
+        self.set_format(ref_type, format)?;
+        let encrypted = self.metadata.encryption.contains_key(ref_type);
+        let mut count = 0;
+        if let Some(loc) = self.location(ref_type) {
+            let mut b = WriteBatch::default();
+            for (k, v) in loc.iter(&self.db, &[], ReadOptions::default()) {
+                let plain = self.open_stored(ref_type, &k, &v)?;
+                if ValueFormat::of(&plain) != format || is_encrypted(&v) != encrypted {
+                    let value = self.encode_value(ref_type, &k, &decode_value(&plain)?)?;
+                    b.put_cf(loc.cf, loc.key(&k), value);
+                    count += 1;
+                    if b.len() > 1000 {
+                        self.db.write(b)?;
+                        b = WriteBatch::default();
+                    }
+                }
+            }
+            self.db.write(b)?;
+        }
+        Ok(count)
+    }
+
+    /// Sets the provider of the keys used by encrypted record types
+    /// # Arguments
+    /// * `provider` - The key provider
+    pub fn set_key_provider<K: KeyProvider + 'static>(&mut self, provider: K) {
+        self.key_provider = Some(Box::new(provider));
+    }
+
+    /// Encrypts the new values of a record type. Existing values are still read transparently, use
+    /// `convert_format` to rewrite them encrypted. The index protection cannot change while the record type has indices
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `encryption` - The encryption settings
+    pub fn set_encryption<T: AsRef<str>>(&mut self, rec_type: T, encryption: Encryption) -> Result<()> {
+        let ref_type = rec_type.as_ref();
+        let current = self
+            .index_protection(ref_type)
+            .cloned()
+            .unwrap_or(IndexProtection::Plaintext);
+        let has_indices = self
+            .metadata
+            .indices
+            .get(ref_type)
+            .map(|idxs| !idxs.is_empty())
+            .unwrap_or_default();
+        if has_indices && current != encryption.index_keys {
+            return Err(EncryptionError::ExistingIndices {
+                rec_type: String::from(ref_type),
+            }
+            .into());
+        }
+        self.metadata
+            .encryption
+            .insert(String::from(ref_type), encryption);
+        self.save_metadata()
+    }
+
+    /// Returns the key provider, failing if there is none
+    fn key_provider(&self, rec_type: &str) -> Result<&dyn KeyProvider> {
+        self.key_provider.as_deref().ok_or_else(|| {
+            EncryptionError::NoKeyProvider {
+                rec_type: String::from(rec_type),
+            }
+            .into()
+        })
+    }
+
+    /// Fails early when the values of a record type are encrypted and cannot be decrypted
+    fn check_readable(&self, rec_type: &str) -> Result<()> {
+        if self.metadata.encryption.contains_key(rec_type) {
+            self.key_provider(rec_type)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the index protection of an encrypted record type
+    fn index_protection(&self, rec_type: &str) -> Option<&IndexProtection> {
+        self.metadata.encryption.get(rec_type).map(|e| &e.index_keys)
+    }
+
+    /// Returns the key used to hash the index keys of a record type, if they are hashed
+    fn index_hash_key(&self, rec_type: &str) -> Result<Option<Vec<u8>>> {
+        match self.index_protection(rec_type) {
+            Some(IndexProtection::KeyedHash { key_id }) => {
+                Ok(Some(self.key_provider(rec_type)?.key(key_id)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Encodes a value in the format of its record type, and encrypts it if the record type is encrypted
+    fn encode_value(&self, rec_type: &str, kv: &[u8], value: &Value) -> Result<Vec<u8>> {
+        let bs = self
+            .metadata
+            .formats
+            .get(rec_type)
+            .copied()
+            .unwrap_or_default()
+            .encode(value)?;
+        if self.metadata.encryption.contains_key(rec_type) {
+            encrypt(self.key_provider(rec_type)?, rec_type, kv, &bs)
+        } else {
+            Ok(bs)
+        }
+    }
+
+    /// Returns the encoded value from a stored value, decrypting it if needed
+    fn open_stored(&self, rec_type: &str, kv: &[u8], bytes: &[u8]) -> Result<Vec<u8>> {
+        if is_encrypted(bytes) {
+            decrypt(self.key_provider(rec_type)?, rec_type, kv, bytes)
+        } else {
+            Ok(bytes.to_vec())
+        }
+    }
+
+    /// Decodes a stored value, decrypting it if needed
+    fn decode_stored(&self, rec_type: &str, kv: &[u8], bytes: &[u8]) -> Result<Value> {
+        if is_encrypted(bytes) {
+            decode_value(&self.open_stored(rec_type, kv, bytes)?)
+        } else {
+            decode_value(bytes)
+        }
+    }
+
+    /// Sets how the keys of a record type are generated by `insert_auto`
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `generator` - The key generator
+    pub fn set_key_generator<T: AsRef<str>>(&mut self, rec_type: T, generator: KeyGenerator) -> Result<()> {
+        self.metadata
+            .key_generators
+            .insert(String::from(rec_type.as_ref()), generator);
+        self.save_metadata()
+    }
+
+    /// Allocates the next key of a record type from its sequence
+    /// The sequence is updated durably right away, so keys are never handed out twice, but keys
+    /// allocated for records that are never written leave gaps
+    /// # Arguments
+    /// * `rec_type` - The record type
+    pub fn next_key<T: AsRef<str>>(&mut self, rec_type: T) -> Result<Value> {
+        let ref_type = rec_type.as_ref();
+        let cf = self.db.cf_handle(SEQUENCE_CF).unwrap();
+        let n = self
+            .db
+            .get_cf(cf, ref_type)?
+            .map(|v| decode_counter(ref_type, &v))
+            .transpose()?
+            .unwrap_or(0)
+            + 1;
+        self.db.put_cf(cf, ref_type, n.to_be_bytes())?;
+        Ok(self
+            .metadata
+            .key_generators
+            .get(ref_type)
+            .cloned()
+            .unwrap_or_default()
+            .key(n))
+    }
+
+    /// Inserts a record with a generated key, returning the key
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `value` - A reference to the value to store
+    pub fn insert_auto<T: AsRef<str>>(&mut self, rec_type: T, value: &Value) -> Result<Value> {
+        let mut batch = EQLBatch::default();
+        let key = self.batch_insert_auto(&mut batch, rec_type, value)?;
+        self.write(batch)?;
+        Ok(key)
+    }
+
+    /// Inserts a record with a generated key into a write batch, returning the key
+    /// The key is allocated immediately, so it can be used in other records of the same batch
+    /// Fails with `ConflictError::Exists` if a record was already inserted with the generated key
+    /// # Arguments
+    /// * `batch`- The write batch
+    /// * `rec_type` - The record type
+    /// * `value` - A reference to the value to store
+    pub fn batch_insert_auto<T: AsRef<str>>(
+        &mut self,
+        batch: &mut EQLBatch,
+        rec_type: T,
+        value: &Value,
+    ) -> Result<Value> {
+        let ref_type = rec_type.as_ref();
+        let key = self.next_key(ref_type)?;
+        let kv = serde_json::to_vec(&key)?;
+        if let Some(found) = self.previous_value(batch, ref_type, &kv)? {
+            return Err(ConflictError::Exists {
+                rec_type: String::from(ref_type),
+                key,
+                found,
+            }
+            .into());
+        }
+        self.batch_insert(batch, ref_type, key.clone(), value)?;
+        Ok(key)
+    }
+
+    /// Inserts a record
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `key` - The key
+    /// * `value` - A reference to the value to store
+    pub fn insert<T: AsRef<str>, V: Into<Value>>(
+        &mut self,
+        rec_type: T,
+        key: V,
+        value: &Value,
+    ) -> Result<()> {
+        let mut batch = EQLBatch::default();
+        self.batch_insert(&mut batch, rec_type, key, value)?;
+        self.write(batch)
+    }
+
+    /// Inserts a record into a write batch
+    /// If the record type does not exist yet, it will only be created when the batch is written
+    /// # Arguments
+    /// * `batch`- The write batch
+    /// * `rec_type` - The record type
+    /// * `key` - The key
+    /// * `value` - A reference to the value to store
+    pub fn batch_insert<T: AsRef<str>, V: Into<Value>>(
+        &mut self,
+        batch: &mut EQLBatch,
+        rec_type: T,
+        key: V,
+        value: &Value,
+    ) -> Result<()> {
+        let ref_type = rec_type.as_ref();
+        let kv = serde_json::to_vec(&key.into()).unwrap();
+        if self.location(ref_type).is_some() {
+            if self.has_indices(ref_type) {
+                self.check_unique(batch, ref_type, &kv, value)?;
+                if let Some(previous) = self.previous_value(batch, ref_type, &kv)? {
+                    self.delete_index_entries(&mut batch.batch, ref_type, &kv, &previous)?;
+                }
+                batch
+                    .written
+                    .insert((String::from(ref_type), kv.clone()), Some(value.clone()));
+            }
+            self.put_record(&mut batch.batch, ref_type, kv, value)?;
+        } else {
+            // an index can be added to a record type before its first record is written
+            if self.has_indices(ref_type) {
+                self.check_unique(batch, ref_type, &kv, value)?;
+                batch
+                    .written
+                    .insert((String::from(ref_type), kv.clone()), Some(value.clone()));
+            }
+            batch
+                .pending
+                .retain(|(t, k, _)| t != ref_type || *k != kv);
+            batch
+                .pending
+                .push((String::from(ref_type), kv, value.clone()));
+        }
+        batch.counts_mut(ref_type).inserts += 1;
+        Ok(())
+    }
+
+    /// Returns true if the record type has indices
+    fn has_indices(&self, ref_type: &str) -> bool {
+        self.metadata
+            .indices
+            .get(ref_type)
+            .map(|idxs| !idxs.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of a record before a batch write, taking into account the previous writes of the batch
+    fn previous_value(&self, batch: &EQLBatch, ref_type: &str, kv: &[u8]) -> Result<Option<Value>> {
+        if let Some(v) = batch.written.get(&(String::from(ref_type), kv.to_vec())) {
+            return Ok(v.clone());
+        }
+        match self.location(ref_type) {
+            Some(loc) => self
+                .db
+                .get_cf(loc.cf, loc.key(kv))?
+                .map(|v| self.decode_stored(ref_type, kv, &v))
+                .transpose(),
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes the index entries of a record in a write batch
+    fn delete_index_entries(&self, batch: &mut WriteBatch, ref_type: &str, kv: &[u8], value: &Value) -> Result<()> {
+        if let Some(idxs) = self.metadata.indices.get(ref_type) {
+            let hash_key = self.index_hash_key(ref_type)?;
+            for (idx_name, on) in idxs.iter() {
+                if let Some(idx_loc) = self.index_location(ref_type, idx_name) {
+                    for (ix_key, _) in self.index_entries(ref_type, idx_name, on, kv, value, hash_key.as_deref())? {
+                        batch.delete_cf(idx_loc.cf, idx_loc.key(ix_key));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a record and its index entries in a write batch, the record type must exist
+    fn put_record(
+        &self,
+        batch: &mut WriteBatch,
+        ref_type: &str,
+        kv: Vec<u8>,
+        value: &Value,
+    ) -> Result<()> {
+        if let Some(loc) = self.location(ref_type) {
+            batch.put_cf(loc.cf, loc.key(&kv), self.encode_value(ref_type, &kv, value)?);
+        }
+        if let Some(idxs) = self.metadata.indices.get(ref_type) {
+            let hash_key = self.index_hash_key(ref_type)?;
+            for (idx_name, on) in idxs.iter() {
+                if let Some(loc) = self.index_location(ref_type, idx_name) {
+                    for (ix_key, ev) in self.index_entries(ref_type, idx_name, on, &kv, value, hash_key.as_deref())? {
+                        batch.put_cf(loc.cf, loc.key(ix_key), ev);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a record type with the given storage, if it does not exist yet
+    /// Record types created implicitly by inserts get a dedicated column family
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `storage` - How the records of the type are stored
+    pub fn create_type<T: AsRef<str>>(&mut self, rec_type: T, storage: Storage) -> Result<()> {
+        let ref_type = rec_type.as_ref();
+        let existing = self.metadata.storage.get(ref_type).copied().unwrap_or_default();
+        let exists =
+            self.metadata.indices.contains_key(ref_type) || self.db.cf_handle(ref_type).is_some();
+        if exists && existing != storage {
+            return Err(MetadataError::StorageMismatch {
+                rec_type: String::from(ref_type),
+                storage: existing,
+            }
+            .into());
+        }
+        if storage == Storage::Shared && !exists {
+            self.metadata
+                .storage
+                .insert(String::from(ref_type), Storage::Shared);
+        }
+        self.ensure_type(ref_type)
+    }
+
+    /// Returns true if the record type is stored in the shared column family
+    fn is_shared(&self, ref_type: &str) -> bool {
+        self.metadata.storage.get(ref_type) == Some(&Storage::Shared)
+    }
+
+    /// Returns where the records of a record type are stored, None if the record type does not exist yet
+    fn location(&self, ref_type: &str) -> Option<Location<'_>> {
+        if self.is_shared(ref_type) {
+            self.db
+                .cf_handle(SHARED_CF)
+                .map(|cf| Location::shared(cf, ref_type))
+        } else {
+            self.db.cf_handle(ref_type).map(Location::dedicated)
+        }
+    }
+
+    /// Returns where the entries of an index are stored, None if the index does not exist
+    fn index_location(&self, ref_type: &str, idx_name: &str) -> Option<Location<'_>> {
+        let idx_cf = index_cf_name(ref_type, idx_name);
+        if self.is_shared(ref_type) {
+            self.db
+                .cf_handle(SHARED_CF)
+                .map(|cf| Location::shared(cf, &idx_cf))
+        } else {
+            self.db.cf_handle(&idx_cf).map(Location::dedicated)
+        }
+    }
+
+    /// Creates the column family of a record type and records it in the metadata, if needed
+    fn ensure_type(&mut self, ref_type: &str) -> Result<()> {
+        self.create_type_cf(ref_type)?;
+        if !self.metadata.indices.contains_key(ref_type) {
+            self.metadata
+                .indices
+                .insert(String::from(ref_type), HashMap::new());
+            self.save_metadata()?;
+        }
+        Ok(())
+    }
+
+    /// Creates the column family of a record type if needed, returning true if it was created
+    fn create_type_cf(&mut self, ref_type: &str) -> Result<bool> {
+        if !self.is_shared(ref_type) && self.db.cf_handle(ref_type).is_none() {
+            self.db.create_cf(ref_type, &Options::default())?;
+            return Ok(true);
         }
-        DB::destroy(&Options::default(), path)?;
-        Ok(())
+        Ok(false)
     }
 
-    /// Adds an index
+    /// Loads many records at once without going through the memtable: the data and the entries of each index
+    /// are sorted, spilling to disk when needed, written to SST files and ingested in their column families.
+    /// Records replace existing records with the same key, but the index entries of the replaced records are not
+    /// removed, so this is meant to load new records. When unsorted records share a key, only the last one is loaded.
+    /// Nothing is loaded if records would share indexed values in a unique index, with each other or with existing
+    /// records. Returns the number of records loaded
+    /// RocksDB cannot ingest files in several column families at once, so the index files are ingested first and the
+    /// data last. If an ingestion fails, the indices already ingested are repaired to remove their entries for records
+    /// that were not loaded. If that repair fails too, `repair_index` should be run on each index of the record type
     /// # Arguments
     /// * `rec_type` - The record type
-    /// * `idx_name` - The index name, must be unique for a given record type
-    /// * `on` - The list of JSON expressions to apply to values and index
-    pub fn add_index<T: AsRef<str>, IT: AsRef<str>, OT: AsRef<str>>(
+    /// * `records` - The records to load
+    /// * `options` - The bulk load options
+    pub fn bulk_load<T: AsRef<str>, I: IntoIterator<Item = EQLRecord>>(
         &mut self,
         rec_type: T,
-        idx_name: IT,
-        on: Vec<OT>,
-    ) -> Result<()> {
-        let ref_type = String::from(rec_type.as_ref());
-        let ref_idx = String::from(idx_name.as_ref());
-        let m = self
-            .metadata
-            .indices
-            .entry(ref_type.clone())
-            .or_insert_with(HashMap::new);
-        if m.contains_key(&ref_idx) {
-            return Err(MetadataError::DuplicateIndex {
-                rec_type: ref_type,
-                index_name: ref_idx,
+        records: I,
+        options: BulkLoadOptions,
+    ) -> Result<usize> {
+        let ref_type = rec_type.as_ref();
+        self.ensure_type(ref_type)?;
+        let dir = self.db.path().join("bulk_load");
+        create_dir_all(&dir)?;
+        let r = self.bulk_load_files(ref_type, records, &options, &dir);
+        // a failed cleanup must not hide the load error, or fail a successful load: leftover files are overwritten
+        let _ = remove_dir_all(&dir);
+        r
+    }
+
+    /// Writes the SST files for a bulk load in the given folder and ingests them
+    fn bulk_load_files<I: IntoIterator<Item = EQLRecord>>(
+        &self,
+        ref_type: &str,
+        records: I,
+        options: &BulkLoadOptions,
+        dir: &Path,
+    ) -> Result<usize> {
+        let mut idx_sorters = vec![];
+        let hash_key = self.index_hash_key(ref_type)?;
+        if let Some(idxs) = self.metadata.indices.get(ref_type) {
+            for (ix, (idx_name, on)) in idxs.iter().enumerate() {
+                if let Some(loc) = self.index_location(ref_type, idx_name) {
+                    let sorter = ExternalSorter::new(dir, format!("idx{}", ix), options.memory_budget);
+                    idx_sorters.push((idx_name, loc, on, sorter));
+                }
             }
-            .into());
         }
-        let idx_cf = index_cf_name(rec_type.as_ref(), &ref_idx);
-        self.db.create_cf(&idx_cf, &Options::default())?;
-        let cf1 = self.db.cf_handle(&idx_cf).unwrap();
-        m.insert(
-            ref_idx,
-            on.iter().map(|s| String::from(s.as_ref())).collect(),
-        );
-
-        self.save_metadata()?;
-
-        self.execute(scan(rec_type.as_ref()))?
-            .try_fold(WriteBatch::default(), |mut b, rec| {
-                let kv = serde_json::to_vec(&rec.key).unwrap();
-                let ix_key = index_key(&on, &kv, &rec.value);
-
-                b.put_cf(cf1, ix_key, &kv);
-                if b.len() > 1000 {
-                    self.db.write(b)?;
-                    return Ok(WriteBatch::default());
+        let loc = match self.location(ref_type) {
+            Some(loc) => loc,
+            None => return Ok(0),
+        };
+        let data_sst = dir.join("data.sst");
+        let opts = Options::default();
+        let mut writer = SstFileWriter::create(&opts);
+        let mut count = 0;
+        // writes a record and pushes its index entries, records coming sorted by key without duplicates
+        let mut add = |kv: &[u8], value: &Value| -> Result<()> {
+            for (idx_name, idx_loc, on, sorter) in idx_sorters.iter_mut() {
+                for (ix_key, ev) in self.index_entries(ref_type, idx_name, on, kv, value, hash_key.as_deref())? {
+                    sorter.push(idx_loc.key(ix_key), ev)?;
                 }
-                let r: Result<WriteBatch, rocksdb::Error> = Ok(b);
-                r
-            })?;
+            }
+            if count == 0 {
+                writer.open(&data_sst)?;
+            }
+            writer.put(loc.key(kv), self.encode_value(ref_type, kv, value)?)?;
+            count += 1;
+            Ok(())
+        };
+        if options.sorted {
+            let mut last: Option<Vec<u8>> = None;
+            for rec in records {
+                let kv = serde_json::to_vec(&rec.key)?;
+                match &last {
+                    Some(l) if *l == kv => return Err(BulkLoadError::DuplicateKey { key: rec.key }.into()),
+                    Some(l) if *l > kv => {
+                        return Err(BulkLoadError::UnsortedInput { key: rec.key }.into())
+                    }
+                    _ => (),
+                }
+                add(&kv, &rec.value)?;
+                last = Some(kv);
+            }
+        } else {
+            // the sort keeps the last record of each key, so only the records loaded give index entries
+            let mut data = ExternalSorter::new(dir, "data", options.memory_budget);
+            for rec in records {
+                data.push(serde_json::to_vec(&rec.key)?, serde_json::to_vec(&rec.value)?)?;
+            }
+            let mut sorted = data.into_sorted()?;
+            while let Some((kv, v)) = sorted.next_entry()? {
+                add(&kv, &serde_json::from_slice(&v)?)?;
+            }
+        }
 
-        Ok(())
+        // index files come first and the data file last, the index name telling which index a file belongs to
+        let mut files: Vec<(Option<&String>, &ColumnFamily, PathBuf)> = vec![];
+        for (ix, (idx_name, idx_loc, on, sorter)) in idx_sorters.into_iter().enumerate() {
+            let idx_sst = dir.join(format!("idx{}.sst", ix));
+            let mut sorted = sorter.into_sorted()?;
+            let written = if self.is_unique_index(ref_type, idx_name) {
+                // the new entries are merged with the existing ones to find the records sharing values
+                let mut finder = DuplicateFinder::new(on.len(), index_component(&Value::Null, hash_key.as_deref()));
+                let mut existing = idx_loc.iter(&self.db, &[], ReadOptions::default()).peekable();
+                let prefix_len = idx_loc.key([]).len();
+                let written = write_sst(&idx_sst, &mut sorted, |k, v| {
+                    let k = &k[prefix_len..];
+                    while let Some((ek, ev)) = existing.next_if(|(ek, _)| **ek < *k) {
+                        finder.push(&ek, &ev)?;
+                    }
+                    // an existing entry with the same key is replaced
+                    existing.next_if(|(ek, _)| **ek == *k);
+                    finder.push(k, v)
+                })?;
+                for (ek, ev) in existing {
+                    finder.push(&ek, &ev)?;
+                }
+                let keys = finder.finish();
+                if !keys.is_empty() {
+                    return Err(BulkLoadError::UniqueViolation {
+                        index_name: idx_name.clone(),
+                        keys,
+                    }
+                    .into());
+                }
+                written
+            } else {
+                write_sst(&idx_sst, &mut sorted, |_, _| Ok(()))?
+            };
+            if let Some(p) = written {
+                files.push((Some(idx_name), idx_loc.cf, p));
+            }
+        }
+        if count > 0 {
+            writer.finish()?;
+            files.push((None, loc.cf, data_sst));
+        }
+        let mut ingested: Vec<&String> = vec![];
+        for (idx_name, cf, p) in files {
+            if let Err(e) = self.db.ingest_external_file_cf(cf, vec![p]) {
+                for idx_name in ingested {
+                    // the ingestion error is the one to report, the documented recovery covers a failed repair
+                    let _ = self.check_index(ref_type, idx_name, IndexCheck::Repair);
+                }
+                return Err(e.into());
+            }
+            ingested.extend(idx_name);
+        }
+        Ok(count)
     }
 
-    /// Saves the metadata to a file
-    fn save_metadata(&self) -> Result<()> {
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(self.metadata_path.clone())?;
-        serde_json::to_writer(BufWriter::new(file), &self.metadata)?;
-        Ok(())
+    /// Reads a single record
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `key` - The key
+    pub fn get<T: AsRef<str>, V: Into<Value>>(
+        &mut self,
+        rec_type: T,
+        key: V,
+    ) -> Result<Option<Value>> {
+        let ref_type = rec_type.as_ref();
+        let kv = serde_json::to_vec(&key.into()).unwrap();
+        self.get_raw(ref_type, &kv)?
+            .map(|v| self.decode_stored(ref_type, &kv, &v))
+            .transpose()
     }
 
-    /// Deletes an index
+    /// Reads a single record with its version, to use in conditional writes
+    /// The version is derived from the stored bytes, so it changes whenever the value changes
     /// # Arguments
     /// * `rec_type` - The record type
-    /// * `idx_name` - The index name, must be unique for a given record type
-    pub fn delete_index<T: AsRef<str>, IT: AsRef<str>>(
+    /// * `key` - The key
+    pub fn get_versioned<T: AsRef<str>, V: Into<Value>>(
         &mut self,
         rec_type: T,
-        idx_name: IT,
-    ) -> Result<()> {
-        if let Some(m) = self.metadata.indices.get_mut(rec_type.as_ref()) {
-            m.remove(idx_name.as_ref());
-            let idx_cf = index_cf_name(rec_type.as_ref(), idx_name.as_ref());
-            self.db.drop_cf(&idx_cf)?;
+        key: V,
+    ) -> Result<Option<(Value, u64)>> {
+        let ref_type = rec_type.as_ref();
+        let kv = serde_json::to_vec(&key.into()).unwrap();
+        self.get_raw(ref_type, &kv)?
+            .map(|v| {
+                self.decode_stored(ref_type, &kv, &v)
+                    .map(|d| (d, record_version(&v)))
+            })
+            .transpose()
+    }
 
-            self.save_metadata()?;
+    /// Reads the stored bytes of a single record
+    fn get_raw(&self, ref_type: &str, kv: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.location(ref_type) {
+            Some(loc) => Ok(self.db.get_cf(loc.cf, loc.key(kv))?),
+            None => Ok(None),
         }
-        Ok(())
     }
 
-    /// Inserts a record
+    /// Checks that a record is in the expected state
+    fn check_expected(&self, ref_type: &str, key: &Value, expected: &Expected) -> Result<()> {
+        let kv = serde_json::to_vec(key).unwrap();
+        let found = self.get_raw(ref_type, &kv)?;
+        let matches = match (&found, expected) {
+            (Some(v), Expected::Version(ver)) => record_version(v) == *ver,
+            (Some(v), Expected::Value(val)) => self.decode_stored(ref_type, &kv, v)? == *val,
+            (None, _) => false,
+        };
+        if matches {
+            Ok(())
+        } else {
+            Err(ConflictError::Mismatch {
+                rec_type: String::from(ref_type),
+                key: key.clone(),
+                found: found
+                    .map(|v| self.decode_stored(ref_type, &kv, &v))
+                    .transpose()?,
+            }
+            .into())
+        }
+    }
+
+    /// Inserts a record only if there is no record with the same key
+    /// Fails with `ConflictError::Exists` otherwise
     /// # Arguments
     /// * `rec_type` - The record type
     /// * `key` - The key
     /// * `value` - A reference to the value to store
-    pub fn insert<T: AsRef<str>, V: Into<Value>>(
+    pub fn insert_if_absent<T: AsRef<str>, V: Into<Value>>(
         &mut self,
         rec_type: T,
         key: V,
         value: &Value,
     ) -> Result<()> {
-        let mut batch = EQLBatch::default();
-        self.batch_insert(&mut batch, rec_type, key, value)?;
-        self.db.write(batch.batch)?;
-        Ok(())
+        let ref_type = rec_type.as_ref();
+        let key = key.into();
+        let kv = serde_json::to_vec(&key).unwrap();
+        if let Some(found) = self.get_raw(ref_type, &kv)? {
+            return Err(ConflictError::Exists {
+                rec_type: String::from(ref_type),
+                key,
+                found: self.decode_stored(ref_type, &kv, &found)?,
+            }
+            .into());
+        }
+        self.insert(ref_type, key, value)
     }
 
-    /// Inserts a record into a write batch
+    /// Replaces a record only if it is in the expected state, updating its index entries
+    /// Fails with `ConflictError::Mismatch` otherwise
     /// # Arguments
-    /// * `batch`- The write batch
     /// * `rec_type` - The record type
     /// * `key` - The key
-    /// * `value` - A reference to the value to store
-    pub fn batch_insert<T: AsRef<str>, V: Into<Value>>(
+    /// * `expected` - The expected current value or version
+    /// * `value` - A reference to the new value
+    pub fn replace_if<T: AsRef<str>, V: Into<Value>>(
         &mut self,
-        batch: &mut EQLBatch,
         rec_type: T,
         key: V,
+        expected: Expected,
         value: &Value,
     ) -> Result<()> {
         let ref_type = rec_type.as_ref();
-        let ocf1 = self.db.cf_handle(ref_type);
-        let cf = match ocf1 {
-            None => {
-                self.db.create_cf(ref_type, &Options::default())?;
-                let cf1 = self.db.cf_handle(ref_type).unwrap();
-                cf1
-            }
-            Some(cf1) => cf1,
-        };
-        let kv = serde_json::to_vec(&key.into()).unwrap();
-        batch
-            .batch
-            .put_cf(cf, kv.clone(), serde_json::to_vec(value).unwrap());
-
-        if let Some(idxs) = self.metadata.indices.get(ref_type) {
-            for (idx_name, on) in idxs.iter() {
-                let idx_cf = index_cf_name(rec_type.as_ref(), idx_name);
-                if let Some(cf1) = self.db.cf_handle(&idx_cf) {
-                    let ix_key = index_key(on, &kv, value);
-                    batch.batch.put_cf(cf1, ix_key, kv.clone());
-                }
-            }
-        } else {
-            self.metadata
-                .indices
-                .insert(String::from(ref_type), HashMap::new());
-            self.save_metadata()?;
-        }
-
-        Ok(())
+        let key = key.into();
+        self.check_expected(ref_type, &key, &expected)?;
+        let mut batch = EQLBatch::default();
+        self.batch_delete(&mut batch, ref_type, key.clone())?;
+        self.batch_insert(&mut batch, ref_type, key, value)?;
+        self.write(batch)
     }
 
-    /// Reads a single record
+    /// Deletes a record only if it is in the expected state
+    /// Fails with `ConflictError::Mismatch` otherwise
     /// # Arguments
     /// * `rec_type` - The record type
     /// * `key` - The key
-    pub fn get<T: AsRef<str>, V: Into<Value>>(
+    /// * `expected` - The expected current value or version
+    pub fn delete_if<T: AsRef<str>, V: Into<Value>>(
         &mut self,
         rec_type: T,
         key: V,
-    ) -> Result<Option<Value>> {
+        expected: Expected,
+    ) -> Result<()> {
         let ref_type = rec_type.as_ref();
-        let ocf1 = self.db.cf_handle(ref_type);
-        if let Some(cf1) = ocf1 {
-            Ok(self
-                .db
-                .get_cf(cf1, serde_json::to_vec(&key.into()).unwrap())?
-                .map(|v| serde_json::from_slice(&v).unwrap()))
-        } else {
-            Ok(None)
-        }
+        let key = key.into();
+        self.check_expected(ref_type, &key, &expected)?;
+        self.delete(ref_type, key)
     }
 
     /// Deletes a single record
@@ -392,8 +1931,7 @@ impl EQLDB {
     pub fn delete<T: AsRef<str>, V: Into<Value>>(&mut self, rec_type: T, key: V) -> Result<()> {
         let mut batch = EQLBatch::default();
         self.batch_delete(&mut batch, rec_type, key)?;
-        self.db.write(batch.batch)?;
-        Ok(())
+        self.write(batch)
     }
 
     /// Deletes a single record in batch
@@ -408,67 +1946,183 @@ impl EQLDB {
         key: V,
     ) -> Result<()> {
         let ref_type = rec_type.as_ref();
-        let ocf1 = self.db.cf_handle(ref_type);
-        if let Some(cf1) = ocf1 {
-            let kv = serde_json::to_vec(&key.into()).unwrap();
-            if let Some(idxs) = self.metadata.indices.get(ref_type) {
-                if !idxs.is_empty() {
-                    if let Some(value) = self
-                        .db
-                        .get_cf(cf1, kv.clone())?
-                        .map(|v| serde_json::from_slice(&v).unwrap())
-                    {
-                        for (idx_name, on) in idxs.iter() {
-                            let idx_cf = index_cf_name(rec_type.as_ref(), idx_name);
-                            if let Some(cf) = self.db.cf_handle(&idx_cf) {
-                                let ix_key = index_key(on, &kv, &value);
-                                batch.batch.delete_cf(cf, ix_key);
-                            }
-                        }
-                    }
+        let kv = serde_json::to_vec(&key.into()).unwrap();
+        if let Some(loc) = self.location(ref_type) {
+            if self.has_indices(ref_type) {
+                if let Some(value) = self.previous_value(batch, ref_type, &kv)? {
+                    self.delete_index_entries(&mut batch.batch, ref_type, &kv, &value)?;
                 }
+                batch.written.insert((String::from(ref_type), kv.clone()), None);
             }
-            batch.batch.delete_cf(cf1, kv);
+            batch.batch.delete_cf(loc.cf, loc.key(&kv));
+        } else if self.has_indices(ref_type) {
+            batch.written.insert((String::from(ref_type), kv.clone()), None);
         }
+        batch
+            .pending
+            .retain(|(t, k, _)| t != ref_type || *k != kv);
+        batch.counts_mut(ref_type).deletes += 1;
         Ok(())
     }
 
-    /// Writes a batch
+    /// Writes a batch, creating first the record types it needs
+    /// The new record types are only saved in the metadata once the batch is written, and their column families
+    /// are dropped if the write fails, so a failed write leaves the schema unchanged
     /// # Arguments
     /// * `batch` - The write batch
-    pub fn write(&self, batch: EQLBatch) -> Result<()> {
+    pub fn write(&mut self, batch: EQLBatch) -> Result<()> {
+        let mut created = vec![];
+        let r = self.write_creating(batch, &mut created);
+        if r.is_err() {
+            for ref_type in created {
+                // the write error is the one to report
+                let _ = self.db.drop_cf(&ref_type);
+            }
+        }
+        r
+    }
+
+    /// Writes a batch, creating the column families of its new record types and listing them in `created`
+    fn write_creating(&mut self, mut batch: EQLBatch, created: &mut Vec<String>) -> Result<()> {
+        let pending = std::mem::take(&mut batch.pending);
+        for (ref_type, _, _) in pending.iter() {
+            if self.create_type_cf(ref_type)? {
+                created.push(ref_type.clone());
+            }
+        }
+        let new_types: BTreeSet<String> = pending
+            .iter()
+            .map(|(ref_type, _, _)| ref_type.clone())
+            .filter(|ref_type| !self.metadata.indices.contains_key(ref_type))
+            .collect();
+        for (ref_type, kv, value) in pending {
+            // the unique indices added since the record was queued must be checked too
+            if self.has_indices(&ref_type) {
+                self.check_unique(&mut batch, &ref_type, &kv, &value)?;
+                batch.written.insert((ref_type.clone(), kv.clone()), Some(value.clone()));
+            }
+            self.put_record(&mut batch.batch, &ref_type, kv, &value)?;
+        }
         self.db.write(batch.batch)?;
+        if !new_types.is_empty() {
+            for ref_type in new_types.iter() {
+                self.metadata.indices.insert(ref_type.clone(), HashMap::new());
+            }
+            if let Err(e) = self.save_metadata() {
+                for ref_type in new_types.iter() {
+                    self.metadata.indices.remove(ref_type);
+                }
+                return Err(e);
+            }
+        }
         Ok(())
     }
 
+    /// Returns the records of stored entries, in the order of the entries
+    /// Encrypted values are decoded before returning, so that a value written with a key the key provider does not
+    /// know anymore gives an error. Other values are decoded as the records are read
+    /// # Arguments
+    /// * `rec_type` - The record type
+    /// * `entries` - The stored keys and values
+    fn stored_records<'a, I>(&'a self, rec_type: String, entries: I) -> Result<Box<dyn Iterator<Item = EQLRecord> + 'a>>
+    where
+        I: Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a,
+    {
+        if self.metadata.encryption.contains_key(&rec_type) {
+            let v = entries
+                .map(|(k, v)| Ok(EQLRecord::new(serde_json::from_slice(&k)?, self.decode_stored(&rec_type, &k, &v)?)))
+                .collect::<Result<Vec<EQLRecord>>>()?;
+            return Ok(Box::new(v.into_iter()));
+        }
+        Ok(Box::new(entries.map(move |(k, v)| {
+            EQLRecord::new(
+                serde_json::from_slice::<Value>(&k).unwrap(),
+                self.decode_stored(&rec_type, &k, &v).unwrap(),
+            )
+        })))
+    }
+
     /// Executes an operation and returns an iterator on records
     /// # Arguments
     /// * `operation` - The operation
     pub fn execute<'a>(&'a self, operation: Operation<'a>) -> Result<Box<dyn Iterator<Item = EQLRecord> + 'a>> {
         match operation {
             Operation::Scan { name } => {
-                let ocf1 = self.db.cf_handle(&name);
-                if let Some(cf1) = ocf1 {
-                    let it = self.db.iterator_cf(cf1, IteratorMode::Start).map(|(k, v)| {
-                        EQLRecord::new(
-                            serde_json::from_slice::<Value>(&k).unwrap(),
-                            serde_json::from_slice::<Value>(&v).unwrap(),
-                        )
-                    });
-                    return Ok(Box::new(it));
+                self.check_readable(&name)?;
+                if let Some(loc) = self.location(&name) {
+                    let it = loc.iter(&self.db, &[], ReadOptions::default());
+                    return self.stored_records(name, it);
                 }
             }
             Operation::KeyLookup { name, key } => {
-                let ocf1 = self.db.cf_handle(&name);
-                if let Some(cf1) = ocf1 {
+                self.check_readable(&name)?;
+                if let Some(loc) = self.location(&name) {
                     let rk = serde_json::to_vec(&key).unwrap();
-                    let v =
-                        self.db.get_cf(cf1, &rk).unwrap().map(|v| {
-                            EQLRecord::new(key, serde_json::from_slice::<Value>(&v).unwrap())
-                        });
+                    let v = match self.db.get_cf(loc.cf, loc.key(&rk))? {
+                        Some(v) => Some(EQLRecord::new(key, self.decode_stored(&name, &rk, &v)?)),
+                        None => None,
+                    };
                     return Ok(Box::new(v.into_iter()));
                 }
             }
+            Operation::KeyPrefixScan { name, prefix } => {
+                self.check_readable(&name)?;
+                if let Some(loc) = self.location(&name) {
+                    let bs = key_prefix(&prefix);
+                    let l = bs.len();
+                    let it = loc
+                        .iter(&self.db, &bs, ReadOptions::default())
+                        .take_while(move |(k, _)| k.starts_with(&bs))
+                        .filter(move |(k, _)| is_component_boundary(k, l, prefix.is_empty()));
+                    return self.stored_records(name, it);
+                }
+            }
+            Operation::MultiKeyLookup {
+                name,
+                keys,
+                preserve_order,
+                report_missing,
+            } => {
+                let mut values: Vec<Option<Value>> = vec![None; keys.len()];
+                let mut rks: Vec<(Vec<u8>, usize)> = keys
+                    .iter()
+                    .enumerate()
+                    .map(|(ix, k)| (serde_json::to_vec(k).unwrap(), ix))
+                    .collect();
+                // a single batched read of the distinct keys, sorted so RocksDB can read them in order
+                rks.sort();
+                if let Some(loc) = self.location(&name) {
+                    let mut distinct: Vec<&[u8]> = rks.iter().map(|(rk, _)| rk.as_slice()).collect();
+                    distinct.dedup();
+                    let read = self
+                        .db
+                        .multi_get_cf(distinct.iter().map(|rk| (loc.cf, loc.key(rk))));
+                    let mut found = HashMap::new();
+                    for (rk, r) in distinct.into_iter().zip(read) {
+                        if let Some(v) = r? {
+                            found.insert(rk, self.decode_stored(&name, rk, &v)?);
+                        }
+                    }
+                    for (rk, ix) in rks.iter() {
+                        values[*ix] = found.get(rk.as_slice()).cloned();
+                    }
+                }
+                let order: Vec<usize> = if preserve_order {
+                    (0..keys.len()).collect()
+                } else {
+                    rks.dedup_by(|a, b| a.0 == b.0);
+                    rks.into_iter().map(|(_, ix)| ix).collect()
+                };
+                let v: Vec<EQLRecord> = order
+                    .into_iter()
+                    .filter_map(|ix| match values[ix].take() {
+                        Some(v) => Some(EQLRecord::new(keys[ix].clone(), v)),
+                        None if report_missing => Some(EQLRecord::new(keys[ix].clone(), Value::Null)),
+                        None => None,
+                    })
+                    .collect();
+                return Ok(Box::new(v.into_iter()));
+            }
             Operation::Extract {
                 names,
                 operation: b_op,
@@ -493,41 +2147,147 @@ impl EQLDB {
                 values,
                 keys,
             } => {
-                let idx_cf = index_cf_name(&name, &index_name);
-                if let Some(cf) = self.db.cf_handle(&idx_cf) {
-                    if values.is_empty() {
-                        let it = self
-                            .db
-                            .iterator_cf(cf, IteratorMode::Start)
-                            .map(move |(k, v)| {
-                                EQLRecord::new(
-                                    serde_json::from_slice(&v).unwrap(),
-                                    extract_from_index_key(&k, &keys),
-                                )
-                            });
-                        return Ok(Box::new(it));
-                    } else {
-                        let mut v = vec![];
-
-                        for o in values.iter() {
-                            v.append(&mut serde_json::to_vec(o).unwrap());
-                            v.push(0);
+                if let Some(loc) = self.index_location(&name, &index_name) {
+                    let hash_key = self.index_hash_key(&name)?;
+                    // the values before the first wildcard give the prefix of all the keys to read
+                    let skip = values
+                        .iter()
+                        .position(|v| *v == IndexValue::Any)
+                        .unwrap_or(values.len());
+                    let mut p = vec![];
+                    for o in values.iter().take(skip) {
+                        if let IndexValue::Equals(o) = o {
+                            p.append(&mut index_component(o, hash_key.as_deref()));
+                            p.push(0);
+                        }
+                    }
+                    let ranges = values
+                        .iter()
+                        .skip(skip)
+                        .map(|v| match v {
+                            IndexValue::Any => EncodedRange::all(),
+                            IndexValue::Equals(o) => EncodedRange::equal(index_component(o, hash_key.as_deref())),
+                        })
+                        .collect();
+                    let it = SkipScan::new(&self.db, loc, &p, skip, ranges, false)
+                        .map(move |(k, v)| index_record(&k, &v, &keys));
+                    return Ok(Box::new(it));
+                }
+            }
+            Operation::IndexRange {
+                name,
+                index_name,
+                prefix,
+                ranges,
+                descending,
+                keys,
+            } => {
+                if let Some(loc) = self.index_location(&name, &index_name) {
+                    let hash_key = self.index_hash_key(&name)?;
+                    if hash_key.is_some() && ranges.iter().any(|r| r.is_bounded()) {
+                        return Err(QueryError::HashedIndexRange {
+                            rec_type: name,
+                            index_name,
                         }
-                        let mut opts = ReadOptions::default();
-                        let mut u = v.clone();
-                        u.pop();
-                        u.push(1);
-                        opts.set_iterate_upper_bound(u);
-                        let mode = IteratorMode::From(&v.as_ref(), Direction::Forward);
-                        let it = self.db.iterator_cf_opt(cf, opts, mode).map(move |(k, v)| {
-                            EQLRecord::new(
-                                serde_json::from_slice(&v).unwrap(),
-                                extract_from_index_key(&k, &keys),
-                            )
-                        });
-                        return Ok(Box::new(it));
+                        .into());
+                    }
+                    let mut p = vec![];
+                    for o in prefix.iter() {
+                        p.append(&mut index_component(o, hash_key.as_deref()));
+                        p.push(0);
+                    }
+                    let ranges = ranges.iter().map(|r| r.encode()).collect();
+                    let it = SkipScan::new(&self.db, loc, &p, prefix.len(), ranges, descending)
+                        .map(move |(k, v)| index_record(&k, &v, &keys));
+                    return Ok(Box::new(it));
+                }
+            }
+            Operation::TextSearch {
+                name,
+                index_name,
+                query,
+            } => {
+                if let Some(loc) = self.index_location(&name, &index_name) {
+                    let text = self.text_options(&name, &index_name).ok_or_else(|| TextError::NotTextIndex {
+                        rec_type: name.clone(),
+                        index_name: index_name.clone(),
+                    })?;
+                    let matcher = match TextQuery::parse(&query)?.analyze(text) {
+                        Some(m) => m,
+                        None => return Ok(Box::new(iter::empty::<EQLRecord>())),
+                    };
+                    let hash_key = self.index_hash_key(&name)?;
+                    let mut postings: HashMap<&str, Postings> = HashMap::new();
+                    for term in matcher.terms() {
+                        let mut p = index_component(&Value::String(String::from(term)), hash_key.as_deref());
+                        p.push(0);
+                        let ps = loc
+                            .iter(&self.db, &p, ReadOptions::default())
+                            .take_while(|(k, _)| k.starts_with(&p))
+                            .map(|(_, v)| {
+                                let (kv, positions) = split_entry_value(&v);
+                                let positions = positions
+                                    .and_then(|p| serde_json::from_value(p.get("positions")?.clone()).ok())
+                                    .unwrap_or_default();
+                                (kv.to_vec(), positions)
+                            })
+                            .collect();
+                        postings.insert(term, ps);
+                    }
+                    // the estimated number of records, exact for shared storage which counts them
+                    let records = match self.location(&name) {
+                        Some(l) => location_usage(&self.db, &l)?.estimated_keys as usize,
+                        None => 0,
+                    };
+                    let mut scores: Vec<(Vec<u8>, f64)> = matcher.scores(&postings, records).into_iter().collect();
+                    scores.sort_by(|(k1, s1), (k2, s2)| s2.partial_cmp(s1).unwrap_or(Ordering::Equal).then_with(|| k1.cmp(k2)));
+                    return Ok(Box::new(scores.into_iter().map(|(kv, score)| {
+                        EQLRecord::new(serde_json::from_slice(&kv).unwrap(), serde_json::json!({ "score": score }))
+                    })));
+                }
+            }
+            Operation::GeoWithinBox {
+                name,
+                index_name,
+                min_lat,
+                min_lon,
+                max_lat,
+                max_lon,
+            } => {
+                check_coordinates(min_lat, min_lon)?;
+                check_coordinates(max_lat, max_lon)?;
+                if min_lat > max_lat {
+                    return Err(GeoError::InvalidBox { min_lat, max_lat }.into());
+                }
+                let center_lat = (min_lat + max_lat) / 2.0;
+                let mut center_lon = (min_lon + max_lon) / 2.0;
+                if min_lon > max_lon {
+                    center_lon += if center_lon > 0.0 { -180.0 } else { 180.0 };
+                }
+                let v = self.geo_search(&name, &index_name, (min_lat, min_lon, max_lat, max_lon), |lat, lon| {
+                    if in_box(lat, lon, min_lat, min_lon, max_lat, max_lon) {
+                        Some(distance(center_lat, center_lon, lat, lon))
+                    } else {
+                        None
                     }
+                })?;
+                return Ok(Box::new(v.into_iter()));
+            }
+            Operation::GeoWithinRadius {
+                name,
+                index_name,
+                lat,
+                lon,
+                radius,
+            } => {
+                check_coordinates(lat, lon)?;
+                if radius.is_nan() || radius < 0.0 {
+                    return Err(GeoError::InvalidRadius { radius }.into());
                 }
+                let v = self.geo_search(&name, &index_name, radius_box(lat, lon, radius), |lat2, lon2| {
+                    Some(distance(lat, lon, lat2, lon2)).filter(|d| *d <= radius)
+                })?;
+                return Ok(Box::new(v.into_iter()));
             }
             Operation::NestedLoops { first, second } => {
                 return Ok(Box::new(self.execute(*first)?
@@ -545,18 +2305,19 @@ impl EQLDB {
                 probe,
                 probe_hash,
                 join,
+                options,
             } => {
-                let map: HashMap<String, EQLRecord> = self
+                let by_value = options.numbers_by_value;
+                let build_it = self
                     .execute(*build)?
-                    .flat_map(|rec| build_hash.apply(&rec).map(|s| (format!("{}", s), rec)))
-                    .collect();
-                return Ok(Box::new(self.execute(*probe)?.flat_map(move |rec| {
-                    probe_hash.apply(&rec)
-                        .map(|h| {
-                            let hash = format!("{}", h);
-                            join((map.get(&hash), rec))
-                        })
-                }).collect::<Result<Vec<Option<EQLRecord>>>>()?.into_iter().filter_map(|c| c)));
+                    .flat_map(|rec| build_hash.apply(&rec).map(|s| Ok((CanonicalValue::new(&s, by_value), rec))));
+                let probe_it = self
+                    .execute(*probe)?
+                    .flat_map(move |rec| probe_hash.apply(&rec).map(|h| Ok((CanonicalValue::new(&h, by_value), rec))));
+                let v = GraceHashJoin::new(self.db.path(), options.memory_budget, join)
+                    .run(build_it, probe_it)?
+                    .collect::<Result<Vec<EQLRecord>>>()?;
+                return Ok(Box::new(v.into_iter()));
             }
             Operation::Merge {
                 first,
@@ -564,52 +2325,35 @@ impl EQLDB {
                 second,
                 second_key,
                 join,
+                compare,
             } => {
-                let mut it1 = self.execute(*first)?.peekable();
-                let mut it2 = self.execute(*second)?.peekable();
-                let mut v = vec![];
-                let mut orec1 = it1.next();
-                let mut orec2 = it2.next();
-                while orec1.is_some() || orec2.is_some() {
-                    if let Some(rec1) = &orec1 {
-                        if let Some(rec2) = &orec2 {
-                            let k1 = serde_json::to_vec(&first_key.apply(rec1).unwrap_or_else(|| Value::Null)).unwrap();
-                            let k2 = serde_json::to_vec(&second_key.apply(rec2).unwrap_or_else(|| Value::Null)).unwrap();
-                            match k1.cmp(&k2) {
-                                Ordering::Less => {
-                                    if let Some(rec3) =  join((Some(rec1), None))?{
-                                        v.push(rec3);
-                                    }
-                                    orec1 = it1.next();
-                                }
-                                Ordering::Greater => {
-                                    if let Some(rec3) = join((None, Some(rec2)))?{
-                                        v.push(rec3);
-                                    }
-                                    orec2 = it2.next();
-                                }
-                                Ordering::Equal => {
-                                    if let Some(rec3) = join((Some(rec1), Some(rec2)))?{
-                                        v.push(rec3);
-                                    }
-                                    orec2 = it2.next();
-                                }
-                            }
-                        } else {
-                            if let Some(rec3) = join((Some(rec1), None))?{
-                                v.push(rec3);
-                            }
-                            orec1 = it1.next();
-                        }
-                    } else if let Some(rec2) = &orec2 {
-                        if let Some(rec3)= join((None, Some(rec2)))?{
-                            v.push(rec3);
-                        }
-                        orec2 = it2.next();
-                    }
-                }
+                let v = merge_join(
+                    self.execute(*first)?,
+                    &first_key,
+                    self.execute(*second)?,
+                    &second_key,
+                    &join,
+                    &compare,
+                )?;
                 return Ok(Box::new(v.into_iter()));
             },
+            Operation::Sort {
+                operation,
+                key,
+                descending,
+                compare,
+            } => {
+                let mut v: Vec<(Value, EQLRecord)> = self
+                    .execute(*operation)?
+                    .map(|rec| (key.apply(&rec).unwrap_or(Value::Null), rec))
+                    .collect();
+                if descending {
+                    v.sort_by(|(k1, _), (k2, _)| compare(k2, k1));
+                } else {
+                    v.sort_by(|(k1, _), (k2, _)| compare(k1, k2));
+                }
+                return Ok(Box::new(v.into_iter().map(|(_, rec)| rec)));
+            },
             Operation::Process {operation, process} => {
                return process(self.execute(*operation)?);
             },
@@ -653,13 +2397,27 @@ fn extract_from_index_key<K: AsRef<[u8]>>(k: K, keys: &[String]) -> Value {
     let mut im: BTreeMap<String, Value> = BTreeMap::new();
     for (part, name) in k.as_ref().split(|u| *u == 0).zip(keys.iter()) {
         if !name.is_empty() {
-            im.insert(name.clone(), serde_json::from_slice(part).unwrap());
+            im.insert(name.clone(), decode_ordered(part).unwrap_or_default());
         }
     }
 
     serde_json::to_value(im).unwrap()
 }
 
+/// Builds the record returned by an index lookup from an index entry: the record key, and a value holding the
+/// named index key parts and, for a covering index, the included values
+fn index_record(k: &[u8], v: &[u8], keys: &[String]) -> EQLRecord {
+    let (kv, included) = split_entry_value(v);
+    let value = extract_from_index_key(k, keys);
+    EQLRecord::new(
+        serde_json::from_slice(kv).unwrap(),
+        match included {
+            Some(included) => merge_values(&value, included),
+            None => value,
+        },
+    )
+}
+
 /// Only keep given names in given JSON value
 fn extract_from_value(mut value: Value, names: &HashSet<String>) -> Value {
     if let Some(m) = value.as_object_mut(){
@@ -677,25 +2435,83 @@ fn get_map_value(n: &str, m: &mut Map<String, Value>) -> Option<(String, Value)>
     m.remove(n).map(|v| (String::from(n), v))
 }
 
+/// The byte prefix shared by all array keys starting with the given components
+/// Keys are stored as compact JSON, so the encoding of an array key starts with the encoding of
+/// any of its prefixes, minus the closing bracket
+fn key_prefix(prefix: &[Value]) -> Vec<u8> {
+    let mut bs = serde_json::to_vec(prefix).unwrap();
+    bs.pop();
+    bs
+}
+
+/// Checks that a key starting with a prefix from `key_prefix` continues with a new component or ends there,
+/// so that `[4]` does not match `[42]`
+/// # Arguments
+/// * `key` - the stored key
+/// * `prefix_len` - the length of the byte prefix
+/// * `empty` - true if the prefix had no components
+fn is_component_boundary(key: &[u8], prefix_len: usize, empty: bool) -> bool {
+    empty || matches!(key.get(prefix_len), Some(b',') | Some(b']'))
+}
+
+/// The version of a stored record: a FNV-1a hash of its bytes, stable across runs
+fn record_version(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Encodes an indexed value so that index keys sort by value, replacing it by its keyed hash when a hash key is given
+fn index_component(value: &Value, hash_key: Option<&[u8]>) -> Vec<u8> {
+    match hash_key {
+        Some(k) => encode_ordered(&Value::String(keyed_hash(k, &serde_json::to_vec(value).unwrap()))),
+        None => encode_ordered(value),
+    }
+}
+
 /// Get the underlying column family name for a given record type and index name
 fn index_cf_name(ref_type: &str, index_name: &str) -> String {
     format!("#idx_{}_{}", ref_type, index_name)
 }
 
-/// Builds the key for an index column family
-/// The index key is made of the serialized values separated by 0, and the record key
-fn index_key<T: AsRef<str>, K: AsRef<[u8]>>(on: &[T], key: &K, value: &Value) -> Vec<u8> {
-    let mut v = vec![];
-    for o in on {
-        if let Some(v2) = value.pointer(o.as_ref()) {
-            v.append(&mut serde_json::to_vec(v2).unwrap());
-        } else {
-            v.append(&mut serde_json::to_vec(&Value::Null).unwrap());
-        }
-        v.push(0);
+/// Builds the keys for an index column family
+/// An index key is made of the encoded values separated by 0, and the record key
+/// When a hash key is given, each value is replaced by its keyed hash
+/// A pointer with a `*` segment gives a value per array element, so a record gets one key per combination of values
+fn index_keys<K: AsRef<[u8]>>(values: &[Vec<Value>], key: &K, hash_key: Option<&[u8]>) -> Vec<Vec<u8>> {
+    let mut keys = index_prefixes(values, hash_key, false);
+    for k in keys.iter_mut() {
+        k.extend_from_slice(key.as_ref());
     }
-    for k in key.as_ref() {
-        v.push(*k);
+    keys
+}
+
+/// Builds the distinct parts of index keys made of the indexed values
+/// # Arguments
+/// * `values` - the values of each index component
+/// * `hash_key` - the key to hash indexed values with, if they are hashed
+/// * `skip_null` - true to leave out the combinations with a missing or null value
+fn index_prefixes(values: &[Vec<Value>], hash_key: Option<&[u8]>, skip_null: bool) -> Vec<Vec<u8>> {
+    let mut prefixes = vec![vec![]];
+    for vs in values {
+        let components: Vec<Vec<u8>> = vs
+            .iter()
+            .filter(|v| !(skip_null && v.is_null()))
+            .map(|v| index_component(v, hash_key))
+            .collect();
+        prefixes = prefixes
+            .iter()
+            .flat_map(|p| {
+                components.iter().map(move |c| {
+                    let mut p = p.clone();
+                    p.extend_from_slice(c);
+                    p.push(0);
+                    p
+                })
+            })
+            .collect();
     }
-    v
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
 }
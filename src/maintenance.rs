@@ -0,0 +1,184 @@
+use crate::storage::Location;
+use anyhow::Result;
+use rocksdb::{ColumnFamily, ReadOptions, DB};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+
+/// The disk usage of a record type or an index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    /// The estimated number of keys
+    pub estimated_keys: u64,
+    /// The estimated size in bytes of the live data
+    pub live_data_size: u64,
+    /// The total size in bytes of the SST files
+    pub sst_files_size: u64,
+    /// The size in bytes of the memtables
+    pub memtable_size: u64,
+}
+
+/// The disk usage of a record type and its indices
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TypeUsage {
+    /// The usage of the records
+    pub data: Usage,
+    /// The usage of each index
+    pub indices: BTreeMap<String, Usage>,
+}
+
+/// The disk usage of the whole database
+/// Record types in shared storage are measured by scanning their entries, so their key counts and data sizes
+/// are exact, but their SST and memtable sizes are only reported for the shared column family as a whole
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    /// The usage of each record type
+    pub types: BTreeMap<String, TypeUsage>,
+    /// The usage of the shared column family
+    pub shared: Usage,
+}
+
+/// A snapshot of RocksDB internal counters, summed over all column families
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// The size in bytes of the active memtables
+    pub active_memtable_size: u64,
+    /// The size in bytes of all memtables, active, immutable and pinned
+    pub memtable_size: u64,
+    /// The number of immutable memtables not flushed yet
+    pub immutable_memtables: u64,
+    /// The memory size in bytes of the block cache
+    pub block_cache_usage: u64,
+    /// The memory size in bytes of the pinned entries of the block cache
+    pub block_cache_pinned_usage: u64,
+    /// The number of running compactions
+    pub running_compactions: u64,
+    /// The number of running flushes
+    pub running_flushes: u64,
+    /// The number of column families with a compaction pending
+    pub pending_compactions: u64,
+    /// The estimated number of bytes compaction needs to rewrite
+    pub pending_compaction_bytes: u64,
+    /// The number of background errors
+    pub background_errors: u64,
+    /// The full text report of RocksDB
+    pub report: String,
+}
+
+/// The differences between an index and the entries its records give
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct IndexReport {
+    /// The number of records checked
+    pub records: usize,
+    /// The number of index entries the records give
+    pub entries: usize,
+    /// The number of entries missing from the index, or whose value is out of date
+    pub missing_entries: usize,
+    /// The number of entries in the index that no record gives
+    pub orphaned_entries: usize,
+    /// The keys of the records with missing entries
+    pub missing: Vec<Value>,
+    /// The record keys held by the orphaned entries
+    pub orphaned: Vec<Value>,
+}
+
+impl IndexReport {
+    /// Returns true if the index holds exactly the entries of its records
+    pub fn is_consistent(&self) -> bool {
+        self.missing_entries == 0 && self.orphaned_entries == 0
+    }
+
+    /// Counts a missing entry
+    pub(crate) fn add_missing(&mut self, key: Value, seen: &mut HashSet<String>) {
+        self.missing_entries += 1;
+        if seen.insert(key.to_string()) {
+            self.missing.push(key);
+        }
+    }
+
+    /// Counts an orphaned entry
+    pub(crate) fn add_orphaned(&mut self, key: Value, seen: &mut HashSet<String>) {
+        self.orphaned_entries += 1;
+        if seen.insert(key.to_string()) {
+            self.orphaned.push(key);
+        }
+    }
+}
+
+/// What an index check does with the entries it compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IndexCheck {
+    /// Only reports the differences
+    Verify,
+    /// Writes the missing entries and deletes the orphaned ones
+    Repair,
+    /// Writes all the entries the records give and deletes the orphaned ones, in a single SST file
+    Rebuild,
+}
+
+/// Reads an integer property of a column family, 0 if it is not available
+fn cf_property(db: &DB, cf: &ColumnFamily, name: &str) -> Result<u64> {
+    Ok(db.property_int_value_cf(cf, name)?.unwrap_or_default())
+}
+
+/// Measures a location: whole column families are measured with RocksDB properties, shared locations by scanning
+/// # Arguments
+/// * `db` - the database
+/// * `loc` - the location
+pub(crate) fn location_usage(db: &DB, loc: &Location) -> Result<Usage> {
+    if loc.is_shared() {
+        let mut usage = Usage::default();
+        for (k, v) in loc.iter(db, &[], ReadOptions::default()) {
+            usage.estimated_keys += 1;
+            usage.live_data_size += (k.len() + v.len()) as u64;
+        }
+        Ok(usage)
+    } else {
+        cf_usage(db, loc.cf)
+    }
+}
+
+/// Measures a whole column family with RocksDB properties
+pub(crate) fn cf_usage(db: &DB, cf: &ColumnFamily) -> Result<Usage> {
+    Ok(Usage {
+        estimated_keys: cf_property(db, cf, "rocksdb.estimate-num-keys")?,
+        live_data_size: cf_property(db, cf, "rocksdb.estimate-live-data-size")?,
+        sst_files_size: cf_property(db, cf, "rocksdb.total-sst-files-size")?,
+        memtable_size: cf_property(db, cf, "rocksdb.size-all-mem-tables")?,
+    })
+}
+
+/// Reads the counters of the database
+/// # Arguments
+/// * `db` - the database
+/// * `cfs` - all the column families
+pub(crate) fn db_stats(db: &DB, cfs: &[&ColumnFamily]) -> Result<Stats> {
+    let mut stats = Stats {
+        block_cache_usage: db
+            .property_int_value("rocksdb.block-cache-usage")?
+            .unwrap_or_default(),
+        block_cache_pinned_usage: db
+            .property_int_value("rocksdb.block-cache-pinned-usage")?
+            .unwrap_or_default(),
+        running_compactions: db
+            .property_int_value("rocksdb.num-running-compactions")?
+            .unwrap_or_default(),
+        running_flushes: db
+            .property_int_value("rocksdb.num-running-flushes")?
+            .unwrap_or_default(),
+        background_errors: db
+            .property_int_value("rocksdb.background-errors")?
+            .unwrap_or_default(),
+        report: db.property_value("rocksdb.stats")?.unwrap_or_default(),
+        ..Default::default()
+    };
+    for cf in cfs {
+        stats.active_memtable_size += cf_property(db, cf, "rocksdb.cur-size-active-mem-table")?;
+        stats.memtable_size += cf_property(db, cf, "rocksdb.size-all-mem-tables")?;
+        stats.immutable_memtables += cf_property(db, cf, "rocksdb.num-immutable-mem-table")?;
+        stats.pending_compactions += cf_property(db, cf, "rocksdb.compaction-pending")?;
+        stats.pending_compaction_bytes +=
+            cf_property(db, cf, "rocksdb.estimate-pending-compaction-bytes")?;
+    }
+    Ok(stats)
+}
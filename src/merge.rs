@@ -0,0 +1,207 @@
+use crate::{EQLRecord, QueryError, RecordExtract};
+use crate::compare::ValueComparator;
+use anyhow::Result;
+use serde_json::Value;
+use std::{cmp::Ordering, iter::Peekable};
+
+/// The merge join function
+type MergeFn<'a> = dyn Fn((Option<&EQLRecord>, Option<&EQLRecord>)) -> Result<Option<EQLRecord>> + 'a;
+
+/// A run of records sharing the same merge key
+struct Run {
+    /// The merge key, records without a key get the null key
+    key: Value,
+    records: Vec<EQLRecord>,
+}
+
+/// Groups consecutive records with the same merge key, checking the keys are sorted
+struct Runs<'a, I: Iterator<Item = EQLRecord>> {
+    /// The name of the input, for errors
+    side: &'static str,
+    it: Peekable<I>,
+    extract: &'a RecordExtract,
+    compare: &'a ValueComparator<'a>,
+    /// The key of the last run, to check the next one comes after it
+    last: Option<Value>,
+}
+
+impl<'a, I: Iterator<Item = EQLRecord>> Runs<'a, I> {
+    fn new(side: &'static str, it: I, extract: &'a RecordExtract, compare: &'a ValueComparator<'a>) -> Self {
+        Runs {
+            side,
+            it: it.peekable(),
+            extract,
+            compare,
+            last: None,
+        }
+    }
+
+    /// Reads the next run
+    fn next_run(&mut self) -> Result<Option<Run>> {
+        let first = match self.it.next() {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let key = self.extract.apply(&first).unwrap_or(Value::Null);
+        if let Some(last) = &self.last {
+            if (self.compare)(last, &key) == Ordering::Greater {
+                return Err(QueryError::UnsortedMergeInput {
+                    side: self.side,
+                    previous: last.to_string(),
+                    key: key.to_string(),
+                }
+                .into());
+            }
+        }
+        let mut records = vec![first];
+        while let Some(rec) = self.it.peek() {
+            let k = self.extract.apply(rec).unwrap_or(Value::Null);
+            if (self.compare)(&k, &key) != Ordering::Equal {
+                break;
+            }
+            records.extend(self.it.next());
+        }
+        self.last = Some(key.clone());
+        Ok(Some(Run { key, records }))
+    }
+}
+
+/// Merges two inputs sorted by their merge key in the order of the comparator
+/// Records whose keys only appear on one side are joined alone, and each run of records sharing a key
+/// is joined with every record of the run with the same key on the other side
+/// # Arguments
+/// * `first` - the first input
+/// * `first_key` - the merge key of the first records
+/// * `second` - the second input
+/// * `second_key` - the merge key of the second records
+/// * `join` - the join function
+/// * `compare` - the comparator of merge keys
+pub(crate) fn merge_join<'a, I1, I2>(
+    first: I1,
+    first_key: &'a RecordExtract,
+    second: I2,
+    second_key: &'a RecordExtract,
+    join: &MergeFn,
+    compare: &'a ValueComparator<'a>,
+) -> Result<Vec<EQLRecord>>
+where
+    I1: Iterator<Item = EQLRecord>,
+    I2: Iterator<Item = EQLRecord>,
+{
+    let mut runs1 = Runs::new("first", first, first_key, compare);
+    let mut runs2 = Runs::new("second", second, second_key, compare);
+    let mut v = vec![];
+    let mut push = |r: Option<EQLRecord>| v.extend(r);
+    let mut orun1 = runs1.next_run()?;
+    let mut orun2 = runs2.next_run()?;
+    loop {
+        let ord = match (&orun1, &orun2) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(run1), Some(run2)) => compare(&run1.key, &run2.key),
+        };
+        match ord {
+            Ordering::Less => {
+                if let Some(run1) = &orun1 {
+                    for rec1 in &run1.records {
+                        push(join((Some(rec1), None))?);
+                    }
+                }
+                orun1 = runs1.next_run()?;
+            }
+            Ordering::Greater => {
+                if let Some(run2) = &orun2 {
+                    for rec2 in &run2.records {
+                        push(join((None, Some(rec2)))?);
+                    }
+                }
+                orun2 = runs2.next_run()?;
+            }
+            Ordering::Equal => {
+                if let (Some(run1), Some(run2)) = (&orun1, &orun2) {
+                    for rec1 in &run1.records {
+                        for rec2 in &run2.records {
+                            push(join((Some(rec1), Some(rec2)))?);
+                        }
+                    }
+                }
+                orun1 = runs1.next_run()?;
+                orun2 = runs2.next_run()?;
+            }
+        }
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::compare_values;
+    use serde_json::json;
+
+    fn records(keys: &[u64]) -> Vec<EQLRecord> {
+        keys.iter()
+            .enumerate()
+            .map(|(i, k)| EQLRecord::new(json!(i), json!({ "k": k })))
+            .collect()
+    }
+
+    fn pair((o1, o2): (Option<&EQLRecord>, Option<&EQLRecord>)) -> Result<Option<EQLRecord>> {
+        Ok(Some(EQLRecord::new(
+            json!([o1.map(|r| r.key.clone()), o2.map(|r| r.key.clone())]),
+            Value::Null,
+        )))
+    }
+
+    #[test]
+    fn test_runs() -> Result<()> {
+        let k = RecordExtract::pointer("/k");
+        let compare: ValueComparator = Box::new(compare_values);
+        let v = merge_join(
+            records(&[1, 2, 2, 10]).into_iter(),
+            &k,
+            records(&[2, 2, 2, 3]).into_iter(),
+            &k,
+            &pair,
+            &compare,
+        )?;
+        let keys: Vec<Value> = v.into_iter().map(|r| r.key).collect();
+        assert_eq!(
+            vec![
+                json!([0, null]),
+                json!([1, 0]),
+                json!([1, 1]),
+                json!([1, 2]),
+                json!([2, 0]),
+                json!([2, 1]),
+                json!([2, 2]),
+                json!([null, 3]),
+                json!([3, null]),
+            ],
+            keys
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsorted() {
+        let k = RecordExtract::pointer("/k");
+        let compare: ValueComparator = Box::new(compare_values);
+        let r = merge_join(
+            records(&[1, 2]).into_iter(),
+            &k,
+            records(&[10, 2]).into_iter(),
+            &k,
+            &pair,
+            &compare,
+        );
+        assert_eq!(
+            true,
+            matches!(
+                r.unwrap_err().downcast_ref::<QueryError>(),
+                Some(QueryError::UnsortedMergeInput { side: "second", .. })
+            )
+        );
+    }
+}
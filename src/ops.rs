@@ -6,6 +6,14 @@ use std::{
 use anyhow::Result;
 use thiserror::Error;
 
+use crate::format::ValueFormat;
+use crate::sequence::KeyGenerator;
+use crate::storage::Storage;
+use crate::crypt::Encryption;
+use crate::index::{IndexOptions, IndexRange, IndexValue};
+use crate::join::{HashJoinOptions, DEFAULT_JOIN_MEMORY_BUDGET};
+use crate::compare::{compare_values, ValueComparator};
+
 #[derive(Error, Debug)]
 pub enum QueryError {
     #[error("Script error in nested loops: {0}")]
@@ -22,6 +30,17 @@ pub enum QueryError {
     ParseError(String),
     #[error("Error converting value to scripting Dynamic: {0}")]
     DynamicError(String),
+    #[error("Index {index_name} of record type {rec_type} stores hashed values, which cannot be looked up by range")]
+    HashedIndexRange {
+        rec_type: String,
+        index_name: String,
+    },
+    #[error("The {side} input of a merge is not sorted by its key: {key} comes after {previous}")]
+    UnsortedMergeInput {
+        side: &'static str,
+        previous: String,
+        key: String,
+    },
 }
 
 /// A specific operation on the data store
@@ -33,6 +52,16 @@ pub enum Operation<'a> {
         name: String,
         key: Value,
     },
+    KeyPrefixScan {
+        name: String,
+        prefix: Vec<Value>,
+    },
+    MultiKeyLookup {
+        name: String,
+        keys: Vec<Value>,
+        preserve_order: bool,
+        report_missing: bool,
+    },
     Extract {
         names: HashSet<String>,
         operation: Box<Operation<'a>>,
@@ -44,9 +73,37 @@ pub enum Operation<'a> {
     IndexLookup {
         name: String,
         index_name: String,
-        values: Vec<Value>,
+        values: Vec<IndexValue>,
+        keys: Vec<String>,
+    },
+    IndexRange {
+        name: String,
+        index_name: String,
+        prefix: Vec<Value>,
+        ranges: Vec<IndexRange>,
+        descending: bool,
         keys: Vec<String>,
     },
+    TextSearch {
+        name: String,
+        index_name: String,
+        query: String,
+    },
+    GeoWithinBox {
+        name: String,
+        index_name: String,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    },
+    GeoWithinRadius {
+        name: String,
+        index_name: String,
+        lat: f64,
+        lon: f64,
+        radius: f64,
+    },
     NestedLoops {
         first: Box<Operation<'a>>,
         second: Box<dyn Fn(&EQLRecord) -> Result<Operation<'a>> + 'a>,
@@ -57,6 +114,7 @@ pub enum Operation<'a> {
         probe: Box<Operation<'a>>,
         probe_hash: RecordExtract,
         join: HashJoinFunction<'a>,
+        options: HashJoinOptions,
     },
     Merge {
         first: Box<Operation<'a>>,
@@ -64,6 +122,13 @@ pub enum Operation<'a> {
         second: Box<Operation<'a>>,
         second_key: RecordExtract,
         join: MergeJoinFunction<'a>,
+        compare: ValueComparator<'a>,
+    },
+    Sort {
+        operation: Box<Operation<'a>>,
+        key: RecordExtract,
+        descending: bool,
+        compare: ValueComparator<'a>,
     },
     Process {
         operation: Box<Operation<'a>>,
@@ -124,6 +189,47 @@ pub fn key_lookup<'a,'b, N: Into<String>, V:Into<ValueRef<'b>>>(name: N, key: V)
     }
 }
 
+/// Builds an operation to scan the records whose keys are arrays starting with the given components
+/// # Arguments
+/// * `name` - the name of the record type
+/// * `prefix` - the first components of the keys
+pub fn key_prefix_scan<'a, N: Into<String>>(name: N, prefix: Vec<Value>) -> Operation<'a> {
+    Operation::KeyPrefixScan {
+        name: name.into(),
+        prefix,
+    }
+}
+
+/// Builds an operation to lookup several keys at once, returning the records in the order of the keys
+/// and ignoring keys that are not found
+/// The distinct keys are read in a single batched RocksDB `multi_get`, which sees a consistent view of the records
+/// # Arguments
+/// * `name` - the name of the record type
+/// * `keys` - the keys
+pub fn multi_key_lookup<'a, N: Into<String>>(name: N, keys: Vec<Value>) -> Operation<'a> {
+    multi_key_lookup_with(name, keys, true, false)
+}
+
+/// Builds an operation to lookup several keys at once
+/// # Arguments
+/// * `name` - the name of the record type
+/// * `keys` - the keys
+/// * `preserve_order` - true to return records in the order of the keys, false to return them in storage order without duplicates
+/// * `report_missing` - true to return a record with a null value for each key that is not found
+pub fn multi_key_lookup_with<'a, N: Into<String>>(
+    name: N,
+    keys: Vec<Value>,
+    preserve_order: bool,
+    report_missing: bool,
+) -> Operation<'a> {
+    Operation::MultiKeyLookup {
+        name: name.into(),
+        keys,
+        preserve_order,
+        report_missing,
+    }
+}
+
 /// Builds an operation to extract specific keys from the values returned by the wrapped operation
 /// # Arguments
 /// * `extract` - the names of the keys to extract, the others will be dropped
@@ -154,33 +260,45 @@ pub fn augment<'a,'b, V:Into<ValueRef<'b>>>(value: V, operation: Operation<'a>)
 /// # Arguments
 /// * `name` - the name of the table/column family
 /// * `index_name` - the name of the index
-/// * `values` - the values to lookup in the index, in the order the index was built. Null values can be used to indicate
-/// all values can be considered at this level in the index. An empty Vec means the full index will be scanned
+/// * `values` - the values to lookup in the index, in the order the index was built. Null values match records
+/// with null or missing values, see `index_lookup_with` to match any value. An empty Vec means the full index will be scanned
 pub fn index_lookup<'a,N: Into<String>, IN: Into<String>>(
     name: N,
     index_name: IN,
     values: Vec<Value>,
 ) -> Operation<'a> {
-    Operation::IndexLookup {
-        name: name.into(),
-        index_name: index_name.into(),
-        values,
-        keys: vec![],
-    }
+    index_lookup_with(name, index_name, values.into_iter().map(IndexValue::Equals).collect(), Vec::<String>::new())
 }
 
 /// Builds an operation to perform an index lookup and return some index keys
 /// # Arguments
 /// * `name` - the name of the table/column family
 /// * `index_name` - the name of the index
-/// * `values` - the values to lookup in the index, in the order the index was built. Null values can be used to indicate
-/// all values can be considered at this level in the index. An empty Vec means the full index will be scanned
+/// * `values` - the values to lookup in the index, in the order the index was built. Null values match records
+/// with null or missing values, see `index_lookup_with` to match any value. An empty Vec means the full index will be scanned
 /// * `keys` - the names to use as keys in the returned Value for each index values section, empty string meaning "ignore this part of the index key"
 pub fn index_lookup_keys<'a, N: Into<String>, IN: Into<String>, OT: AsRef<str>>(
     name: N,
     index_name: IN,
     values: Vec<Value>,
     keys: Vec<OT>,
+) -> Operation<'a> {
+    index_lookup_with(name, index_name, values.into_iter().map(IndexValue::Equals).collect(), keys)
+}
+
+/// Builds an operation to perform an index lookup where some components can take any value
+/// For each distinct value of a component matching any value, the lookup seeks directly to the entries matching
+/// the next components (a skip scan), which is fast when the leading components have few distinct values
+/// # Arguments
+/// * `name` - the name of the table/column family
+/// * `index_name` - the name of the index
+/// * `values` - the values to lookup in the index, in the order the index was built. An empty Vec means the full index will be scanned
+/// * `keys` - the names to use as keys in the returned Value for each index values section, empty string meaning "ignore this part of the index key"
+pub fn index_lookup_with<'a, N: Into<String>, IN: Into<String>, OT: AsRef<str>>(
+    name: N,
+    index_name: IN,
+    values: Vec<IndexValue>,
+    keys: Vec<OT>,
 ) -> Operation<'a> {
     Operation::IndexLookup {
         name: name.into(),
@@ -190,6 +308,118 @@ pub fn index_lookup_keys<'a, N: Into<String>, IN: Into<String>, OT: AsRef<str>>(
     }
 }
 
+/// Builds an operation to return the index entries whose component after the given prefix is in a range,
+/// in the order of the index
+/// # Arguments
+/// * `name` - the name of the table/column family
+/// * `index_name` - the name of the index
+/// * `prefix` - the values of the first components of the index, which must be equal
+/// * `range` - the range of the next component
+pub fn index_range<'a, N: Into<String>, IN: Into<String>>(
+    name: N,
+    index_name: IN,
+    prefix: Vec<Value>,
+    range: IndexRange,
+) -> Operation<'a> {
+    index_range_with(name, index_name, prefix, vec![range], false, Vec::<String>::new())
+}
+
+/// Builds an operation to return the index entries whose components after the given prefix are in ranges
+/// The index is read between the bounds of the first range, skipping the entries that do not match the following ranges
+/// # Arguments
+/// * `name` - the name of the table/column family
+/// * `index_name` - the name of the index
+/// * `prefix` - the values of the first components of the index, which must be equal
+/// * `ranges` - the ranges of the next components, in order
+/// * `descending` - true to return the entries in descending order of the index
+/// * `keys` - the names to use as keys in the returned Value for each index values section, empty string meaning "ignore this part of the index key"
+pub fn index_range_with<'a, N: Into<String>, IN: Into<String>, OT: AsRef<str>>(
+    name: N,
+    index_name: IN,
+    prefix: Vec<Value>,
+    ranges: Vec<IndexRange>,
+    descending: bool,
+    keys: Vec<OT>,
+) -> Operation<'a> {
+    Operation::IndexRange {
+        name: name.into(),
+        index_name: index_name.into(),
+        prefix,
+        ranges,
+        descending,
+        keys: keys.iter().map(|s| String::from(s.as_ref())).collect(),
+    }
+}
+
+/// Builds an operation to search a full-text index, returning the keys of the matching records by decreasing relevance,
+/// with a value holding their relevance score: `{"score": 1.5}`
+/// # Arguments
+/// * `name` - the name of the table/column family
+/// * `index_name` - the name of the full-text index
+/// * `query` - the words and quoted phrases to find, all of them unless separated by `OR`, grouped with parentheses: `(red OR blue) "running shoes"`
+pub fn text_search<'a, N: Into<String>, IN: Into<String>, Q: Into<String>>(
+    name: N,
+    index_name: IN,
+    query: Q,
+) -> Operation<'a> {
+    Operation::TextSearch {
+        name: name.into(),
+        index_name: index_name.into(),
+        query: query.into(),
+    }
+}
+
+/// Builds an operation to return the keys of the records of a geospatial index in a box, by increasing distance to its center,
+/// with a value holding the distance in meters: `{"distance": 1250.5}`
+/// # Arguments
+/// * `name` - the name of the table/column family
+/// * `index_name` - the name of the geospatial index
+/// * `min_lat` - the southern edge of the box
+/// * `min_lon` - the western edge of the box, greater than the eastern edge if the box crosses the antimeridian
+/// * `max_lat` - the northern edge of the box
+/// * `max_lon` - the eastern edge of the box
+pub fn geo_within_box<'a, N: Into<String>, IN: Into<String>>(
+    name: N,
+    index_name: IN,
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+) -> Operation<'a> {
+    Operation::GeoWithinBox {
+        name: name.into(),
+        index_name: index_name.into(),
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+    }
+}
+
+/// Builds an operation to return the keys of the records of a geospatial index within a distance of a point, by increasing
+/// distance, with a value holding the distance in meters: `{"distance": 1250.5}`
+/// # Arguments
+/// * `name` - the name of the table/column family
+/// * `index_name` - the name of the geospatial index
+/// * `lat` - the latitude of the point
+/// * `lon` - the longitude of the point
+/// * `radius` - the maximum distance in meters
+pub fn geo_within_radius<'a, N: Into<String>, IN: Into<String>>(
+    name: N,
+    index_name: IN,
+    lat: f64,
+    lon: f64,
+    radius: f64,
+) -> Operation<'a> {
+    Operation::GeoWithinRadius {
+        name: name.into(),
+        index_name: index_name.into(),
+        lat,
+        lon,
+        radius,
+    }
+}
+
 /// Builds an operation to perform nested loops
 /// # Arguments
 /// * `first` - the initial operation on which we'll iterate
@@ -204,14 +434,14 @@ where
     }
 }
 
-/// Builds an operation to perform a hash join lookup
+/// Builds an operation to perform a hash join lookup, with the default memory budget for the build side
 /// # Arguments
 /// * `build` - the build operation
 /// * `build_hash` - the function to build a value from each record from the first operation, returning None if we want to ignore that record
 /// * `probe` - the probe operation
 /// * `probe_hash` - the function to build a value from each record from the second operation, returning None if we want to ignore that record
 /// * `join` - the function to join the record from the first operation if it exists and the record from the second operation. Two records
-/// are joined when they gave the same value via `build_hash` and `probe_hash`
+/// are joined when they gave the same value via `build_hash` and `probe_hash`. The function is called once per matching record from the first operation, or once with None if none matches
 pub fn hash_join<'a,F>(
     build: Operation<'a>,
     build_hash: RecordExtract,
@@ -219,6 +449,63 @@ pub fn hash_join<'a,F>(
     probe_hash: RecordExtract,
     join: F,
 ) -> Operation<'a>
+where
+    F: Fn((Option<&EQLRecord>, EQLRecord)) -> Result<Option<EQLRecord>> + 'a,
+{
+    hash_join_with_budget(build, build_hash, probe, probe_hash, join, DEFAULT_JOIN_MEMORY_BUDGET)
+}
+
+/// Builds an operation to perform a hash join lookup
+/// When the build side does not fit in the memory budget, both sides are partitioned on disk and joined partition
+/// by partition, so the joined records do not come in the order of the probe records anymore
+/// # Arguments
+/// * `build` - the build operation
+/// * `build_hash` - the function to build a value from each record from the first operation, returning None if we want to ignore that record
+/// * `probe` - the probe operation
+/// * `probe_hash` - the function to build a value from each record from the second operation, returning None if we want to ignore that record
+/// * `join` - the function to join the record from the first operation if it exists and the record from the second operation, called once per matching record from the first operation, or once with None if none matches
+/// * `memory_budget` - the memory budget in bytes for the build side
+pub fn hash_join_with_budget<'a, F>(
+    build: Operation<'a>,
+    build_hash: RecordExtract,
+    probe: Operation<'a>,
+    probe_hash: RecordExtract,
+    join: F,
+    memory_budget: usize,
+) -> Operation<'a>
+where
+    F: Fn((Option<&EQLRecord>, EQLRecord)) -> Result<Option<EQLRecord>> + 'a,
+{
+    hash_join_with(
+        build,
+        build_hash,
+        probe,
+        probe_hash,
+        join,
+        HashJoinOptions {
+            memory_budget,
+            ..Default::default()
+        },
+    )
+}
+
+/// Builds an operation to perform a hash join lookup with the given options
+/// Values are matched on their canonical form, so objects match whatever the order of their fields
+/// # Arguments
+/// * `build` - the build operation
+/// * `build_hash` - the function to build a value from each record from the first operation, returning None if we want to ignore that record
+/// * `probe` - the probe operation
+/// * `probe_hash` - the function to build a value from each record from the second operation, returning None if we want to ignore that record
+/// * `join` - the function to join the record from the first operation if it exists and the record from the second operation, called once per matching record from the first operation, or once with None if none matches
+/// * `options` - the join options
+pub fn hash_join_with<'a, F>(
+    build: Operation<'a>,
+    build_hash: RecordExtract,
+    probe: Operation<'a>,
+    probe_hash: RecordExtract,
+    join: F,
+    options: HashJoinOptions,
+) -> Operation<'a>
 where
     F: Fn((Option<&EQLRecord>, EQLRecord)) -> Result<Option<EQLRecord>> + 'a,
 {
@@ -228,17 +515,18 @@ where
         probe: Box::new(probe),
         probe_hash,
         join: Box::new(join),
+        options,
     }
 }
 
-/// Builds an operation to merge two operations
+/// Builds an operation to merge two operations, comparing keys with `compare_values`
 /// # Arguments
 /// * `first` - the first operation
 /// * `first_key` - builds the array of values that will be the key for the first records.
 /// * `second` - the second operation
 /// * `second_key` - builds the array of values that will be the key for the second records
 /// * `join` - the function to join records from both operations. There may be only a first record, only a second record, or both.
-/// The join uses key comparisons so expects the two sets of keys to be in the same order
+/// The join uses key comparisons so expects the two sets of keys to be in the same order, and fails if an input is not sorted by its key. When several records share a key, each of them is joined with every record with the same key from the other operation
 pub fn merge<'a, F>(
     first: Operation<'a>,
     first_key: RecordExtract,
@@ -246,15 +534,67 @@ pub fn merge<'a, F>(
     second_key: RecordExtract,
     join: F,
 ) -> Operation<'a>
+where
+    F: Fn((Option<&EQLRecord>, Option<&EQLRecord>)) -> Result<Option<EQLRecord>> +'a,
+{
+    merge_with(first, first_key, second, second_key, join, Box::new(compare_values))
+}
+
+/// Builds an operation to merge two operations, comparing keys with the given comparator
+/// # Arguments
+/// * `first` - the first operation
+/// * `first_key` - builds the array of values that will be the key for the first records.
+/// * `second` - the second operation
+/// * `second_key` - builds the array of values that will be the key for the second records
+/// * `join` - the function to join records from both operations. There may be only a first record, only a second record, or both
+/// * `compare` - the order of keys, both operations must return their records sorted in that order
+pub fn merge_with<'a, F>(
+    first: Operation<'a>,
+    first_key: RecordExtract,
+    second: Operation<'a>,
+    second_key: RecordExtract,
+    join: F,
+    compare: ValueComparator<'a>,
+) -> Operation<'a>
 where
     F: Fn((Option<&EQLRecord>, Option<&EQLRecord>)) -> Result<Option<EQLRecord>> +'a,
 {
     Operation::Merge {
         first: Box::new(first),
-        first_key: first_key,
+        first_key,
         second: Box::new(second),
-        second_key: second_key,
+        second_key,
         join: Box::new(join),
+        compare,
+    }
+}
+
+/// Builds an operation to sort records in ascending order of a key, with `compare_values`
+/// # Arguments
+/// * `operation` - the underlying operation providing the records
+/// * `key` - the sort key of each record, records without a key get the null key, which comes first
+pub fn sort(operation: Operation, key: RecordExtract) -> Operation {
+    sort_with(operation, key, false, Box::new(compare_values))
+}
+
+/// Builds an operation to sort records with the given comparator
+/// The sort is stable, so records with equal keys keep their order
+/// # Arguments
+/// * `operation` - the underlying operation providing the records
+/// * `key` - the sort key of each record, records without a key get the null key and sort like null values
+/// * `descending` - true to sort in descending order
+/// * `compare` - the order of keys
+pub fn sort_with<'a>(
+    operation: Operation<'a>,
+    key: RecordExtract,
+    descending: bool,
+    compare: ValueComparator<'a>,
+) -> Operation<'a> {
+    Operation::Sort {
+        operation: Box::new(operation),
+        key,
+        descending,
+        compare,
     }
 }
 
@@ -276,6 +616,24 @@ pub fn process<'a>(
 pub struct Metadata {
     /// all the indices created: first key is record type, second is index name, the final value are the JSON pointers to index, in order
     pub indices: HashMap<String, HashMap<String, Vec<String>>>,
+    /// the storage format of each record type, types not listed here are stored as JSON
+    #[serde(default)]
+    pub formats: HashMap<String, ValueFormat>,
+    /// the key generator of each record type, types not listed here get plain numbers
+    #[serde(default)]
+    pub key_generators: HashMap<String, KeyGenerator>,
+    /// the storage of each record type, types not listed here have a dedicated column family
+    #[serde(default)]
+    pub storage: HashMap<String, Storage>,
+    /// the encryption settings of each record type, types not listed here are not encrypted
+    #[serde(default)]
+    pub encryption: HashMap<String, Encryption>,
+    /// the options of indices: first key is record type, second is index name, indices not listed here have the default options
+    #[serde(default)]
+    pub index_options: HashMap<String, HashMap<String, IndexOptions>>,
+    /// the version of the encoding of index keys, metadata written before versions were introduced gets 0
+    #[serde(default)]
+    pub index_key_version: u32,
 }
 
 /// A record from an operation. Both keys and values are arbitrary JSON values, but some operations expect the values to be JSON objects
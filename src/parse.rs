@@ -1,22 +1,33 @@
 use crate::script::*;
+use crate::compare::Collation;
+use crate::join::DEFAULT_JOIN_MEMORY_BUDGET;
+use crate::index::{IndexRange, IndexValue};
 
 use nom::{IResult, branch::alt, bytes::complete::{escaped_transform, tag, tag_no_case, take, take_while, take_while1}, character::{
-        complete::{char, none_of},
+        complete::{char, digit1, none_of},
         is_alphanumeric,
-    }, combinator::{cut, map, opt, value}, error::{ContextError, ParseError, VerboseError, context}, multi::{count, fold_many0, many_till, separated_list0}, number::complete::{double}, sequence::{delimited, pair, preceded, separated_pair, terminated}};
+    }, combinator::{cut, map, map_opt, opt, value}, error::{ContextError, ParseError, VerboseError, context}, multi::{count, fold_many0, many_till, separated_list0}, number::complete::{double}, sequence::{delimited, pair, preceded, separated_pair, terminated, tuple}};
 
-use serde_json::{Map, Value};
+use serde_json::{Map, Number, Value};
+use std::ops::Bound;
 
 pub fn parse_operation<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
     alt((
         parse_scan,
         parse_key_lookup,
+        parse_multi_key_lookup,
+        parse_key_prefix_scan,
         parse_extract,
         parse_augment,
         parse_index_lookup,
+        parse_index_range,
+        parse_text_search,
+        parse_geo_within_box,
+        parse_geo_within_radius,
         parse_nested_loops,
         parse_hash_lookup,
         parse_merge,
+        parse_sort,
         parse_map,
         parse_reduce,
     ))(input)
@@ -61,6 +72,69 @@ fn parse_key_lookup<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(inpu
     )(input)
 }
 
+fn parse_key_prefix_scan<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
+    map(
+        preceded(
+            spaced("key_prefix_scan"),
+            preceded(
+                spaced("("),
+                cut(terminated(
+                    preceded(
+                        sp,
+                        separated_pair(parse_eql_string, spaced(","), preceded(sp, array)),
+                    ),
+                    preceded(sp, char(')')),
+                )),
+            ),
+        ),
+        |(s, prefix)| ScriptedOperation::KeyPrefixScan { name: s, prefix },
+    )(input)
+}
+
+fn parse_multi_key_lookup<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
+    map(
+        preceded(
+            spaced("multi_key_lookup"),
+            preceded(
+                spaced("("),
+                cut(terminated(
+                    preceded(
+                        sp,
+                        separated_pair(
+                            parse_eql_string,
+                            spaced(","),
+                            preceded(
+                                sp,
+                                pair(
+                                    array,
+                                    opt(preceded(
+                                        spaced(","),
+                                        separated_pair(
+                                            preceded(sp, boolean),
+                                            spaced(","),
+                                            preceded(sp, boolean),
+                                        ),
+                                    )),
+                                ),
+                            ),
+                        ),
+                    ),
+                    preceded(sp, char(')')),
+                )),
+            ),
+        ),
+        |(s, (keys, flags))| {
+            let (preserve_order, report_missing) = flags.unwrap_or((true, false));
+            ScriptedOperation::MultiKeyLookup {
+                name: s,
+                keys,
+                preserve_order,
+                report_missing,
+            }
+        },
+    )(input)
+}
+
 fn parse_extract<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
     map(
         preceded(
@@ -124,7 +198,7 @@ fn parse_index_lookup<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(in
                             preceded(
                                 sp,
                                 pair(
-                                    array,
+                                    index_values,
                                     opt(preceded(spaced(","), preceded(sp, string_array))),
                                 ),
                             ),
@@ -143,6 +217,138 @@ fn parse_index_lookup<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(in
     )(input)
 }
 
+fn parse_index_range<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
+    map(
+        preceded(
+            spaced("index_range"),
+            preceded(
+                spaced("("),
+                cut(terminated(
+                    preceded(
+                        sp,
+                        tuple((
+                            parse_eql_string,
+                            preceded(spaced(","), preceded(sp, parse_eql_string)),
+                            preceded(spaced(","), preceded(sp, array)),
+                            preceded(spaced(","), index_ranges),
+                            opt(preceded(
+                                spaced(","),
+                                pair(
+                                    preceded(sp, boolean),
+                                    opt(preceded(spaced(","), preceded(sp, string_array))),
+                                ),
+                            )),
+                        )),
+                    ),
+                    preceded(sp, char(')')),
+                )),
+            ),
+        ),
+        |(tbl, idx, prefix, ranges, options)| {
+            let (descending, keys) = options.unwrap_or((false, None));
+            ScriptedOperation::IndexRange {
+                name: tbl,
+                index_name: idx,
+                prefix,
+                ranges,
+                descending,
+                keys: keys.unwrap_or_default(),
+            }
+        },
+    )(input)
+}
+
+fn parse_text_search<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
+    map(
+        preceded(
+            spaced("text_search"),
+            preceded(
+                spaced("("),
+                cut(terminated(
+                    preceded(
+                        sp,
+                        tuple((
+                            parse_eql_string,
+                            preceded(spaced(","), preceded(sp, parse_eql_string)),
+                            preceded(spaced(","), preceded(sp, string)),
+                        )),
+                    ),
+                    preceded(sp, char(')')),
+                )),
+            ),
+        ),
+        |(tbl, idx, query)| ScriptedOperation::TextSearch {
+            name: tbl,
+            index_name: idx,
+            query,
+        },
+    )(input)
+}
+
+fn parse_geo_within_box<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
+    map(
+        preceded(
+            spaced("geo_within_box"),
+            preceded(
+                spaced("("),
+                cut(terminated(
+                    preceded(
+                        sp,
+                        tuple((
+                            parse_eql_string,
+                            preceded(spaced(","), preceded(sp, parse_eql_string)),
+                            preceded(spaced(","), number),
+                            preceded(spaced(","), number),
+                            preceded(spaced(","), number),
+                            preceded(spaced(","), number),
+                        )),
+                    ),
+                    preceded(sp, char(')')),
+                )),
+            ),
+        ),
+        |(tbl, idx, min_lat, min_lon, max_lat, max_lon)| ScriptedOperation::GeoWithinBox {
+            name: tbl,
+            index_name: idx,
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+        },
+    )(input)
+}
+
+fn parse_geo_within_radius<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
+    map(
+        preceded(
+            spaced("geo_within_radius"),
+            preceded(
+                spaced("("),
+                cut(terminated(
+                    preceded(
+                        sp,
+                        tuple((
+                            parse_eql_string,
+                            preceded(spaced(","), preceded(sp, parse_eql_string)),
+                            preceded(spaced(","), number),
+                            preceded(spaced(","), number),
+                            preceded(spaced(","), number),
+                        )),
+                    ),
+                    preceded(sp, char(')')),
+                )),
+            ),
+        ),
+        |(tbl, idx, lat, lon, radius)| ScriptedOperation::GeoWithinRadius {
+            name: tbl,
+            index_name: idx,
+            lat,
+            lon,
+            radius,
+        },
+    )(input)
+}
+
 fn parse_nested_loops<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
     map(
         preceded(
@@ -176,18 +382,19 @@ fn parse_hash_lookup<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(inp
                             spaced(","),
                             separated_pair(parse_operation, spaced(","), parse_record_extract)),
                             spaced(","),
-                            quoted_str)
+                            pair(quoted_str, opt(preceded(spaced(","), memory_budget))))
                         ),
                     preceded(sp, char(')')),
                 )),
             ),
         )
-        ,|(((build,build_hash),(probe,probe_hash)),join)| ScriptedOperation::HashJoin{
+        ,|(((build,build_hash),(probe,probe_hash)),(join,memory_budget))| ScriptedOperation::HashJoin{
             build: Box::new(build),
             build_hash,
             probe: Box::new(probe),
             probe_hash,
-            join:join.into()
+            join:join.into(),
+            memory_budget: memory_budget.unwrap_or(DEFAULT_JOIN_MEMORY_BUDGET),
         }
     )(input)
 }
@@ -206,22 +413,59 @@ fn parse_merge<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'
                             spaced(","),
                             separated_pair(parse_operation, spaced(","), parse_record_extract)),
                             spaced(","),
-                            quoted_str)
+                            pair(quoted_str, opt(preceded(spaced(","), preceded(sp, collation)))))
                         ),
                     preceded(sp, char(')')),
                 )),
             ),
         )
-        ,|(((first,first_key),(second,second_key)),join)| ScriptedOperation::Merge{
+        ,|(((first,first_key),(second,second_key)),(join,collation))| ScriptedOperation::Merge{
             first: Box::new(first),
             first_key,
             second: Box::new(second),
             second_key,
-            join:join.into()
+            join:join.into(),
+            collation: collation.unwrap_or(Collation::Binary),
         }
     )(input)
 }
 
+fn parse_sort<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
+    map(
+        preceded(
+            spaced("sort"),
+            preceded(
+                spaced("("),
+                cut(terminated(
+                    preceded(
+                        sp,
+                        pair(
+                            separated_pair(parse_operation, spaced(","), parse_record_extract),
+                            opt(preceded(
+                                spaced(","),
+                                pair(
+                                    preceded(sp, boolean),
+                                    opt(preceded(spaced(","), preceded(sp, collation))),
+                                ),
+                            )),
+                        ),
+                    ),
+                    preceded(sp, char(')')),
+                )),
+            ),
+        ),
+        |((op, key), options)| {
+            let (descending, collation) = options.unwrap_or((false, None));
+            ScriptedOperation::Sort {
+                operation: Box::new(op),
+                key,
+                descending,
+                collation: collation.unwrap_or(Collation::Binary),
+            }
+        },
+    )(input)
+}
+
 fn parse_map<'a, Error: ParseError<&'a str> + ContextError<&'a str>>(input: &'a str) -> IResult<&'a str, ScriptedOperation, Error> {
     map(
         preceded(
@@ -364,6 +608,98 @@ fn boolean<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str, bool,
     alt((parse_true, parse_false))(input)
 }
 
+fn collation<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str, Collation, E> {
+    alt((
+        value(Collation::Binary, tag("binary")),
+        value(Collation::CaseInsensitive, tag("case_insensitive")),
+    ))(input)
+}
+
+/// The values of an index lookup: JSON values, or `*` for any value
+fn index_values<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Vec<IndexValue>, E> {
+    context(
+        "array",
+        preceded(
+            char('['),
+            cut(terminated(
+                separated_list0(
+                    preceded(sp, char(',')),
+                    alt((
+                        value(IndexValue::Any, sp_char('*')),
+                        map(json_value, IndexValue::Equals),
+                    )),
+                ),
+                preceded(sp, char(']')),
+            )),
+        ),
+    )(i)
+}
+
+/// A bound of an index range: `>=`, `>`, `<=` or `<` followed by a value, with true for lower bounds
+fn index_bound<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, (bool, Bound<Value>), E> {
+    alt((
+        map(preceded(tag(">="), json_value), |v| (true, Bound::Included(v))),
+        map(preceded(tag(">"), json_value), |v| (true, Bound::Excluded(v))),
+        map(preceded(tag("<="), json_value), |v| (false, Bound::Included(v))),
+        map(preceded(tag("<"), json_value), |v| (false, Bound::Excluded(v))),
+    ))(i)
+}
+
+/// An index range: bounds between parentheses, like `(>= 30, < 40)`, `(> "M")` or `()` for all values
+fn index_range<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, IndexRange, E> {
+    context(
+        "range",
+        map(
+            preceded(
+                sp_char('('),
+                cut(terminated(
+                    separated_list0(sp_char(','), preceded(sp, index_bound)),
+                    sp_char(')'),
+                )),
+            ),
+            |bounds| {
+                let mut range = IndexRange::all();
+                for (lower, bound) in bounds {
+                    if lower {
+                        range.lower = bound;
+                    } else {
+                        range.upper = bound;
+                    }
+                }
+                range
+            },
+        ),
+    )(i)
+}
+
+fn index_ranges<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Vec<IndexRange>, E> {
+    preceded(
+        sp_char('['),
+        cut(terminated(
+            separated_list0(sp_char(','), index_range),
+            sp_char(']'),
+        )),
+    )(i)
+}
+
+/// A memory budget in bytes
+fn memory_budget<'a, E: ParseError<&'a str> + ContextError<&'a str>>(i: &'a str) -> IResult<&'a str, usize, E> {
+    context("memory budget", map_opt(preceded(sp, digit1), |s: &str| s.parse::<usize>().ok()))(i)
+}
+
+/// A finite number
+fn number<'a, E: ParseError<&'a str> + ContextError<&'a str>>(i: &'a str) -> IResult<&'a str, Number, E> {
+    context("number", map_opt(preceded(sp, double), Number::from_f64))(i)
+}
+
 fn string<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, String, E> {
@@ -492,6 +828,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_key_prefix_scan() {
+        match parse_operation_verbose(r#"key_prefix_scan(orders, ["customer42", "2024-01-05"])"#) {
+            Ok((_, op)) => assert_eq!(
+                ScriptedOperation::KeyPrefixScan {
+                    name: String::from("orders"),
+                    prefix: vec![json!("customer42"), json!("2024-01-05")],
+                },
+                op
+            ),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_parse_multi_key_lookup() {
+        match parse_operation_verbose(r#"multi_key_lookup(accounts, ["123", "456"])"#) {
+            Ok((_, op)) => assert_eq!(
+                ScriptedOperation::MultiKeyLookup {
+                    name: String::from("accounts"),
+                    keys: vec![json!("123"), json!("456")],
+                    preserve_order: true,
+                    report_missing: false,
+                },
+                op
+            ),
+            Err(e) => panic!("{:?}", e),
+        }
+        match parse_operation_verbose(r#"multi_key_lookup("accounts",["123"], false , true)"#) {
+            Ok((_, op)) => assert_eq!(
+                ScriptedOperation::MultiKeyLookup {
+                    name: String::from("accounts"),
+                    keys: vec![json!("123")],
+                    preserve_order: false,
+                    report_missing: true,
+                },
+                op
+            ),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
     fn test_parse_key_lookup_arb(input: &str, table: &str, val: Value) {
         match parse_operation_verbose(input) {
             Ok(op) => {
@@ -645,6 +1023,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_index_lookup_any() {
+        let input = r#"index_lookup(accounts, by_country_age, [*, 43], [country, age])"#;
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::IndexLookup {
+                    name: "accounts".into(),
+                    index_name: "by_country_age".into(),
+                    values: vec![IndexValue::Any, IndexValue::Equals(json!(43.0))],
+                    keys: vec!["country".into(), "age".into()],
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+    }
+
     fn test_parse_index_lookup_arb(
         input: &str,
         table: &str,
@@ -658,7 +1053,7 @@ mod tests {
                     ScriptedOperation::IndexLookup {
                         name: table.into(),
                         index_name: idx.into(),
-                        values: val.into(),
+                        values: val.into_iter().map(IndexValue::Equals).collect(),
                         keys: ks.into_iter().map(|s| s.into()).collect()
                     },
                     op.1
@@ -671,11 +1066,11 @@ mod tests {
     #[test]
     fn test_parse_nested_loops() {
         test_parse_nested_loops_arb("nested_loops(index_lookup(\"accounts\",\"account_id\",[\"123\"],[\"name\",\"age\"]),#\"key_lookup(\"type1\", rec.key)\"#)",
-        ScriptedOperation::IndexLookup{name:"accounts".into(),index_name:"account_id".into(),values: vec![json!("123")],keys:vec!["name".into(), "age".into()]},
+        ScriptedOperation::IndexLookup{name:"accounts".into(),index_name:"account_id".into(),values: vec![IndexValue::Equals(json!("123"))],keys:vec!["name".into(), "age".into()]},
         r#"key_lookup("type1", rec.key)"#
       );
       test_parse_nested_loops_arb("nested_loops(index_lookup(accounts,account_id,[\"123\"],[\"name\",\"age\"]),  #\"key_lookup(type1, rec.key)\"#)",
-        ScriptedOperation::IndexLookup{name:"accounts".into(),index_name:"account_id".into(),values: vec![json!("123")],keys:vec!["name".into(), "age".into()]},
+        ScriptedOperation::IndexLookup{name:"accounts".into(),index_name:"account_id".into(),values: vec![IndexValue::Equals(json!("123"))],keys:vec!["name".into(), "age".into()]},
         r#"key_lookup(type1, rec.key)"#
       );
     }
@@ -706,13 +1101,29 @@ mod tests {
                         build_hash: ScriptedRecordExtract::Key,
                         probe: Box::new(ScriptedOperation::Scan{name:"products".into()}),
                         probe_hash: ScriptedRecordExtract::pointer("/category_id"),
-                        join: "probe.value[\"description\"]=build.value[\"description\"];probe".into()
+                        join: "probe.value[\"description\"]=build.value[\"description\"];probe".into(),
+                        memory_budget: DEFAULT_JOIN_MEMORY_BUDGET,
                     },
                     op.1
                 );
             }
             Err(e) => panic!("Cannot parse: {}: {}", input, e),
         }
+        let input="hash_lookup(scan(categories),key,scan(products),pointer(\"/category_id\"),\"probe\",1048576)";
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::HashJoin {
+                    build: Box::new(ScriptedOperation::Scan{name:"categories".into()}),
+                    build_hash: ScriptedRecordExtract::Key,
+                    probe: Box::new(ScriptedOperation::Scan{name:"products".into()}),
+                    probe_hash: ScriptedRecordExtract::pointer("/category_id"),
+                    join: "probe".into(),
+                    memory_budget: 1048576,
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
     }
 
     #[test]
@@ -726,14 +1137,148 @@ mod tests {
                         first_key: ScriptedRecordExtract::Key,
                         second: Box::new(ScriptedOperation::IndexLookup{name:"products".into(),index_name:"product_category_id".into(),values:vec![],keys:vec!["category_id".into()]}),
                         second_key: ScriptedRecordExtract::pointer("/category_id"),
-                        join: "let rec3=empty_record();if rec2.value!=(){rec3.key=rec2.key;rec3.value=#{description:rec1.value[\"description\"]};rec3.value.fill_with(rec2.value);};rec3".into()
+                        join: "let rec3=empty_record();if rec2.value!=(){rec3.key=rec2.key;rec3.value=#{description:rec1.value[\"description\"]};rec3.value.fill_with(rec2.value);};rec3".into(),
+                        collation: Collation::Binary,
                     },
                     op.1
                 );
             }
             Err(e) => panic!("Cannot parse: {}: {}", input, e),
         }
+        let input="merge(scan(users),pointer(\"/name\"),scan(admins),pointer(\"/name\"),\"rec1\",case_insensitive)";
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::Merge {
+                    first: Box::new(ScriptedOperation::Scan{name:"users".into()}),
+                    first_key: ScriptedRecordExtract::pointer("/name"),
+                    second: Box::new(ScriptedOperation::Scan{name:"admins".into()}),
+                    second_key: ScriptedRecordExtract::pointer("/name"),
+                    join: "rec1".into(),
+                    collation: Collation::CaseInsensitive,
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+    }
+
+    #[test]
+    fn test_parse_index_range() {
+        let input = r#"index_range(people, by_country_age, ["UK"], [(>= 30, < 40), (> "M")], true, [country, age])"#;
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::IndexRange {
+                    name: "people".into(),
+                    index_name: "by_country_age".into(),
+                    prefix: vec![json!("UK")],
+                    ranges: vec![
+                        IndexRange {
+                            lower: Bound::Included(json!(30.0)),
+                            upper: Bound::Excluded(json!(40.0)),
+                        },
+                        IndexRange::greater_than(json!("M")),
+                    ],
+                    descending: true,
+                    keys: vec!["country".into(), "age".into()],
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+        let input = "index_range(people, by_age, [], [(<= 40), ()])";
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::IndexRange {
+                    name: "people".into(),
+                    index_name: "by_age".into(),
+                    prefix: vec![],
+                    ranges: vec![IndexRange::at_most(json!(40.0)), IndexRange::all()],
+                    descending: false,
+                    keys: vec![],
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+    }
+
+    #[test]
+    fn test_parse_text_search() {
+        let input = r#"text_search(products, description, "red OR \"running shoes\"")"#;
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::TextSearch {
+                    name: "products".into(),
+                    index_name: "description".into(),
+                    query: r#"red OR "running shoes""#.into(),
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+    }
 
+    #[test]
+    fn test_parse_geo() {
+        let input = "geo_within_box(stores, location, 51.4, -0.2, 51.6, 0)";
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::GeoWithinBox {
+                    name: "stores".into(),
+                    index_name: "location".into(),
+                    min_lat: Number::from_f64(51.4).unwrap(),
+                    min_lon: Number::from_f64(-0.2).unwrap(),
+                    max_lat: Number::from_f64(51.6).unwrap(),
+                    max_lon: Number::from_f64(0.0).unwrap(),
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+        let input = "geo_within_radius(stores, location, 51.5, -0.12, 1000)";
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::GeoWithinRadius {
+                    name: "stores".into(),
+                    index_name: "location".into(),
+                    lat: Number::from_f64(51.5).unwrap(),
+                    lon: Number::from_f64(-0.12).unwrap(),
+                    radius: Number::from_f64(1000.0).unwrap(),
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+    }
+
+    #[test]
+    fn test_parse_sort() {
+        let input = "sort(scan(products),pointer(\"/name\"),true,case_insensitive)";
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::Sort {
+                    operation: Box::new(ScriptedOperation::Scan { name: "products".into() }),
+                    key: ScriptedRecordExtract::pointer("/name"),
+                    descending: true,
+                    collation: Collation::CaseInsensitive,
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
+        let input = "sort(scan(products),key)";
+        match parse_operation_verbose(input) {
+            Ok(op) => assert_eq!(
+                ScriptedOperation::Sort {
+                    operation: Box::new(ScriptedOperation::Scan { name: "products".into() }),
+                    key: ScriptedRecordExtract::Key,
+                    descending: false,
+                    collation: Collation::Binary,
+                },
+                op.1
+            ),
+            Err(e) => panic!("Cannot parse: {}: {}", input, e),
+        }
     }
 
     #[test]
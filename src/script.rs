@@ -1,8 +1,11 @@
 use std::collections::HashSet;
 
 use crate::ops::*;
+use crate::join::{HashJoinOptions, DEFAULT_JOIN_MEMORY_BUDGET};
+use crate::compare::Collation;
+use crate::index::{IndexRange, IndexValue};
 use serde::{Deserialize, Serialize};
-use serde_json::{Value};
+use serde_json::{Number, Value};
 use rhai::{Array, Dynamic, Engine, ImmutableString, Scope, serde::{from_dynamic, to_dynamic}};
 
 use anyhow::Result;
@@ -80,6 +83,16 @@ pub enum ScriptedOperation {
       name: String,
       key: Value,
   },
+  KeyPrefixScan {
+      name: String,
+      prefix: Vec<Value>,
+  },
+  MultiKeyLookup {
+      name: String,
+      keys: Vec<Value>,
+      preserve_order: bool,
+      report_missing: bool,
+  },
   Extract {
       names: HashSet<String>,
       operation: Box<ScriptedOperation>,
@@ -91,9 +104,37 @@ pub enum ScriptedOperation {
   IndexLookup {
       name: String,
       index_name: String,
-      values: Vec<Value>,
+      values: Vec<IndexValue>,
       keys: Vec<String>,
   },
+  IndexRange {
+      name: String,
+      index_name: String,
+      prefix: Vec<Value>,
+      ranges: Vec<IndexRange>,
+      descending: bool,
+      keys: Vec<String>,
+  },
+  TextSearch {
+      name: String,
+      index_name: String,
+      query: String,
+  },
+  GeoWithinBox {
+      name: String,
+      index_name: String,
+      min_lat: Number,
+      min_lon: Number,
+      max_lat: Number,
+      max_lon: Number,
+  },
+  GeoWithinRadius {
+      name: String,
+      index_name: String,
+      lat: Number,
+      lon: Number,
+      radius: Number,
+  },
   NestedLoops {
       first: Box<ScriptedOperation>,
       second: String,
@@ -104,6 +145,7 @@ pub enum ScriptedOperation {
       probe: Box<ScriptedOperation>,
       probe_hash: ScriptedRecordExtract,
       join: String,
+      memory_budget: usize,
   },
   Merge {
       first: Box<ScriptedOperation>,
@@ -111,6 +153,13 @@ pub enum ScriptedOperation {
       second: Box<ScriptedOperation>,
       second_key: ScriptedRecordExtract,
       join: String,
+      collation: Collation,
+  },
+  Sort {
+      operation: Box<ScriptedOperation>,
+      key: ScriptedRecordExtract,
+      descending: bool,
+      collation: Collation,
   },
   Map {
       operation: Box<ScriptedOperation>,
@@ -126,19 +175,45 @@ pub fn eql_engine() -> Engine {
   let mut engine = Engine::new();
   engine.register_result_fn("scan",|str: ImmutableString| to_dynamic(ScriptedOperation::Scan{name:str.into_owned()}));
   engine.register_result_fn("key_lookup",|str: ImmutableString, key: Dynamic| to_dynamic(ScriptedOperation::KeyLookup{name:str.into_owned(), key:from_dynamic::<Value>(&key)?}));
+  engine.register_result_fn("key_prefix_scan",|str: ImmutableString, prefix: Dynamic| to_dynamic(ScriptedOperation::KeyPrefixScan{name:str.into_owned(), prefix:from_dynamic(&prefix)?}));
+  engine.register_result_fn("multi_key_lookup",|str: ImmutableString, keys: Dynamic| to_dynamic(ScriptedOperation::MultiKeyLookup{name:str.into_owned(), keys:from_dynamic(&keys)?, preserve_order:true, report_missing:false}));
+  engine.register_result_fn("multi_key_lookup",|str: ImmutableString, keys: Dynamic, preserve_order: bool, report_missing: bool| to_dynamic(ScriptedOperation::MultiKeyLookup{name:str.into_owned(), keys:from_dynamic(&keys)?, preserve_order, report_missing}));
   engine.register_result_fn("extract",|names: Dynamic, op: Dynamic| to_dynamic(ScriptedOperation::Extract{names:from_dynamic(&names)?,operation:Box::new(from_dynamic(&op)?)}));
   engine.register_result_fn("augment",|value: Dynamic, op: Dynamic| to_dynamic(ScriptedOperation::Augment{value:from_dynamic(&value)?,operation:Box::new(from_dynamic(&op)?)}));
-  engine.register_result_fn("index_lookup",|name: ImmutableString, index: ImmutableString, values: Dynamic| to_dynamic(ScriptedOperation::IndexLookup{name:name.into_owned(),index_name:index.into_owned(),values:from_dynamic(&values)?,keys:vec![]}));
-  engine.register_result_fn("index_lookup",|name: ImmutableString, index: ImmutableString, values: Dynamic, keys: Dynamic| to_dynamic(ScriptedOperation::IndexLookup{name:name.into_owned(),index_name:index.into_owned(),values:from_dynamic(&values)?,keys:from_dynamic(&keys)?}));
+  engine.register_result_fn("index_lookup",|name: ImmutableString, index: ImmutableString, values: Dynamic| to_dynamic(ScriptedOperation::IndexLookup{name:name.into_owned(),index_name:index.into_owned(),values:index_values(&values)?,keys:vec![]}));
+  engine.register_result_fn("index_lookup",|name: ImmutableString, index: ImmutableString, values: Dynamic, keys: Dynamic| to_dynamic(ScriptedOperation::IndexLookup{name:name.into_owned(),index_name:index.into_owned(),values:index_values(&values)?,keys:from_dynamic(&keys)?}));
+  engine.register_result_fn("index_range",|name: ImmutableString, index: ImmutableString, prefix: Dynamic, ranges: Dynamic| to_dynamic(ScriptedOperation::IndexRange{name:name.into_owned(),index_name:index.into_owned(),prefix:from_dynamic(&prefix)?,ranges:from_dynamic(&ranges)?,descending:false,keys:vec![]}));
+  engine.register_result_fn("index_range",|name: ImmutableString, index: ImmutableString, prefix: Dynamic, ranges: Dynamic, descending: bool, keys: Dynamic| to_dynamic(ScriptedOperation::IndexRange{name:name.into_owned(),index_name:index.into_owned(),prefix:from_dynamic(&prefix)?,ranges:from_dynamic(&ranges)?,descending,keys:from_dynamic(&keys)?}));
+  engine.register_result_fn("text_search",|name: ImmutableString, index: ImmutableString, query: ImmutableString| to_dynamic(ScriptedOperation::TextSearch{name:name.into_owned(),index_name:index.into_owned(),query:query.into_owned()}));
+  engine.register_result_fn("geo_within_box",|name: ImmutableString, index: ImmutableString, min_lat: Dynamic, min_lon: Dynamic, max_lat: Dynamic, max_lon: Dynamic| to_dynamic(ScriptedOperation::GeoWithinBox{name:name.into_owned(),index_name:index.into_owned(),min_lat:from_dynamic(&min_lat)?,min_lon:from_dynamic(&min_lon)?,max_lat:from_dynamic(&max_lat)?,max_lon:from_dynamic(&max_lon)?}));
+  engine.register_result_fn("geo_within_radius",|name: ImmutableString, index: ImmutableString, lat: Dynamic, lon: Dynamic, radius: Dynamic| to_dynamic(ScriptedOperation::GeoWithinRadius{name:name.into_owned(),index_name:index.into_owned(),lat:from_dynamic(&lat)?,lon:from_dynamic(&lon)?,radius:from_dynamic(&radius)?}));
   engine.register_result_fn("nested_loops",|op: Dynamic,second: ImmutableString| to_dynamic(ScriptedOperation::NestedLoops{first:Box::new(from_dynamic(&op)?),second:second.into_owned()}));
-  engine.register_result_fn("hash_join",|build: Dynamic,build_hash: Dynamic, probe: Dynamic, probe_hash: Dynamic, join: ImmutableString| to_dynamic(ScriptedOperation::HashJoin{build:Box::new(from_dynamic(&build)?),build_hash:from_dynamic(&build_hash)?,probe:Box::new(from_dynamic(&probe)?),probe_hash:from_dynamic(&probe_hash)?,join:join.into_owned()}));
-  engine.register_result_fn("merge",|first: Dynamic,first_key: Dynamic, second: Dynamic, second_key: Dynamic, join: ImmutableString| to_dynamic(ScriptedOperation::Merge{first:Box::new(from_dynamic(&first)?),first_key:from_dynamic(&first_key)?,second:Box::new(from_dynamic(&second)?),second_key:from_dynamic(&second_key)?,join:join.into_owned()}));
+  engine.register_result_fn("hash_join",|build: Dynamic,build_hash: Dynamic, probe: Dynamic, probe_hash: Dynamic, join: ImmutableString| to_dynamic(ScriptedOperation::HashJoin{build:Box::new(from_dynamic(&build)?),build_hash:from_dynamic(&build_hash)?,probe:Box::new(from_dynamic(&probe)?),probe_hash:from_dynamic(&probe_hash)?,join:join.into_owned(),memory_budget:DEFAULT_JOIN_MEMORY_BUDGET}));
+  engine.register_result_fn("hash_join",|build: Dynamic,build_hash: Dynamic, probe: Dynamic, probe_hash: Dynamic, join: ImmutableString, memory_budget: Dynamic| to_dynamic(ScriptedOperation::HashJoin{build:Box::new(from_dynamic(&build)?),build_hash:from_dynamic(&build_hash)?,probe:Box::new(from_dynamic(&probe)?),probe_hash:from_dynamic(&probe_hash)?,join:join.into_owned(),memory_budget:from_dynamic(&memory_budget)?}));
+  engine.register_result_fn("merge",|first: Dynamic,first_key: Dynamic, second: Dynamic, second_key: Dynamic, join: ImmutableString| to_dynamic(ScriptedOperation::Merge{first:Box::new(from_dynamic(&first)?),first_key:from_dynamic(&first_key)?,second:Box::new(from_dynamic(&second)?),second_key:from_dynamic(&second_key)?,join:join.into_owned(),collation:Collation::Binary}));
+  engine.register_result_fn("merge",|first: Dynamic,first_key: Dynamic, second: Dynamic, second_key: Dynamic, join: ImmutableString, collation: Dynamic| to_dynamic(ScriptedOperation::Merge{first:Box::new(from_dynamic(&first)?),first_key:from_dynamic(&first_key)?,second:Box::new(from_dynamic(&second)?),second_key:from_dynamic(&second_key)?,join:join.into_owned(),collation:from_dynamic(&collation)?}));
+  engine.register_result_fn("sort",|op: Dynamic, key: Dynamic| to_dynamic(ScriptedOperation::Sort{operation:Box::new(from_dynamic(&op)?),key:from_dynamic(&key)?,descending:false,collation:Collation::Binary}));
+  engine.register_result_fn("sort",|op: Dynamic, key: Dynamic, descending: bool| to_dynamic(ScriptedOperation::Sort{operation:Box::new(from_dynamic(&op)?),key:from_dynamic(&key)?,descending,collation:Collation::Binary}));
+  engine.register_result_fn("sort",|op: Dynamic, key: Dynamic, descending: bool, collation: Dynamic| to_dynamic(ScriptedOperation::Sort{operation:Box::new(from_dynamic(&op)?),key:from_dynamic(&key)?,descending,collation:from_dynamic(&collation)?}));
   engine.register_result_fn("map",|op: Dynamic,process: ImmutableString| to_dynamic(ScriptedOperation::Map{operation:Box::new(from_dynamic(&op)?),process:process.into_owned()}));
   engine.register_result_fn("reduce",|op: Dynamic,process: ImmutableString| to_dynamic(ScriptedOperation::Reduce{operation:Box::new(from_dynamic(&op)?),process:process.into_owned()}));
   engine.register_result_fn("empty_record", || to_dynamic(EQLRecord::empty()));
+  // case conversions, for instance to index values ignoring case
+  engine.register_fn("to_lower", |s: ImmutableString| s.to_lowercase());
+  engine.register_fn("to_upper", |s: ImmutableString| s.to_uppercase());
   engine
 }
 
+/// convert the values of an index lookup, which match exactly in Rhai scripts
+fn index_values(values: &Dynamic) -> Result<Vec<IndexValue>, Box<rhai::EvalAltResult>> {
+  Ok(from_dynamic::<Vec<Value>>(values)?.into_iter().map(IndexValue::Equals).collect())
+}
+
+/// convert a JSON number to a float, which it always fits in without the arbitrary precision feature
+fn as_f64(n: &Number) -> f64 {
+  n.as_f64().unwrap_or_default()
+}
+
 /// convert a value to a Dynamic, returning an anhow error
 fn eql_to_dynamic<T>(value: T) -> Result<Dynamic>
 where
@@ -153,9 +228,15 @@ impl ScriptedOperation {
       match self {
           ScriptedOperation::Scan{name}=>Ok(Operation::Scan{name}),
           ScriptedOperation::KeyLookup{name, key}=>Ok(Operation::KeyLookup{name,key}),
+          ScriptedOperation::KeyPrefixScan{name, prefix}=>Ok(Operation::KeyPrefixScan{name,prefix}),
+          ScriptedOperation::MultiKeyLookup{name, keys, preserve_order, report_missing}=>Ok(Operation::MultiKeyLookup{name,keys,preserve_order,report_missing}),
           ScriptedOperation::Extract{names,operation}=>operation.into_rust(engine).map(|op| Operation::Extract{names,operation:Box::new(op)}),
           ScriptedOperation::Augment{value,operation}=>operation.into_rust(engine).map(|op| Operation::Augment{value,operation:Box::new(op)}),
           ScriptedOperation::IndexLookup{name,index_name, values, keys}=>Ok(Operation::IndexLookup{name,index_name,values,keys}),
+          ScriptedOperation::IndexRange{name,index_name,prefix,ranges,descending,keys}=>Ok(Operation::IndexRange{name,index_name,prefix,ranges,descending,keys}),
+          ScriptedOperation::TextSearch{name,index_name,query}=>Ok(Operation::TextSearch{name,index_name,query}),
+          ScriptedOperation::GeoWithinBox{name,index_name,min_lat,min_lon,max_lat,max_lon}=>Ok(Operation::GeoWithinBox{name,index_name,min_lat:as_f64(&min_lat),min_lon:as_f64(&min_lon),max_lat:as_f64(&max_lat),max_lon:as_f64(&max_lon)}),
+          ScriptedOperation::GeoWithinRadius{name,index_name,lat,lon,radius}=>Ok(Operation::GeoWithinRadius{name,index_name,lat:as_f64(&lat),lon:as_f64(&lon),radius:as_f64(&radius)}),
           ScriptedOperation::NestedLoops{first,second}=>first.into_rust(engine).and_then(|op| {
             let ast = engine.compile(&second)?;
             Ok(Operation::NestedLoops{first:Box::new(op),second:Box::new(move |rec|{
@@ -172,14 +253,14 @@ impl ScriptedOperation {
                             
             })})
           }),
-          ScriptedOperation::HashJoin{build,build_hash,probe,probe_hash,join}=>{
+          ScriptedOperation::HashJoin{build,build_hash,probe,probe_hash,join,memory_budget}=>{
             let op1=build.into_rust(engine)?;
             let s1=build_hash.into_rust()?;
             let op2=probe.into_rust(engine)?;
             let s2=probe_hash.into_rust()?;
             let ast = engine.compile(&join)?;
 
-            Ok(Operation::HashJoin{build:Box::new(op1),build_hash:s1,probe:Box::new(op2),probe_hash:s2,join:Box::new(move |(rec1,rec2)|{
+            Ok(Operation::HashJoin{build:Box::new(op1),build_hash:s1,probe:Box::new(op2),probe_hash:s2,options:HashJoinOptions{memory_budget,..Default::default()},join:Box::new(move |(rec1,rec2)|{
               let mut scope = Scope::new();
               let e=EQLRecord::empty();
               scope.push_constant_dynamic("build", eql_to_dynamic(rec1.unwrap_or(&e))?);
@@ -192,14 +273,14 @@ impl ScriptedOperation {
             })})
             }
             ,
-          ScriptedOperation::Merge{first, first_key,second,second_key,join}=>{
+          ScriptedOperation::Merge{first, first_key,second,second_key,join,collation}=>{
             let op1=first.into_rust(engine)?;
             let k1=first_key.into_rust()?;
             let op2=second.into_rust(engine)?;
             let k2=second_key.into_rust()?;
             let ast = engine.compile(&join)?;
 
-            Ok(Operation::Merge{first:Box::new(op1),first_key:k1,second:Box::new(op2),second_key:k2,join:Box::new(move |(rec1,rec2)|{
+            Ok(Operation::Merge{first:Box::new(op1),first_key:k1,second:Box::new(op2),second_key:k2,compare:collation.comparator(),join:Box::new(move |(rec1,rec2)|{
               let mut scope = Scope::new();
               let e=EQLRecord::empty();
               scope.push_constant_dynamic("rec1", eql_to_dynamic(rec1.unwrap_or(&e))?);
@@ -211,6 +292,10 @@ impl ScriptedOperation {
               }
             })})
           },
+          ScriptedOperation::Sort{operation,key,descending,collation}=>{
+            let op1=operation.into_rust(engine)?;
+            Ok(Operation::Sort{operation:Box::new(op1),key:key.into_rust()?,descending,compare:collation.comparator()})
+          },
           ScriptedOperation::Map{operation,process}=>{
             let op1=operation.into_rust(engine)?;
             let ast = engine.compile(&process)?;
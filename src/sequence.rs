@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::TryInto;
+use thiserror::Error;
+
+/// The column family holding the sequence counters, keyed by record type
+pub(crate) const SEQUENCE_CF: &str = "#seq";
+
+/// Sequence errors
+#[derive(Error, Debug)]
+pub enum SequenceError {
+    /// The stored counter is not an 8 bytes big endian number
+    #[error("invalid sequence counter for record type {rec_type}: {length} bytes")]
+    InvalidCounter { rec_type: String, length: usize },
+}
+
+/// How generated keys are built from the sequence number of a record type
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum KeyGenerator {
+    /// The key is the sequence number itself
+    #[default]
+    Number,
+    /// The key is a string made of a prefix and the sequence number, zero padded to `width` digits so keys sort in sequence order
+    Prefixed { prefix: String, width: usize },
+    /// The key is an array made of fixed components followed by the sequence number
+    Composite { components: Vec<Value> },
+}
+
+impl KeyGenerator {
+    /// Builds the key for a sequence number
+    /// # Arguments
+    /// * `n` - the sequence number
+    pub fn key(&self, n: u64) -> Value {
+        match self {
+            KeyGenerator::Number => Value::from(n),
+            KeyGenerator::Prefixed { prefix, width } => {
+                Value::from(format!("{}{:0width$}", prefix, n, width = width))
+            }
+            KeyGenerator::Composite { components } => {
+                let mut v = components.clone();
+                v.push(Value::from(n));
+                Value::Array(v)
+            }
+        }
+    }
+}
+
+/// Decodes a stored sequence counter
+/// # Arguments
+/// * `rec_type` - the record type the counter belongs to
+/// * `bytes` - the stored counter
+pub(crate) fn decode_counter(rec_type: &str, bytes: &[u8]) -> Result<u64, SequenceError> {
+    bytes
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| SequenceError::InvalidCounter {
+            rec_type: String::from(rec_type),
+            length: bytes.len(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(json!(12), KeyGenerator::Number.key(12));
+        assert_eq!(
+            json!("INV-000012"),
+            KeyGenerator::Prefixed {
+                prefix: String::from("INV-"),
+                width: 6
+            }
+            .key(12)
+        );
+        assert_eq!(
+            json!(["eu", 2021, 12]),
+            KeyGenerator::Composite {
+                components: vec![json!("eu"), json!(2021)]
+            }
+            .key(12)
+        );
+    }
+
+    #[test]
+    fn test_decode_counter() {
+        assert_eq!(12, decode_counter("type1", &12u64.to_be_bytes()).unwrap());
+        assert_eq!(true, decode_counter("type1", &[0, 12]).is_err());
+        assert_eq!(true, decode_counter("type1", &[0; 9]).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::index::EncodedRange;
+use crate::storage::Location;
+use rocksdb::DB;
+
+/// An index entry: its key without the location prefix, and its value
+type Entry = (Box<[u8]>, Box<[u8]>);
+
+/// Reads the index entries starting with given components and whose next components are in ranges
+/// When a component is out of its range, the scan seeks to the next key that can match instead of reading the
+/// entries in between, so a constraint after a component with few distinct values reads few more entries than
+/// the ones returned
+pub(crate) struct SkipScan<'a> {
+    db: &'a DB,
+    loc: Location<'a>,
+    /// The number of components in the prefix
+    skip: usize,
+    /// The ranges of the components after the prefix
+    ranges: Vec<EncodedRange>,
+    reverse: bool,
+    /// The first key left to read
+    lower: Vec<u8>,
+    /// The first key after the keys left to read, none for the end of the index
+    upper: Option<Vec<u8>>,
+    entries: Box<dyn Iterator<Item = Entry> + 'a>,
+}
+
+impl<'a> SkipScan<'a> {
+    /// Starts a skip scan
+    /// # Arguments
+    /// * `db` - the database
+    /// * `loc` - the location of the index
+    /// * `prefix` - the first encoded components, each followed by 0
+    /// * `skip` - the number of components in the prefix
+    /// * `ranges` - the ranges of the next components
+    /// * `reverse` - true to read the entries in descending order
+    pub(crate) fn new(
+        db: &'a DB,
+        loc: Location<'a>,
+        prefix: &[u8],
+        skip: usize,
+        ranges: Vec<EncodedRange>,
+        reverse: bool,
+    ) -> Self {
+        let (lower, upper) = match ranges.first() {
+            Some(r) => r.keys(prefix),
+            None => EncodedRange::all().keys(prefix),
+        };
+        let entries = Box::new(loc.range(db, &lower, upper.as_deref(), reverse));
+        SkipScan {
+            db,
+            loc,
+            skip,
+            ranges,
+            reverse,
+            lower,
+            upper,
+            entries,
+        }
+    }
+
+    /// Returns the position of the first component out of its range in the ranges, with the length of the key
+    /// up to this component
+    fn mismatch(&self, k: &[u8]) -> Option<(usize, usize)> {
+        let mut start = 0;
+        for (i, part) in k.split(|b| *b == 0).enumerate() {
+            if i >= self.skip {
+                let r = self.ranges.get(i - self.skip)?;
+                if !r.contains(part) {
+                    return Some((i - self.skip, start));
+                }
+            }
+            start += part.len() + 1;
+        }
+        None
+    }
+
+    /// Reads the keys between new bounds, returns false if there is nothing left to read
+    fn seek(&mut self, lower: Vec<u8>, upper: Option<Vec<u8>>) -> bool {
+        if upper.as_ref().map(|u| lower >= *u).unwrap_or(false) {
+            return false;
+        }
+        self.entries = Box::new(self.loc.range(self.db, &lower, upper.as_deref(), self.reverse));
+        self.lower = lower;
+        self.upper = upper;
+        true
+    }
+}
+
+impl<'a> Iterator for SkipScan<'a> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let (k, v) = self.entries.next()?;
+            let (i, start) = match self.mismatch(&k) {
+                None => return Some((k, v)),
+                Some(m) => m,
+            };
+            // the components before the mismatch, followed by 0
+            let p = &k[..start];
+            let part = k[start..].split(|b| *b == 0).next().unwrap_or_default();
+            let (range_lower, range_upper) = self.ranges[i].keys(p);
+            // the first key after all the keys starting with p
+            let after = |p: &[u8]| {
+                let mut a = p.to_vec();
+                a.pop();
+                a.push(1);
+                a
+            };
+            let ok = match (self.reverse, self.ranges[i].is_after(part)) {
+                (false, true) => self.seek(range_lower, self.upper.clone()),
+                (false, false) if !p.is_empty() => self.seek(after(p), self.upper.clone()),
+                (true, false) => self.seek(self.lower.clone(), range_upper),
+                (true, true) if !p.is_empty() => self.seek(self.lower.clone(), Some(p.to_vec())),
+                _ => false,
+            };
+            if !ok {
+                return None;
+            }
+        }
+    }
+}
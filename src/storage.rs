@@ -0,0 +1,142 @@
+use rocksdb::{ColumnFamily, Direction, IteratorMode, ReadOptions, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+
+/// The column family holding the records and index entries of all shared record types
+pub(crate) const SHARED_CF: &str = "#shared";
+
+/// How the records of a record type are stored, chosen when the record type is created
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Storage {
+    /// The record type and each of its indices get their own column family
+    #[default]
+    Dedicated,
+    /// The record type and its indices live in a column family shared with other record types,
+    /// under a prefix, which avoids the overhead of many column families for small record types
+    Shared,
+}
+
+/// Where the records of a record type or the entries of an index are stored: a column family
+/// and a key prefix, which is empty unless the column family is shared
+pub(crate) struct Location<'a> {
+    pub(crate) cf: &'a ColumnFamily,
+    prefix: Vec<u8>,
+}
+
+impl<'a> Location<'a> {
+    /// A location owning the whole column family
+    pub(crate) fn dedicated(cf: &'a ColumnFamily) -> Self {
+        Location { cf, prefix: vec![] }
+    }
+
+    /// A location in the shared column family
+    /// # Arguments
+    /// * `cf` - the shared column family
+    /// * `name` - the unique name of the record type or index, which cannot contain a 0 byte
+    pub(crate) fn shared(cf: &'a ColumnFamily, name: &str) -> Self {
+        let mut prefix = name.as_bytes().to_vec();
+        prefix.push(0);
+        Location { cf, prefix }
+    }
+
+    /// Returns the stored key for a key
+    pub(crate) fn key<K: AsRef<[u8]>>(&self, key: K) -> Vec<u8> {
+        let mut v = self.prefix.clone();
+        v.extend_from_slice(key.as_ref());
+        v
+    }
+
+    /// Iterates over the entries of this location from a key, returning keys without the location prefix
+    /// # Arguments
+    /// * `db` - the database
+    /// * `from` - the key to start from
+    /// * `opts` - the read options, any upper bound must be a key of this location
+    pub(crate) fn iter(
+        &self,
+        db: &'a DB,
+        from: &[u8],
+        opts: ReadOptions,
+    ) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a {
+        let prefix = self.prefix.clone();
+        let l = prefix.len();
+        let start = self.key(from);
+        db.iterator_cf_opt(self.cf, opts, IteratorMode::From(&start, Direction::Forward))
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(move |(k, v)| {
+                if l == 0 {
+                    (k, v)
+                } else {
+                    (k[l..].into(), v)
+                }
+            })
+    }
+
+    /// Iterates over the entries of this location between two keys, returning keys without the location prefix
+    /// # Arguments
+    /// * `db` - the database
+    /// * `lower` - the first key included
+    /// * `upper` - the first key excluded, none to read up to the end of the location
+    /// * `reverse` - true to iterate from the last key down to the first
+    pub(crate) fn range(
+        &self,
+        db: &'a DB,
+        lower: &[u8],
+        upper: Option<&[u8]>,
+        reverse: bool,
+    ) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a {
+        let prefix = self.prefix.clone();
+        let l = prefix.len();
+        let mut opts = ReadOptions::default();
+        opts.set_iterate_lower_bound(self.key(lower));
+        let end = match upper {
+            Some(u) => Some(self.key(u)),
+            None if self.is_shared() => Some(self.prefix_end()),
+            None => None,
+        };
+        if let Some(end) = &end {
+            opts.set_iterate_upper_bound(end.clone());
+        }
+        let start = self.key(lower);
+        let mode = if reverse {
+            IteratorMode::End
+        } else {
+            IteratorMode::From(&start, Direction::Forward)
+        };
+        db.iterator_cf_opt(self.cf, opts, mode)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(move |(k, v)| {
+                if l == 0 {
+                    (k, v)
+                } else {
+                    (k[l..].into(), v)
+                }
+            })
+    }
+
+    /// Returns true if the location is a part of the shared column family
+    pub(crate) fn is_shared(&self) -> bool {
+        !self.prefix.is_empty()
+    }
+
+    /// The first key after all the keys of a location in the shared column family
+    fn prefix_end(&self) -> Vec<u8> {
+        let mut end = self.prefix.clone();
+        if let Some(l) = end.last_mut() {
+            *l += 1;
+        }
+        end
+    }
+
+    /// Deletes all the entries of a location in the shared column family
+    pub(crate) fn delete_all(&self, batch: &mut WriteBatch) {
+        batch.delete_range_cf(self.cf, &self.prefix, &self.prefix_end());
+    }
+
+    /// Compacts the entries of the location
+    pub(crate) fn compact(&self, db: &DB) {
+        if self.is_shared() {
+            db.compact_range_cf(self.cf, Some(&self.prefix), Some(&self.prefix_end()));
+        } else {
+            db.compact_range_cf(self.cf, None::<&[u8]>, None::<&[u8]>);
+        }
+    }
+}
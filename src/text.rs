@@ -0,0 +1,398 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{take_while, take_while1},
+    character::complete::char,
+    combinator::{all_consuming, cut, map, opt, verify},
+    multi::{many1, separated_list1},
+    sequence::{delimited, preceded, terminated},
+    IResult,
+};
+use rust_stemmers::Stemmer;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use thiserror::Error;
+
+/// The languages of the stemmers
+pub use rust_stemmers::Algorithm as Language;
+
+/// The English words left out of `TextOptions::english`
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it", "no", "not", "of",
+    "on", "or", "such", "that", "the", "their", "then", "there", "these", "they", "this", "to", "was", "will", "with",
+];
+
+/// The gap between the positions of the words of two indexed strings, so that phrases do not span them
+const POSITION_GAP: usize = 1;
+
+/// The BM25 term frequency saturation: the more a term appears in a record, the less each occurrence adds
+const BM25_K1: f64 = 1.2;
+
+/// Errors on full-text queries
+#[derive(Error, Debug)]
+pub enum TextError {
+    /// The query does not follow the syntax of full-text queries
+    #[error("cannot parse full-text query {query}")]
+    Syntax { query: String },
+    /// A full-text index cannot be unique nor include values, as its entries hold the positions of terms
+    #[error("full-text index {index_name} on record type {rec_type} cannot be unique or include values")]
+    InvalidIndex { rec_type: String, index_name: String },
+    /// The index is not a full-text index
+    #[error("index {index_name} on record type {rec_type} is not a full-text index")]
+    NotTextIndex { rec_type: String, index_name: String },
+}
+
+/// How a full-text index splits strings into the terms it indexes
+/// Strings are split into words on the characters that are not alphanumeric, then each word is optionally
+/// lowercased, left out if it is a stop word, and reduced to its stem. Queries go through the same steps
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TextOptions {
+    /// Lowercase the words, so that searches ignore case
+    #[serde(default)]
+    pub lowercase: bool,
+    /// The language of the stemmer reducing words to their stem, so that "shoes" finds "shoe"
+    #[serde(default)]
+    pub stemmer: Option<Language>,
+    /// The words not indexed, compared after lowercasing. They still take a position, so phrases keep their gaps
+    #[serde(default)]
+    pub stop_words: BTreeSet<String>,
+}
+
+impl Default for TextOptions {
+    fn default() -> Self {
+        TextOptions {
+            lowercase: true,
+            stemmer: None,
+            stop_words: BTreeSet::new(),
+        }
+    }
+}
+
+impl TextOptions {
+    /// Options for English text: lowercase words, English stemming and common English stop words
+    pub fn english() -> Self {
+        TextOptions {
+            lowercase: true,
+            stemmer: Some(Language::English),
+            stop_words: ENGLISH_STOP_WORDS.iter().map(|w| String::from(*w)).collect(),
+        }
+    }
+
+    /// Splits a string into terms, with their position in the string
+    /// # Arguments
+    /// * `text` - the string
+    pub fn terms(&self, text: &str) -> Vec<(usize, String)> {
+        let stemmer = self.stemmer.map(Stemmer::create);
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .enumerate()
+            .filter_map(|(pos, w)| {
+                let w = if self.lowercase {
+                    w.to_lowercase()
+                } else {
+                    String::from(w)
+                };
+                if self.stop_words.contains(&w) {
+                    return None;
+                }
+                Some(match &stemmer {
+                    Some(s) => (pos, s.stem(&w).into_owned()),
+                    None => (pos, w),
+                })
+            })
+            .collect()
+    }
+
+    /// Builds the postings of a record: the positions of each term in the indexed strings, which follow each other
+    /// # Arguments
+    /// * `texts` - the indexed strings of the record
+    pub(crate) fn postings(&self, texts: &[&str]) -> BTreeMap<String, Vec<usize>> {
+        let mut postings: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        let mut start = 0;
+        for text in texts {
+            let words = text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).count();
+            for (pos, term) in self.terms(text) {
+                postings.entry(term).or_default().push(start + pos);
+            }
+            start += words + POSITION_GAP;
+        }
+        postings
+    }
+}
+
+/// A full-text query
+/// In the query syntax, words and quoted phrases separated by spaces must all match, `OR` separates alternatives
+/// and binds less than `AND`, which is implied, and parentheses group queries: `(red OR blue) "running shoes"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextQuery {
+    /// A word
+    Word(String),
+    /// Words that must follow each other
+    Phrase(String),
+    /// All the queries must match
+    And(Vec<TextQuery>),
+    /// One of the queries at least must match
+    Or(Vec<TextQuery>),
+}
+
+impl TextQuery {
+    /// Parses a query
+    /// # Arguments
+    /// * `query` - the query, in the query syntax
+    pub fn parse(query: &str) -> Result<TextQuery, TextError> {
+        match all_consuming(terminated(or_query, sp))(query) {
+            Ok((_, q)) => Ok(q),
+            Err(_) => Err(TextError::Syntax {
+                query: String::from(query),
+            }),
+        }
+    }
+
+    /// Turns the words and phrases into terms with the options of an index, leaving out the parts without terms
+    pub(crate) fn analyze(&self, options: &TextOptions) -> Option<Matcher> {
+        let phrase = |text: &str| {
+            let terms = options.terms(text);
+            if terms.is_empty() {
+                None
+            } else {
+                Some(Matcher::Phrase(terms))
+            }
+        };
+        match self {
+            TextQuery::Word(w) => phrase(w),
+            TextQuery::Phrase(p) => phrase(p),
+            TextQuery::And(qs) => Matcher::group(qs.iter().filter_map(|q| q.analyze(options)).collect(), Matcher::And),
+            TextQuery::Or(qs) => Matcher::group(qs.iter().filter_map(|q| q.analyze(options)).collect(), Matcher::Or),
+        }
+    }
+}
+
+/// The positions of a term in the records containing it, by record key
+pub(crate) type Postings = HashMap<Vec<u8>, Vec<usize>>;
+
+/// A query made of terms
+pub(crate) enum Matcher {
+    /// Terms with their relative positions, a single term matching wherever it appears
+    Phrase(Vec<(usize, String)>),
+    And(Vec<Matcher>),
+    Or(Vec<Matcher>),
+}
+
+impl Matcher {
+    /// Builds a group of matchers, none if empty and the matcher itself if alone
+    fn group(mut ms: Vec<Matcher>, f: fn(Vec<Matcher>) -> Matcher) -> Option<Matcher> {
+        match ms.len() {
+            0 => None,
+            1 => ms.pop(),
+            _ => Some(f(ms)),
+        }
+    }
+
+    /// The distinct terms of the query
+    pub(crate) fn terms(&self) -> BTreeSet<&str> {
+        match self {
+            Matcher::Phrase(ts) => ts.iter().map(|(_, t)| t.as_str()).collect(),
+            Matcher::And(ms) | Matcher::Or(ms) => ms.iter().flat_map(|m| m.terms()).collect(),
+        }
+    }
+
+    /// Scores the matching records, by record key
+    /// A phrase scores like a term with BM25, without length normalization, the rarer its terms the higher:
+    /// `idf * f * (k1 + 1) / (f + k1)` where `f` is the number of times it appears in the record and `idf` sums
+    /// `ln(1 + (n - df + 0.5) / (df + 0.5))` over its terms, `df` being the number of records containing a term
+    /// Alternatives and conjunctions add the scores of their matching parts
+    /// # Arguments
+    /// * `postings` - the postings of each term
+    /// * `records` - the number of records `n`, at least the number of records containing any term
+    pub(crate) fn scores(&self, postings: &HashMap<&str, Postings>, records: usize) -> HashMap<Vec<u8>, f64> {
+        match self {
+            Matcher::Phrase(terms) => {
+                let lists: Vec<&Postings> = terms.iter().filter_map(|(_, t)| postings.get(t.as_str())).collect();
+                if lists.len() < terms.len() {
+                    return HashMap::new();
+                }
+                let idf: f64 = lists
+                    .iter()
+                    .map(|l| {
+                        let df = l.len() as f64;
+                        (1.0 + (records.max(l.len()) as f64 - df + 0.5) / (df + 0.5)).ln()
+                    })
+                    .sum();
+                let (first_pos, _) = terms[0];
+                lists[0]
+                    .iter()
+                    .filter_map(|(kv, starts)| {
+                        let f = starts
+                            .iter()
+                            .filter(|start| {
+                                terms.iter().zip(lists.iter()).skip(1).all(|((pos, _), l)| {
+                                    l.get(kv)
+                                        .map(|ps| ps.contains(&(*start + pos - first_pos)))
+                                        .unwrap_or_default()
+                                })
+                            })
+                            .count() as f64;
+                        if f > 0.0 {
+                            Some((kv.clone(), idf * f * (BM25_K1 + 1.0) / (f + BM25_K1)))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            }
+            Matcher::And(ms) => {
+                let mut it = ms.iter().map(|m| m.scores(postings, records));
+                let mut scores = it.next().unwrap_or_default();
+                for s in it {
+                    scores = scores
+                        .into_iter()
+                        .filter_map(|(kv, score)| s.get(&kv).map(|s2| (kv, score + s2)))
+                        .collect();
+                }
+                scores
+            }
+            Matcher::Or(ms) => {
+                let mut scores: HashMap<Vec<u8>, f64> = HashMap::new();
+                for s in ms.iter().map(|m| m.scores(postings, records)) {
+                    for (kv, score) in s {
+                        *scores.entry(kv).or_default() += score;
+                    }
+                }
+                scores
+            }
+        }
+    }
+}
+
+fn sp(i: &str) -> IResult<&str, &str> {
+    take_while(char::is_whitespace)(i)
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && c != '"' && c != '(' && c != ')'
+}
+
+/// An operator, a word in capitals
+fn operator<'a>(op: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    verify(preceded(sp, take_while1(is_word_char)), move |w: &str| w == op)
+}
+
+/// A word, which is not an operator
+fn word(i: &str) -> IResult<&str, TextQuery> {
+    map(
+        verify(preceded(sp, take_while1(is_word_char)), |w: &str| w != "AND" && w != "OR"),
+        |w: &str| TextQuery::Word(String::from(w)),
+    )(i)
+}
+
+fn phrase(i: &str) -> IResult<&str, TextQuery> {
+    map(
+        preceded(sp, delimited(char('"'), take_while(|c| c != '"'), cut(char('"')))),
+        |p: &str| TextQuery::Phrase(String::from(p)),
+    )(i)
+}
+
+fn group(i: &str) -> IResult<&str, TextQuery> {
+    preceded(sp, delimited(char('('), or_query, cut(preceded(sp, char(')')))))(i)
+}
+
+fn and_query(i: &str) -> IResult<&str, TextQuery> {
+    map(
+        many1(preceded(opt(operator("AND")), alt((phrase, group, word)))),
+        |mut qs| if qs.len() == 1 { qs.remove(0) } else { TextQuery::And(qs) },
+    )(i)
+}
+
+fn or_query(i: &str) -> IResult<&str, TextQuery> {
+    map(
+        separated_list1(operator("OR"), and_query),
+        |mut qs| if qs.len() == 1 { qs.remove(0) } else { TextQuery::Or(qs) },
+    )(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(w: &str) -> TextQuery {
+        TextQuery::Word(String::from(w))
+    }
+
+    #[test]
+    fn test_terms() {
+        let english = TextOptions::english();
+        assert_eq!(
+            vec![(1, String::from("quick")), (2, String::from("brown")), (3, String::from("fox")), (4, String::from("jump"))],
+            english.terms("The quick-brown fox JUMPS!")
+        );
+        let plain = TextOptions {
+            lowercase: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![(0, String::from("The")), (1, String::from("foxes"))],
+            plain.terms("The foxes")
+        );
+        let postings = english.postings(&["red shoes", "the shoe"]);
+        assert_eq!(Some(&vec![1, 4]), postings.get("shoe"));
+        assert_eq!(Some(&vec![0]), postings.get("red"));
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(word("red"), TextQuery::parse(" red ").unwrap());
+        assert_eq!(
+            TextQuery::And(vec![word("red"), word("shoes"), word("size")]),
+            TextQuery::parse("red shoes AND size").unwrap()
+        );
+        assert_eq!(
+            TextQuery::Or(vec![
+                TextQuery::And(vec![word("red"), word("shoes")]),
+                TextQuery::Phrase(String::from("blue suede shoes")),
+            ]),
+            TextQuery::parse(r#"red shoes OR "blue suede shoes""#).unwrap()
+        );
+        assert_eq!(
+            TextQuery::And(vec![TextQuery::Or(vec![word("red"), word("blue")]), word("shoes")]),
+            TextQuery::parse("(red OR blue) shoes").unwrap()
+        );
+        assert_eq!(
+            TextQuery::And(vec![word("ANDROID"), word("ORANGE")]),
+            TextQuery::parse("ANDROID ORANGE").unwrap()
+        );
+        assert_eq!(true, TextQuery::parse("red OR").is_err());
+        assert_eq!(true, TextQuery::parse("(red").is_err());
+        assert_eq!(true, TextQuery::parse(r#""red"#).is_err());
+        assert_eq!(true, TextQuery::parse("").is_err());
+    }
+
+    #[test]
+    fn test_scores() {
+        let options = TextOptions::english();
+        let docs = [
+            (vec![1u8], "red shoes"),
+            (vec![2u8], "blue shoes and red socks"),
+            (vec![3u8], "red red red hat"),
+        ];
+        let mut postings: HashMap<&str, Postings> = HashMap::new();
+        let all: Vec<_> = docs.iter().map(|(kv, t)| (kv.clone(), options.postings(&[t]))).collect();
+        let query = TextQuery::parse(r#""red shoes" OR hat"#).unwrap().analyze(&options).unwrap();
+        for term in query.terms() {
+            let mut p = Postings::new();
+            for (kv, ps) in all.iter() {
+                if let Some(positions) = ps.get(term) {
+                    p.insert(kv.clone(), positions.clone());
+                }
+            }
+            postings.insert(term, p);
+        }
+        let scores = query.scores(&postings, docs.len());
+        assert_eq!(2, scores.len());
+        assert_eq!(true, scores[&vec![1u8]] > 0.0);
+        assert_eq!(true, scores[&vec![3u8]] > 0.0);
+        let and = TextQuery::parse("red shoes").unwrap().analyze(&options).unwrap();
+        let scores = and.scores(&postings, docs.len());
+        assert_eq!(2, scores.len());
+        assert_eq!(true, scores[&vec![1u8]] == scores[&vec![2u8]]);
+        assert_eq!(true, TextQuery::parse("the").unwrap().analyze(&options).is_none());
+    }
+}
@@ -1,9 +1,11 @@
 use std::iter;
+use std::ops::Bound;
 
 use anyhow::Result;
 use kv_eql::{
-    augment, extract, hash_join, index_lookup, index_lookup_keys, key_lookup, merge,
-    nested_loops, process, scan, EQLBatch, EQLRecord, RecordExtract, EQLDB,
+    augment, extract, geo_within_box, geo_within_radius, hash_join, hash_join_with, hash_join_with_budget, index_lookup, index_lookup_keys, index_lookup_with, index_range, index_range_with, key_lookup, key_prefix_scan, merge, merge_with,
+    multi_key_lookup, multi_key_lookup_with, nested_loops, process, scan, sort, sort_with, text_search, compare_case_insensitive, BatchCounts, BulkLoadError, BulkLoadOptions, ConflictError, DEFAULT_JOIN_MEMORY_BUDGET, EQLBatch, HashJoinOptions, IndexError, IndexFilter, IndexOptions, IndexRange, IndexValue, QueryError, Encryption, EncryptionError, Expected, GeoError, IndexProtection, KeyGenerator, MemoryKeyProvider, Storage, EQLRecord, Operation, RecordExtract, TextError, TextOptions, ValueFormat,
+    EQLDB,
 };
 use serde_json::json;
 use serde_json::Value;
@@ -162,6 +164,47 @@ fn test_lookup() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_multi_key_lookup() -> Result<()> {
+    let path = "test_multi_key_lookup.db";
+    {
+        let mut meta = EQLDB::open(path)?;
+        for i in 1..=5 {
+            meta.insert("type1", format!("key{}", i), &json!({ "value": i }))?;
+        }
+        let keys = vec![json!("key4"), json!("key9"), json!("key2"), json!("key4")];
+
+        let v1: Vec<EQLRecord> = meta.execute(multi_key_lookup("type1", keys.clone()))?.collect();
+        assert_eq!(3, v1.len());
+        assert_eq!(json!("key4"), v1[0].key);
+        assert_eq!(json!({"value": 4}), v1[0].value);
+        assert_eq!(json!("key2"), v1[1].key);
+        assert_eq!(json!("key4"), v1[2].key);
+
+        let v1: Vec<EQLRecord> = meta
+            .execute(multi_key_lookup_with("type1", keys.clone(), true, true))?
+            .collect();
+        assert_eq!(4, v1.len());
+        assert_eq!(json!("key9"), v1[1].key);
+        assert_eq!(Value::Null, v1[1].value);
+
+        let v1: Vec<EQLRecord> = meta
+            .execute(multi_key_lookup_with("type1", keys, false, false))?
+            .collect();
+        assert_eq!(2, v1.len());
+        assert_eq!(json!("key2"), v1[0].key);
+        assert_eq!(json!("key4"), v1[1].key);
+
+        let v1: Vec<EQLRecord> = meta
+            .execute(multi_key_lookup_with("type2", vec![json!("key1")], true, true))?
+            .collect();
+        assert_eq!(1, v1.len());
+        assert_eq!(Value::Null, v1[0].value);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
 #[test]
 fn test_index_metadata() -> Result<()> {
     let path = "test_index_metadata.db";
@@ -457,6 +500,95 @@ fn test_batch() -> Result<()> {
 
 
 
+#[test]
+fn test_batch_schema() -> Result<()> {
+    let path = "test_batch_schema.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("type1", "key1", &json!({"name": "John Doe"}))?;
+
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "type1", "key2", &json!({"name": "Mary Doe"}))?;
+        eql.batch_insert(&mut batch, "type2", "key1", &json!({"name": "Jane Doe"}))?;
+        eql.batch_insert(&mut batch, "type2", "key2", &json!({"name": "Jim Doe"}))?;
+        eql.batch_delete(&mut batch, "type2", "key2")?;
+        eql.batch_delete(&mut batch, "type1", "key1")?;
+
+        assert_eq!(5, batch.len());
+        assert_eq!(
+            BatchCounts {
+                inserts: 1,
+                deletes: 1
+            },
+            batch.count("type1")
+        );
+        assert_eq!(
+            BatchCounts {
+                inserts: 2,
+                deletes: 1
+            },
+            batch.count("type2")
+        );
+        assert_eq!(2, batch.counts().len());
+        assert_eq!(vec!["type2"], batch.new_types().into_iter().collect::<Vec<_>>());
+        assert_eq!(false, eql.metadata.indices.contains_key("type2"));
+
+        batch.clear();
+        assert_eq!(true, batch.is_empty());
+        assert_eq!(true, batch.new_types().is_empty());
+        eql.write(batch)?;
+        assert_eq!(false, eql.metadata.indices.contains_key("type2"));
+        assert_eq!(Some(json!({"name": "John Doe"})), eql.get("type1", "key1")?);
+
+        // a batch that is never written leaves nothing behind
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "type3", "key1", &json!({"name": "Jane Doe"}))?;
+        drop(batch);
+
+        eql.add_index("type2", "idx1", vec!["/name"])?;
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "type2", "key1", &json!({"name": "Jane Doe"}))?;
+        eql.batch_insert(&mut batch, "type2", "key2", &json!({"name": "Jim Doe"}))?;
+        eql.batch_delete(&mut batch, "type2", "key2")?;
+        eql.write(batch)?;
+        let v1: Vec<EQLRecord> = eql.execute(scan("type2"))?.collect();
+        assert_eq!(1, v1.len());
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type2", "idx1", vec![json!("Jane Doe")]))?
+            .collect();
+        assert_eq!(1, v1.len());
+        assert_eq!(Value::from("key1"), v1[0].key);
+
+        // a failed write leaves the schema unchanged
+        eql.add_index_with(
+            "type4",
+            "idx1",
+            vec!["/name"],
+            IndexOptions {
+                filter: Some(IndexFilter::Script(String::from("rec.value.name"))),
+                ..Default::default()
+            },
+        )?;
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "type5", "key1", &json!({"name": "Jane Doe"}))?;
+        eql.batch_insert(&mut batch, "type4", "key1", &json!({"name": "Jane Doe"}))?;
+        assert_eq!(true, eql.write(batch).is_err());
+        assert_eq!(false, eql.metadata.indices.contains_key("type5"));
+        assert_eq!(None, eql.get("type5", "key1")?);
+        assert_eq!(None, eql.get("type4", "key1")?);
+    }
+    {
+        let eql = EQLDB::open(path)?;
+        assert_eq!(false, eql.metadata.indices.contains_key("type3"));
+        assert_eq!(false, eql.metadata.indices.contains_key("type5"));
+        assert_eq!(true, eql.metadata.indices.contains_key("type2"));
+        let v1: Vec<EQLRecord> = eql.execute(scan("type3"))?.collect();
+        assert_eq!(0, v1.len());
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
 #[test]
 fn test_two_types_nested_loops() -> Result<()> {
     let path = "test_two_types_nested_loops.db";
@@ -641,6 +773,70 @@ fn test_merge() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_merge_runs() -> Result<()> {
+    let path = "test_merge_runs.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("orders", 1, &json!({"customer": "a"}))?;
+        eql.insert("orders", 2, &json!({"customer": "b"}))?;
+        eql.insert("orders", 3, &json!({"customer": "a"}))?;
+        eql.insert("shipments", 10, &json!({"customer": "a"}))?;
+        eql.insert("shipments", 11, &json!({"customer": "c"}))?;
+        eql.insert("shipments", 12, &json!({"customer": "a"}))?;
+        eql.insert("shipments", 13, &json!({"customer": "a"}))?;
+        let join = |(o, s): (Option<&EQLRecord>, Option<&EQLRecord>)| {
+            Ok(Some(EQLRecord::new(
+                json!([o.map(|r| r.key.clone()), s.map(|r| r.key.clone())]),
+                Value::Null,
+            )))
+        };
+        let customer = || RecordExtract::pointer("/customer");
+        let keys: Vec<Value> = eql
+            .execute(merge(
+                sort(scan("orders"), customer()),
+                customer(),
+                sort(scan("shipments"), customer()),
+                customer(),
+                join,
+            ))?
+            .map(|r| r.key)
+            .collect();
+        // the runs of customer a give the cross product of their records
+        assert_eq!(
+            vec![
+                json!([1, 10]),
+                json!([1, 12]),
+                json!([1, 13]),
+                json!([3, 10]),
+                json!([3, 12]),
+                json!([3, 13]),
+                json!([2, null]),
+                json!([null, 11]),
+            ],
+            keys
+        );
+
+        // the shipments are not sorted by customer
+        let r = eql.execute(merge(
+            sort(scan("orders"), customer()),
+            customer(),
+            scan("shipments"),
+            customer(),
+            join,
+        ));
+        match r.err().and_then(|e| e.downcast::<QueryError>().ok()) {
+            Some(QueryError::UnsortedMergeInput { side, previous, key }) => {
+                assert_eq!("second", side);
+                assert_eq!("\"c\"", previous);
+                assert_eq!("\"a\"", key);
+            }
+            _ => panic!("expected unsorted merge input"),
+        }
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
 #[test]
 fn test_process() -> Result<()> {
     let path = "test_process.db";
@@ -731,3 +927,1667 @@ fn test_process() -> Result<()> {
     EQLDB::destroy(path)?;
     Ok(())
 }
+
+#[test]
+fn test_format() -> Result<()> {
+    let path = "test_format.db";
+    {
+        let mut eql = EQLDB::open(path)?;
+        eql.add_index("type1", "idx1", vec!["/name"])?;
+        let john = json!({
+            "name": "John Doe",
+            "age": 43,
+            "phones": [
+                "+44 1234567",
+                "+44 2345678"
+            ]
+        });
+        eql.insert("type1", "key1", &john)?;
+
+        let converted = eql.convert_format("type1", ValueFormat::Cbor)?;
+        assert_eq!(1, converted);
+        assert_eq!(Some(&ValueFormat::Cbor), eql.metadata.formats.get("type1"));
+
+        let mary = json!({
+            "name": "Mary Doe",
+            "age": 34
+        });
+        eql.insert("type1", "key2", &mary)?;
+
+        assert_eq!(Some(john.clone()), eql.get("type1", "key1")?);
+        assert_eq!(Some(mary.clone()), eql.get("type1", "key2")?);
+
+        let v1: Vec<EQLRecord> = eql.execute(scan("type1"))?.collect();
+        assert_eq!(2, v1.len());
+        assert_eq!(john, v1[0].value);
+        assert_eq!(mary, v1[1].value);
+
+        eql.set_format("type1", ValueFormat::Json)?;
+        eql.insert("type1", "key3", &mary)?;
+        assert_eq!(Some(mary.clone()), eql.get("type1", "key3")?);
+
+        eql.delete("type1", "key2")?;
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "idx1", vec![json!("Mary Doe")]))?
+            .collect();
+        assert_eq!(1, v1.len());
+        assert_eq!(Value::from("key3"), v1[0].key);
+    }
+    {
+        let mut eql = EQLDB::open(path)?;
+        assert_eq!(Some(&ValueFormat::Json), eql.metadata.formats.get("type1"));
+        let converted = eql.convert_format("type1", ValueFormat::Json)?;
+        assert_eq!(1, converted);
+        let v1: Vec<EQLRecord> = eql.execute(scan("type1"))?.collect();
+        assert_eq!(2, v1.len());
+        assert_eq!(Value::from("key1"), v1[0].key);
+        assert_eq!(json!(43), v1[0].value["age"]);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_bulk_load() -> Result<()> {
+    let path = "test_bulk_load.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.add_index("type1", "idx1", vec!["/group"])?;
+        eql.insert("type1", 0, &json!({"name": "existing", "group": "g0"}))?;
+
+        let options = BulkLoadOptions {
+            sorted: false,
+            memory_budget: 1024,
+        };
+        let recs = (1..=500)
+            .rev()
+            .map(|i| EQLRecord::new(json!(i), json!({"name": format!("name{}", i), "group": format!("g{}", i % 5)})));
+        let count = eql.bulk_load("type1", recs, options)?;
+        assert_eq!(500, count);
+
+        let v1: Vec<EQLRecord> = eql.execute(scan("type1"))?.collect();
+        assert_eq!(501, v1.len());
+        assert_eq!(
+            Some(json!({"name": "name42", "group": "g2"})),
+            eql.get("type1", 42)?
+        );
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "idx1", vec![json!("g2")]))?
+            .collect();
+        assert_eq!(100, v1.len());
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "idx1", vec![json!("g0")]))?
+            .collect();
+        assert_eq!(101, v1.len());
+
+        let recs = (1..=3).map(|i| EQLRecord::new(json!(format!("k{}", i)), json!({"group": "sorted"})));
+        let count = eql.bulk_load(
+            "type2",
+            recs,
+            BulkLoadOptions {
+                sorted: true,
+                ..BulkLoadOptions::default()
+            },
+        )?;
+        assert_eq!(3, count);
+        let v1: Vec<EQLRecord> = eql.execute(scan("type2"))?.collect();
+        assert_eq!(3, v1.len());
+
+        let recs = vec![
+            EQLRecord::new(json!("b"), json!({})),
+            EQLRecord::new(json!("a"), json!({})),
+        ];
+        let r = eql.bulk_load(
+            "type3",
+            recs,
+            BulkLoadOptions {
+                sorted: true,
+                ..BulkLoadOptions::default()
+            },
+        );
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<BulkLoadError>().ok()),
+                Some(BulkLoadError::UnsortedInput { .. })
+            )
+        );
+        let v1: Vec<EQLRecord> = eql.execute(scan("type3"))?.collect();
+        assert_eq!(0, v1.len());
+
+        let recs = vec![
+            EQLRecord::new(json!("a"), json!({})),
+            EQLRecord::new(json!("a"), json!({})),
+        ];
+        let r = eql.bulk_load(
+            "type3",
+            recs,
+            BulkLoadOptions {
+                sorted: true,
+                ..BulkLoadOptions::default()
+            },
+        );
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<BulkLoadError>().ok()),
+                Some(BulkLoadError::DuplicateKey { .. })
+            )
+        );
+
+        // unsorted records with the same key: the last one is loaded, with its index entries only
+        eql.add_index("type4", "x", vec!["/x"])?;
+        let recs = vec![
+            EQLRecord::new(json!(1), json!({"x": "a"})),
+            EQLRecord::new(json!(1), json!({"x": "b"})),
+        ];
+        assert_eq!(1, eql.bulk_load("type4", recs, BulkLoadOptions::default())?);
+        assert_eq!(Some(json!({"x": "b"})), eql.get("type4", 1)?);
+        assert_eq!(true, eql.verify_index("type4", "x")?.is_consistent());
+        let v1: Vec<EQLRecord> = eql.execute(index_lookup("type4", "x", vec![json!("a")]))?.collect();
+        assert_eq!(0, v1.len());
+        let v1: Vec<EQLRecord> = eql.execute(index_lookup("type4", "x", vec![json!("b")]))?.collect();
+        assert_eq!(1, v1.len());
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_conditional_writes() -> Result<()> {
+    let path = "test_conditional_writes.db";
+    {
+        let mut eql = EQLDB::open(path)?;
+        eql.add_index("type1", "name", vec!["/name"])?;
+        let john = json!({"name": "John Doe", "age": 43});
+        let jack = json!({"name": "Jack Doe", "age": 44});
+
+        eql.insert_if_absent("type1", "key1", &john)?;
+        let err = eql.insert_if_absent("type1", "key1", &jack).unwrap_err();
+        match err.downcast_ref::<ConflictError>() {
+            Some(ConflictError::Exists { found, .. }) => assert_eq!(john, *found),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let err = eql
+            .replace_if("type1", "key1", Expected::Value(jack.clone()), &jack)
+            .unwrap_err();
+        match err.downcast_ref::<ConflictError>() {
+            Some(ConflictError::Mismatch { found, .. }) => assert_eq!(Some(john.clone()), *found),
+            e => panic!("unexpected error {:?}", e),
+        }
+        eql.replace_if("type1", "key1", Expected::Value(john.clone()), &jack)?;
+        assert_eq!(Some(jack.clone()), eql.get("type1", "key1")?);
+
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "name", vec![json!("John Doe")]))?
+            .collect();
+        assert_eq!(0, v1.len());
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "name", vec![json!("Jack Doe")]))?
+            .collect();
+        assert_eq!(1, v1.len());
+
+        let (_, version) = eql.get_versioned("type1", "key1")?.unwrap();
+        eql.replace_if("type1", "key1", Expected::Version(version), &john)?;
+        assert_eq!(true, eql.delete_if("type1", "key1", Expected::Version(version)).is_err());
+        let (_, version) = eql.get_versioned("type1", "key1")?.unwrap();
+        eql.delete_if("type1", "key1", Expected::Version(version))?;
+        assert_eq!(None, eql.get("type1", "key1")?);
+
+        let err = eql
+            .delete_if("type1", "key1", Expected::Value(john))
+            .unwrap_err();
+        match err.downcast_ref::<ConflictError>() {
+            Some(ConflictError::Mismatch { found, .. }) => assert_eq!(None, *found),
+            e => panic!("unexpected error {:?}", e),
+        }
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "name", vec![json!("John Doe")]))?
+            .collect();
+        assert_eq!(0, v1.len());
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_insert_auto() -> Result<()> {
+    let path = "test_insert_auto.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        assert_eq!(json!(1), eql.insert_auto("type1", &json!({"name": "John Doe"}))?);
+        assert_eq!(json!(2), eql.insert_auto("type1", &json!({"name": "Mary Doe"}))?);
+        assert_eq!(Some(json!({"name": "Mary Doe"})), eql.get("type1", 2)?);
+
+        eql.set_key_generator(
+            "invoices",
+            KeyGenerator::Prefixed {
+                prefix: String::from("INV-"),
+                width: 4,
+            },
+        )?;
+        eql.set_key_generator(
+            "lines",
+            KeyGenerator::Composite {
+                components: vec![json!("eu")],
+            },
+        )?;
+        let mut batch = EQLBatch::default();
+        let inv = eql.batch_insert_auto(&mut batch, "invoices", &json!({"total": 10}))?;
+        assert_eq!(json!("INV-0001"), inv);
+        let line = eql.batch_insert_auto(&mut batch, "lines", &json!({ "invoice": inv }))?;
+        assert_eq!(json!(["eu", 1]), line);
+        assert_eq!(None, eql.get("invoices", inv.clone())?);
+        eql.write(batch)?;
+        assert_eq!(Some(json!({"invoice": "INV-0001"})), eql.get("lines", line)?);
+    }
+    {
+        let mut eql = EQLDB::open(path)?;
+        assert_eq!(json!(3), eql.insert_auto("type1", &json!({"name": "Jack Doe"}))?);
+        assert_eq!(json!("INV-0002"), eql.next_key("invoices")?);
+
+        eql.insert("type1", 4, &json!({"name": "Jane Doe"}))?;
+        let r = eql.insert_auto("type1", &json!({"name": "Joe Doe"}));
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<ConflictError>().ok()),
+                Some(ConflictError::Exists { .. })
+            )
+        );
+        assert_eq!(Some(json!({"name": "Jane Doe"})), eql.get("type1", 4)?);
+        assert_eq!(json!(5), eql.insert_auto("type1", &json!({"name": "Joe Doe"}))?);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_key_prefix_scan() -> Result<()> {
+    let path = "test_key_prefix_scan.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("orders", json!(["customer42", "2024-01-05", 17]), &json!({"total": 1}))?;
+        eql.insert("orders", json!(["customer42", "2024-01-05", 18]), &json!({"total": 2}))?;
+        eql.insert("orders", json!(["customer42", "2024-01-06", 1]), &json!({"total": 3}))?;
+        eql.insert("orders", json!(["customer420", "2024-01-05", 1]), &json!({"total": 4}))?;
+        eql.insert("orders", json!([4, 1]), &json!({"total": 5}))?;
+        eql.insert("orders", json!([42, 1]), &json!({"total": 6}))?;
+        eql.insert("orders", json!("customer42"), &json!({"total": 7}))?;
+
+        let totals = |v: Vec<EQLRecord>| v.into_iter().map(|r| r.value["total"].clone()).collect::<Vec<Value>>();
+        let v1: Vec<EQLRecord> = eql.execute(key_prefix_scan("orders", vec![json!("customer42")]))?.collect();
+        assert_eq!(vec![json!(1), json!(2), json!(3)], totals(v1));
+        let v1: Vec<EQLRecord> = eql
+            .execute(key_prefix_scan("orders", vec![json!("customer42"), json!("2024-01-05")]))?
+            .collect();
+        assert_eq!(vec![json!(1), json!(2)], totals(v1));
+        let v1: Vec<EQLRecord> = eql.execute(key_prefix_scan("orders", vec![json!(4)]))?.collect();
+        assert_eq!(vec![json!(5)], totals(v1));
+        let v1: Vec<EQLRecord> = eql.execute(key_prefix_scan("orders", vec![]))?.collect();
+        assert_eq!(6, v1.len());
+        let v1: Vec<EQLRecord> = eql
+            .execute_script(r#"key_prefix_scan(orders, ["customer420"])"#)?
+            .collect();
+        assert_eq!(vec![json!(4)], totals(v1));
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_shared_storage() -> Result<()> {
+    let path = "test_shared_storage.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.create_type("type1", Storage::Shared)?;
+        eql.create_type("type2", Storage::Shared)?;
+        eql.insert("type1", "key1", &json!({"name": "John Doe", "age": 43}))?;
+        eql.insert("type1", "key2", &json!({"name": "Mary Doe", "age": 34}))?;
+        eql.insert("type2", "key1", &json!({"name": "Jack Doe", "age": 43}))?;
+        eql.add_index("type1", "age", vec!["/age"])?;
+        eql.add_index("type2", "age", vec!["/age"])?;
+        assert_eq!(true, eql.create_type("type1", Storage::Dedicated).is_err());
+    }
+    {
+        let mut eql = EQLDB::open(path)?;
+        assert_eq!(Some(&Storage::Shared), eql.metadata.storage.get("type1"));
+        eql.insert("type1", "key3", &json!({"name": "Jill Doe", "age": 43}))?;
+
+        let v1: Vec<EQLRecord> = eql.execute(scan("type1"))?.collect();
+        assert_eq!(3, v1.len());
+        assert_eq!(json!("key1"), v1[0].key);
+        let v1: Vec<EQLRecord> = eql.execute(scan("type2"))?.collect();
+        assert_eq!(1, v1.len());
+        let v1: Vec<EQLRecord> = eql.execute(key_lookup("type2", json!("key1")))?.collect();
+        assert_eq!(json!("Jack Doe"), v1[0].value["name"]);
+
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "age", vec![json!(43)]))?
+            .collect();
+        assert_eq!(vec![json!("key1"), json!("key3")], v1.iter().map(|r| r.key.clone()).collect::<Vec<Value>>());
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type2", "age", vec![]))?
+            .collect();
+        assert_eq!(1, v1.len());
+
+        eql.delete("type1", "key1")?;
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type1", "age", vec![json!(43)]))?
+            .collect();
+        assert_eq!(1, v1.len());
+
+        eql.delete_index("type1", "age")?;
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("type2", "age", vec![json!(43)]))?
+            .collect();
+        assert_eq!(1, v1.len());
+        let v1: Vec<EQLRecord> = eql.execute(scan("type1"))?.collect();
+        assert_eq!(2, v1.len());
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_encryption() -> Result<()> {
+    let path = "test_encryption.db";
+    let mut provider = MemoryKeyProvider {
+        current: String::from("k1"),
+        ..Default::default()
+    };
+    provider.keys.insert(String::from("k1"), vec![1; 32]);
+    provider.keys.insert(String::from("k2"), vec![2; 32]);
+    provider.keys.insert(String::from("idx"), vec![3; 32]);
+    let john = json!({"name": "John Doe", "age": 43});
+    let mary = json!({"name": "Mary Doe", "age": 34});
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("people", "key1", &john)?;
+        eql.set_encryption(
+            "people",
+            Encryption {
+                index_keys: IndexProtection::KeyedHash {
+                    key_id: String::from("idx"),
+                },
+            },
+        )?;
+        assert_eq!(true, eql.insert("people", "key2", &mary).is_err());
+        eql.set_key_provider(provider.clone());
+        eql.insert("people", "key2", &mary)?;
+        eql.insert("people", json!(["family", 1]), &json!({"name": "Ann Doe", "age": 12}))?;
+        assert_eq!(1, eql.convert_format("people", ValueFormat::Json)?);
+        eql.add_index("people", "name", vec!["/name"])?;
+
+        eql.set_encryption(
+            "secrets",
+            Encryption {
+                index_keys: IndexProtection::Forbidden,
+            },
+        )?;
+        let err = eql.add_index("secrets", "name", vec!["/name"]).unwrap_err();
+        assert_eq!(
+            true,
+            matches!(err.downcast_ref::<EncryptionError>(), Some(EncryptionError::ForbiddenIndex { .. }))
+        );
+    }
+    {
+        let mut eql = EQLDB::open(path)?;
+        assert_eq!(true, eql.execute(scan("people")).is_err());
+        assert_eq!(true, eql.get("people", "key1").is_err());
+
+        provider.current = String::from("k2");
+        eql.set_key_provider(provider);
+        eql.insert("people", "key3", &json!({"name": "Jack Doe", "age": 44}))?;
+        assert_eq!(Some(john.clone()), eql.get("people", "key1")?);
+        let v1: Vec<EQLRecord> = eql.execute(scan("people"))?.collect();
+        assert_eq!(vec![john, mary.clone()], v1.iter().take(2).map(|r| r.value.clone()).collect::<Vec<Value>>());
+        let v1: Vec<EQLRecord> = eql
+            .execute(multi_key_lookup("people", vec![json!("key2"), json!("key3")]))?
+            .collect();
+        assert_eq!(json!("Jack Doe"), v1[1].value["name"]);
+
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("people", "name", vec![json!("Mary Doe")]))?
+            .collect();
+        assert_eq!(1, v1.len());
+        assert_eq!(json!("key2"), v1[0].key);
+        // hashed values do not keep their order
+        let r = eql.execute(index_range("people", "name", vec![], IndexRange::at_least(json!("M"))));
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<QueryError>().ok()),
+                Some(QueryError::HashedIndexRange { .. })
+            )
+        );
+        eql.delete("people", "key2")?;
+        let v1: Vec<EQLRecord> = eql
+            .execute(index_lookup("people", "name", vec![json!("Mary Doe")]))?
+            .collect();
+        assert_eq!(0, v1.len());
+    }
+    {
+        // after key rotation, reading values written with a retired key fails instead of panicking
+        let mut eql = EQLDB::open(path)?;
+        let mut rotated = MemoryKeyProvider {
+            current: String::from("k2"),
+            ..Default::default()
+        };
+        rotated.keys.insert(String::from("k2"), vec![2; 32]);
+        rotated.keys.insert(String::from("idx"), vec![3; 32]);
+        eql.set_key_provider(rotated);
+        assert_eq!(true, eql.get("people", "key1").is_err());
+        assert_eq!(true, eql.execute(scan("people")).is_err());
+        assert_eq!(true, eql.execute(key_lookup("people", json!("key1"))).is_err());
+        assert_eq!(true, eql.execute(key_prefix_scan("people", vec![json!("family")])).is_err());
+        let v1: Vec<EQLRecord> = eql.execute(key_lookup("people", json!("key3")))?.collect();
+        assert_eq!(json!("Jack Doe"), v1[0].value["name"]);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_maintenance() -> Result<()> {
+    let path = "test_maintenance.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.create_type("small", Storage::Shared)?;
+        eql.add_index("type1", "name", vec!["/name"])?;
+        for i in 0..10 {
+            eql.insert("type1", i, &json!({"name": format!("name{}", i)}))?;
+        }
+        eql.insert("small", "key1", &json!({"name": "John Doe"}))?;
+        eql.compact_type("type1")?;
+        eql.compact_type("small")?;
+        eql.compact_index("type1", "name")?;
+        assert_eq!(true, eql.compact_type("type2").is_err());
+        assert_eq!(true, eql.compact_index("type1", "age").is_err());
+
+        let usage = eql.disk_usage()?;
+        assert_eq!(10, usage.types["type1"].data.estimated_keys);
+        assert_eq!(10, usage.types["type1"].indices["name"].estimated_keys);
+        assert_eq!(true, usage.types["type1"].data.live_data_size > 0);
+        assert_eq!(1, usage.types["small"].data.estimated_keys);
+        assert_eq!(1, usage.shared.estimated_keys);
+
+        let stats = eql.stats()?;
+        assert_eq!(0, stats.background_errors);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_index_existing_records() -> Result<()> {
+    let path = "test_index_existing_records.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        // more records than a write batch holds, the last batch being partial
+        for i in 0..1001 {
+            eql.insert("type1", i, &json!({"name": format!("name{}", i)}))?;
+        }
+        eql.add_index("type1", "name", vec!["/name"])?;
+        let report = eql.verify_index("type1", "name")?;
+        assert_eq!(1001, report.entries);
+        assert_eq!(true, report.is_consistent());
+        let v1: Vec<EQLRecord> = eql.execute(index_lookup("type1", "name", vec![json!("name1000")]))?.collect();
+        assert_eq!(1, v1.len());
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_index_verification() -> Result<()> {
+    let path = "test_index_verification.db";
+    fn corrupt(path: &str, delete_first: bool) -> Result<()> {
+        let cfs = vec!["#seq", "#shared", "type1", "#idx_type1_name"]
+            .into_iter()
+            .map(|n| rocksdb::ColumnFamilyDescriptor::new(n, rocksdb::Options::default()));
+        let db = rocksdb::DB::open_cf_descriptors(&rocksdb::Options::default(), path, cfs)?;
+        let cf = db.cf_handle("#idx_type1_name").unwrap();
+        if delete_first {
+            let (k, _) = db.iterator_cf(cf, rocksdb::IteratorMode::Start).next().unwrap();
+            db.delete_cf(cf, k)?;
+        }
+        db.put_cf(cf, b"ghost", b"\"ghost\"")?;
+        Ok(())
+    }
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.create_type("small", Storage::Shared)?;
+        eql.add_index("type1", "name", vec!["/name"])?;
+        eql.add_index("small", "name", vec!["/name"])?;
+        for i in 0..10 {
+            eql.insert("type1", i, &json!({"name": format!("name{}", i)}))?;
+        }
+        eql.insert("type1", 3, &json!({"name": "name3bis"}))?;
+        eql.insert("small", "key1", &json!({"name": "John Doe"}))?;
+        let report = eql.verify_index("type1", "name")?;
+        assert_eq!(true, report.is_consistent());
+        assert_eq!(10, report.records);
+        assert_eq!(10, report.entries);
+        assert_eq!(true, eql.verify_index("small", "name")?.is_consistent());
+        assert_eq!(true, eql.verify_index("type1", "age").is_err());
+    }
+    corrupt(path, true)?;
+    {
+        let mut eql = EQLDB::open(path)?;
+        let report = eql.verify_index("type1", "name")?;
+        assert_eq!(false, report.is_consistent());
+        assert_eq!(1, report.missing_entries);
+        assert_eq!(vec![json!(0)], report.missing);
+        assert_eq!(1, report.orphaned_entries);
+        assert_eq!(vec![json!("ghost")], report.orphaned);
+        let v1: Vec<EQLRecord> = eql.execute(index_lookup("type1", "name", vec![json!("name0")]))?.collect();
+        assert_eq!(0, v1.len());
+
+        assert_eq!(report, eql.repair_index("type1", "name")?);
+        assert_eq!(true, eql.verify_index("type1", "name")?.is_consistent());
+        let v1: Vec<EQLRecord> = eql.execute(index_lookup("type1", "name", vec![json!("name0")]))?.collect();
+        assert_eq!(1, v1.len());
+    }
+    corrupt(path, false)?;
+    {
+        let mut eql = EQLDB::open(path)?;
+        assert_eq!(1, eql.verify_index("type1", "name")?.orphaned_entries);
+        assert_eq!(10, eql.rebuild_index("type1", "name")?);
+        assert_eq!(true, eql.verify_index("type1", "name")?.is_consistent());
+        assert_eq!(1, eql.rebuild_index("small", "name")?);
+        assert_eq!(true, eql.verify_index("small", "name")?.is_consistent());
+        let v1: Vec<EQLRecord> = eql.execute(index_lookup("small", "name", vec![json!("John Doe")]))?.collect();
+        assert_eq!(1, v1.len());
+        assert_eq!(json!("key1"), v1[0].key);
+        assert_eq!(true, eql.rebuild_index("type1", "age").is_err());
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_hash_spill() -> Result<()> {
+    let path = "test_hash_spill.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        for i in 0..200 {
+            eql.insert("customers", i, &json!({"name": format!("customer{}", i)}))?;
+        }
+        for i in 0..500 {
+            eql.insert("orders", i, &json!({"customer_id": i % 250}))?;
+        }
+        let join = |(o, mut rec): (Option<&EQLRecord>, EQLRecord)| {
+            if let Some(c) = o {
+                rec.value["name"] = c.value["name"].clone();
+            }
+            Ok(Some(rec))
+        };
+        let mut v1: Vec<EQLRecord> = eql
+            .execute(hash_join_with_budget(
+                scan("customers"),
+                RecordExtract::Key,
+                scan("orders"),
+                RecordExtract::pointer("/customer_id"),
+                join,
+                1024,
+            ))?
+            .collect();
+        let mut v2: Vec<EQLRecord> = eql
+            .execute(hash_join(
+                scan("customers"),
+                RecordExtract::Key,
+                scan("orders"),
+                RecordExtract::pointer("/customer_id"),
+                join,
+            ))?
+            .collect();
+        assert_eq!(500, v1.len());
+        v1.sort_by_key(|r| r.key.as_u64());
+        v2.sort_by_key(|r| r.key.as_u64());
+        assert_eq!(v2, v1);
+        assert_eq!(json!("customer7"), v1[257].value["name"]);
+        assert_eq!(Value::Null, v1[210].value["name"]);
+        // errors of the join function are returned, whether the build side spills or not
+        for budget in [1024, DEFAULT_JOIN_MEMORY_BUDGET] {
+            let r = eql.execute(hash_join_with_budget(
+                scan("customers"),
+                RecordExtract::Key,
+                scan("orders"),
+                RecordExtract::pointer("/customer_id"),
+                |_| Err(QueryError::HashLookupError(String::from("join failed")).into()),
+                budget,
+            ));
+            assert_eq!(true, r.is_err());
+        }
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_hash_many() -> Result<()> {
+    let path = "test_hash_many.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("products", 1, &json!({"name": "Chai"}))?;
+        eql.insert("products", 2, &json!({"name": "Chang"}))?;
+        eql.insert("lines", json!([1, 1]), &json!({"product_id": 1, "quantity": 3}))?;
+        eql.insert("lines", json!([1, 2]), &json!({"product_id": 2, "quantity": 1}))?;
+        eql.insert("lines", json!([2, 1]), &json!({"product_id": 1, "quantity": 5}))?;
+        eql.insert("lines", json!([3, 1]), &json!({"product_id": 3, "quantity": 2}))?;
+        // build on the order lines, several of them match the same product
+        let v: Vec<EQLRecord> = eql
+            .execute(hash_join(
+                scan("lines"),
+                RecordExtract::pointer("/product_id"),
+                scan("products"),
+                RecordExtract::Key,
+                |(o, rec)| {
+                    Ok(Some(EQLRecord::new(
+                        rec.key,
+                        json!({"name": rec.value["name"], "line": o.map(|l| l.key.clone())}),
+                    )))
+                },
+            ))?
+            .collect();
+        assert_eq!(
+            vec![
+                EQLRecord::new(json!(1), json!({"name": "Chai", "line": [1, 1]})),
+                EQLRecord::new(json!(1), json!({"name": "Chai", "line": [2, 1]})),
+                EQLRecord::new(json!(2), json!({"name": "Chang", "line": [1, 2]})),
+            ],
+            v
+        );
+        // products without lines are still joined once
+        eql.insert("products", 4, &json!({"name": "Aniseed Syrup"}))?;
+        let v: Vec<EQLRecord> = eql
+            .execute(hash_join(
+                scan("lines"),
+                RecordExtract::pointer("/product_id"),
+                key_lookup("products", json!(4)),
+                RecordExtract::Key,
+                |(o, rec)| Ok(Some(EQLRecord::new(rec.key, json!(o.is_none())))),
+            ))?
+            .collect();
+        assert_eq!(vec![EQLRecord::new(json!(4), json!(true))], v);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_sort() -> Result<()> {
+    let path = "test_sort.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("items", 2, &json!({"name": "beta"}))?;
+        eql.insert("items", 10, &json!({"name": "Alpha"}))?;
+        eql.insert("items", 1, &json!({"name": "gamma"}))?;
+        eql.insert("items", 3, &json!({}))?;
+        // keys are stored in the order of their JSON serialization
+        let keys: Vec<Value> = eql.execute(scan("items"))?.map(|r| r.key).collect();
+        assert_eq!(vec![json!(1), json!(10), json!(2), json!(3)], keys);
+        let keys: Vec<Value> = eql
+            .execute(sort(scan("items"), RecordExtract::Key))?
+            .map(|r| r.key)
+            .collect();
+        assert_eq!(vec![json!(1), json!(2), json!(3), json!(10)], keys);
+        let keys: Vec<Value> = eql
+            .execute(sort_with(
+                scan("items"),
+                RecordExtract::pointer("/name"),
+                true,
+                Box::new(compare_case_insensitive),
+            ))?
+            .map(|r| r.key)
+            .collect();
+        assert_eq!(vec![json!(1), json!(2), json!(10), json!(3)], keys);
+
+        eql.insert("names", 1, &json!({"name": "ALPHA"}))?;
+        eql.insert("names", 2, &json!({"name": "Gamma"}))?;
+        let join = |(o1, o2): (Option<&EQLRecord>, Option<&EQLRecord>)| {
+            Ok(match (o1, o2) {
+                (Some(r1), Some(r2)) => Some(EQLRecord::new(r1.key.clone(), r2.key.clone())),
+                _ => None,
+            })
+        };
+        let v: Vec<EQLRecord> = eql
+            .execute(merge_with(
+                sort_with(scan("items"), RecordExtract::pointer("/name"), false, Box::new(compare_case_insensitive)),
+                RecordExtract::pointer("/name"),
+                sort_with(scan("names"), RecordExtract::pointer("/name"), false, Box::new(compare_case_insensitive)),
+                RecordExtract::pointer("/name"),
+                join,
+                Box::new(compare_case_insensitive),
+            ))?
+            .collect();
+        assert_eq!(
+            vec![EQLRecord::new(json!(10), json!(1)), EQLRecord::new(json!(1), json!(2))],
+            v
+        );
+        // the scan is not sorted by value
+        let r = eql.execute(merge(
+            scan("items"),
+            RecordExtract::Key,
+            sort(scan("names"), RecordExtract::Key),
+            RecordExtract::Key,
+            join,
+        ));
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<QueryError>().ok()),
+                Some(QueryError::UnsortedMergeInput { side: "first", .. })
+            )
+        );
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_hash_canonical() -> Result<()> {
+    let path = "test_hash_canonical.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("prices", 1, &json!({"amount": 1, "size": {"w": 2, "h": 3}}))?;
+        eql.insert("prices", 2, &json!({"amount": 2.5, "size": {"h": 1, "w": 1}}))?;
+        eql.insert("rates", "a", &json!({"amount": 1.0, "size": {"h": 3, "w": 2}}))?;
+        eql.insert("rates", "b", &json!({"amount": 2.5, "size": {"w": 1, "h": 1}}))?;
+        let join = |(o, rec): (Option<&EQLRecord>, EQLRecord)| {
+            Ok(o.map(|p| EQLRecord::new(rec.key.clone(), p.key.clone())))
+        };
+        let size = || {
+            RecordExtract::Multiple(vec![
+                RecordExtract::pointer("/amount"),
+                RecordExtract::pointer("/size"),
+            ])
+        };
+        // objects match whatever the order of their fields, but 1 and 1.0 do not match by default
+        let v: Vec<EQLRecord> = eql
+            .execute(hash_join(scan("prices"), size(), scan("rates"), size(), join))?
+            .collect();
+        assert_eq!(vec![EQLRecord::new(json!("b"), json!(2))], v);
+        let v: Vec<EQLRecord> = eql
+            .execute(hash_join_with(
+                scan("prices"),
+                size(),
+                scan("rates"),
+                size(),
+                join,
+                HashJoinOptions {
+                    numbers_by_value: true,
+                    ..Default::default()
+                },
+            ))?
+            .collect();
+        assert_eq!(
+            vec![
+                EQLRecord::new(json!("a"), json!(1)),
+                EQLRecord::new(json!("b"), json!(2))
+            ],
+            v
+        );
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_unique_index() -> Result<()> {
+    let path = "test_unique_index.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("users", 1, &json!({"email": "a@x.com"}))?;
+        eql.insert("users", 2, &json!({"email": "b@x.com"}))?;
+        eql.insert("users", 3, &json!({"email": "a@x.com"}))?;
+        eql.insert("users", 4, &json!({"name": "no email"}))?;
+        eql.insert("users", 5, &json!({"name": "no email either"}))?;
+        // existing duplicates prevent the creation of the index
+        let r = eql.add_unique_index("users", "email", vec!["/email"]);
+        match r.err().and_then(|e| e.downcast::<IndexError>().ok()) {
+            Some(IndexError::DuplicateValues { keys, .. }) => assert_eq!(vec![json!(1), json!(3)], keys),
+            _ => panic!("expected duplicate values"),
+        }
+        assert_eq!(false, eql.metadata.indices["users"].contains_key("email"));
+        eql.delete("users", 3)?;
+        eql.add_unique_index("users", "email", vec!["/email"])?;
+
+        let r = eql.insert("users", 6, &json!({"email": "a@x.com"}));
+        match r.err().and_then(|e| e.downcast::<IndexError>().ok()) {
+            Some(IndexError::UniqueViolation { key, existing, .. }) => {
+                assert_eq!(json!(6), key);
+                assert_eq!(json!(1), existing);
+            }
+            _ => panic!("expected unique violation"),
+        }
+        // a record can be replaced with its own values, and missing values are not checked
+        eql.insert("users", 1, &json!({"email": "a@x.com", "name": "A"}))?;
+        eql.insert("users", 6, &json!({"name": "no email"}))?;
+        // values freed by an update can be reused
+        eql.insert("users", 1, &json!({"email": "c@x.com"}))?;
+        eql.insert("users", 7, &json!({"email": "a@x.com"}))?;
+
+        // uniqueness holds inside a batch
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "users", 8, &json!({"email": "d@x.com"}))?;
+        assert_eq!(
+            true,
+            eql.batch_insert(&mut batch, "users", 9, &json!({"email": "d@x.com"}))
+                .is_err()
+        );
+        // values freed in the batch can be reused in the same batch
+        eql.batch_delete(&mut batch, "users", 2)?;
+        eql.batch_insert(&mut batch, "users", 9, &json!({"email": "b@x.com"}))?;
+        eql.batch_insert(&mut batch, "users", 8, &json!({"email": "e@x.com"}))?;
+        eql.batch_insert(&mut batch, "users", 10, &json!({"email": "d@x.com"}))?;
+        eql.write(batch)?;
+        assert_eq!(Some(json!({"email": "b@x.com"})), eql.get("users", 9)?);
+        assert_eq!(Some(json!({"email": "d@x.com"})), eql.get("users", 10)?);
+
+        // uniqueness holds for a record type created by the batch
+        eql.add_unique_index("admins", "email", vec!["/email"])?;
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "admins", 1, &json!({"email": "a@x.com"}))?;
+        assert_eq!(
+            true,
+            eql.batch_insert(&mut batch, "admins", 2, &json!({"email": "a@x.com"}))
+                .is_err()
+        );
+        assert_eq!(1, batch.count("admins").inserts);
+        // and for records queued before the index was added
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "owners", 1, &json!({"email": "a@x.com"}))?;
+        eql.add_unique_index("owners", "email", vec!["/email"])?;
+        eql.batch_insert(&mut batch, "owners", 2, &json!({"email": "a@x.com"}))?;
+        let r = eql.write(batch);
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<IndexError>().ok()),
+                Some(IndexError::UniqueViolation { .. })
+            )
+        );
+        assert_eq!(None, eql.get("owners", 1)?);
+
+        // bulk loads check the loaded records against the existing ones and against each other
+        let recs = vec![EQLRecord::new(json!(20), json!({"email": "a@x.com"}))];
+        let r = eql.bulk_load("users", recs, BulkLoadOptions::default());
+        match r.err().and_then(|e| e.downcast::<BulkLoadError>().ok()) {
+            Some(BulkLoadError::UniqueViolation { keys, .. }) => assert_eq!(vec![json!(20), json!(7)], keys),
+            _ => panic!("expected unique violation"),
+        }
+        assert_eq!(None, eql.get("users", 20)?);
+        let recs = vec![
+            EQLRecord::new(json!(21), json!({"email": "f@x.com"})),
+            EQLRecord::new(json!(22), json!({"email": "f@x.com"})),
+        ];
+        let r = eql.bulk_load("users", recs, BulkLoadOptions::default());
+        match r.err().and_then(|e| e.downcast::<BulkLoadError>().ok()) {
+            Some(BulkLoadError::UniqueViolation { keys, .. }) => assert_eq!(vec![json!(21), json!(22)], keys),
+            _ => panic!("expected unique violation"),
+        }
+        let recs = vec![
+            EQLRecord::new(json!(1), json!({"email": "c@x.com", "name": "C"})),
+            EQLRecord::new(json!(23), json!({"email": "g@x.com"})),
+        ];
+        assert_eq!(2, eql.bulk_load("users", recs, BulkLoadOptions::default())?);
+        assert_eq!(true, eql.verify_index("users", "email")?.is_consistent());
+    }
+    {
+        // the index stays unique after reopening
+        let mut eql = EQLDB::open(path)?;
+        assert_eq!(true, eql.insert("users", 11, &json!({"email": "e@x.com"})).is_err());
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_multi_valued_index() -> Result<()> {
+    let path = "test_multi_valued_index.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("contacts", 1, &json!({"name": "John", "phones": ["123", "456"]}))?;
+        eql.insert("contacts", 2, &json!({"name": "Jane", "phones": ["789"]}))?;
+        eql.add_index("contacts", "phones", vec!["/phones/*"])?;
+        eql.insert("orders", 1, &json!({"lines": [{"product_id": 7}, {"product_id": 8}]}))?;
+        eql.add_index("orders", "products", vec!["/lines/*/product_id"])?;
+        eql.insert("orders", 2, &json!({"lines": [{"product_id": 8}, {"product_id": 8}]}))?;
+
+        let lookup = |eql: &EQLDB, v: Value| -> Result<Vec<Value>> {
+            Ok(eql
+                .execute(index_lookup("contacts", "phones", vec![v]))?
+                .map(|r| r.key)
+                .collect())
+        };
+        assert_eq!(vec![json!(1)], lookup(&eql, json!("456"))?);
+        assert_eq!(vec![json!(2)], lookup(&eql, json!("789"))?);
+        let keys: Vec<Value> = eql
+            .execute(index_lookup("orders", "products", vec![json!(8)]))?
+            .map(|r| r.key)
+            .collect();
+        assert_eq!(vec![json!(1), json!(2)], keys);
+
+        // updates remove the entries of elements that are gone
+        eql.insert("contacts", 1, &json!({"name": "John", "phones": ["123", "999"]}))?;
+        assert_eq!(true, lookup(&eql, json!("456"))?.is_empty());
+        assert_eq!(vec![json!(1)], lookup(&eql, json!("999"))?);
+        assert_eq!(vec![json!(1)], lookup(&eql, json!("123"))?);
+        // updates in the same batch
+        let mut batch = EQLBatch::default();
+        eql.batch_insert(&mut batch, "contacts", 3, &json!({"phones": ["555"]}))?;
+        eql.batch_insert(&mut batch, "contacts", 3, &json!({"phones": ["666"]}))?;
+        eql.write(batch)?;
+        assert_eq!(true, lookup(&eql, json!("555"))?.is_empty());
+        assert_eq!(vec![json!(3)], lookup(&eql, json!("666"))?);
+
+        eql.delete("contacts", 1)?;
+        assert_eq!(true, lookup(&eql, json!("123"))?.is_empty());
+        assert_eq!(true, lookup(&eql, json!("999"))?.is_empty());
+        let entries: Vec<EQLRecord> = eql
+            .execute(index_lookup_keys("contacts", "phones", vec![], vec!["phone"]))?
+            .collect();
+        assert_eq!(
+            vec![
+                EQLRecord::new(json!(3), json!({"phone": "666"})),
+                EQLRecord::new(json!(2), json!({"phone": "789"})),
+            ],
+            entries
+        );
+
+        // every element is unique in a unique multi-valued index
+        eql.add_unique_index("contacts", "unique_phones", vec!["/phones/*"])?;
+        assert_eq!(true, eql.insert("contacts", 4, &json!({"phones": ["000", "789"]})).is_err());
+        eql.insert("contacts", 4, &json!({"phones": ["000", "000"]}))?;
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_partial_index() -> Result<()> {
+    let path = "test_partial_index.db";
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("orders", 1, &json!({"status": "open", "customer": "c1", "total": 10}))?;
+        eql.insert("orders", 2, &json!({"status": "closed", "customer": "c1", "total": 200}))?;
+        eql.add_index_with(
+            "orders",
+            "open_orders",
+            vec!["/customer"],
+            IndexOptions {
+                filter: Some(IndexFilter::Equals {
+                    pointer: String::from("/status"),
+                    value: json!("open"),
+                }),
+                ..Default::default()
+            },
+        )?;
+        eql.add_index_with(
+            "orders",
+            "big_orders",
+            vec!["/customer"],
+            IndexOptions {
+                filter: Some(IndexFilter::Script(String::from("rec.value.total > 100"))),
+                ..Default::default()
+            },
+        )?;
+        eql.insert("orders", 3, &json!({"status": "open", "customer": "c1", "total": 150}))?;
+        let lookup = |eql: &EQLDB, idx: &str| -> Result<Vec<Value>> {
+            Ok(eql
+                .execute(index_lookup("orders", idx, vec![json!("c1")]))?
+                .map(|r| r.key)
+                .collect())
+        };
+        assert_eq!(vec![json!(1), json!(3)], lookup(&eql, "open_orders")?);
+        assert_eq!(vec![json!(2), json!(3)], lookup(&eql, "big_orders")?);
+
+        // records leave the index when they stop matching
+        eql.insert("orders", 1, &json!({"status": "closed", "customer": "c1", "total": 10}))?;
+        eql.delete("orders", 3)?;
+        assert_eq!(true, lookup(&eql, "open_orders")?.is_empty());
+        assert_eq!(vec![json!(2)], lookup(&eql, "big_orders")?);
+
+        // invalid scripts are rejected when the index is created
+        assert_eq!(
+            true,
+            eql.add_index_with(
+                "orders",
+                "bad",
+                vec!["/customer"],
+                IndexOptions {
+                    filter: Some(IndexFilter::Script(String::from("rec.value.total >"))),
+                    ..Default::default()
+                },
+            )
+            .is_err()
+        );
+        // scripts that do not return a boolean fail, and the index is not created
+        let r = eql.add_index_with(
+            "orders",
+            "not_bool",
+            vec!["/customer"],
+            IndexOptions {
+                filter: Some(IndexFilter::Script(String::from("rec.value.total"))),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<IndexError>().ok()),
+                Some(IndexError::Filter { .. })
+            )
+        );
+        assert_eq!(false, eql.metadata.indices["orders"].contains_key("not_bool"));
+    }
+    {
+        // filters are kept in the metadata
+        let mut eql = EQLDB::open(path)?;
+        eql.insert("orders", 4, &json!({"status": "open", "customer": "c1", "total": 500}))?;
+        let keys: Vec<Value> = eql
+            .execute(index_lookup("orders", "big_orders", vec![json!("c1")]))?
+            .map(|r| r.key)
+            .collect();
+        assert_eq!(vec![json!(2), json!(4)], keys);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_expression_index() -> Result<()> {
+    let path = "test_expression_index.db";
+    let lookup = |eql: &EQLDB, idx: &str, values: Vec<Value>| -> Result<Vec<Value>> {
+        Ok(eql
+            .execute(index_lookup("people", idx, values))?
+            .map(|r| r.key)
+            .collect())
+    };
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert(
+            "people",
+            1,
+            &json!({"first": "John", "last": "Doe", "email": "John.Doe@Example.com", "born": "1978-05-12"}),
+        )?;
+        eql.add_index("people", "email", vec!["rec.value.email.to_lower()"])?;
+        eql.add_index("people", "year", vec!["rec.value.born.sub_string(0, 4).parse_int()"])?;
+        eql.add_index("people", "name", vec!["rec.value.first + \" \" + rec.value.last", "/born"])?;
+        eql.insert(
+            "people",
+            2,
+            &json!({"first": "Jane", "last": "Doe", "email": "jane@example.com", "born": "1978-11-02"}),
+        )?;
+        assert_eq!(vec![json!(1)], lookup(&eql, "email", vec![json!("john.doe@example.com")])?);
+        assert_eq!(vec![json!(1), json!(2)], lookup(&eql, "year", vec![json!(1978)])?);
+        assert_eq!(
+            vec![json!(2)],
+            lookup(&eql, "name", vec![json!("Jane Doe"), json!("1978-11-02")])?
+        );
+
+        // computed values follow updates and deletes
+        eql.insert(
+            "people",
+            2,
+            &json!({"first": "Jane", "last": "Smith", "email": "jane@example.com", "born": "1981-11-02"}),
+        )?;
+        assert_eq!(vec![json!(1)], lookup(&eql, "year", vec![json!(1978)])?);
+        assert_eq!(vec![json!(2)], lookup(&eql, "year", vec![json!(1981)])?);
+        assert_eq!(true, lookup(&eql, "name", vec![json!("Jane Doe")])?.is_empty());
+        eql.delete("people", 1)?;
+        assert_eq!(true, lookup(&eql, "email", vec![json!("john.doe@example.com")])?.is_empty());
+
+        // invalid expressions are rejected when the index is created
+        assert_eq!(true, eql.add_index("people", "bad", vec!["rec.value.email +"]).is_err());
+        assert_eq!(false, eql.metadata.indices["people"].contains_key("bad"));
+
+        // expressions failing on a record fail the insert
+        let r = eql.insert("people", 3, &json!({"first": "Max", "last": "Doe", "email": 12, "born": "1990-01-01"}));
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<IndexError>().ok()),
+                Some(IndexError::Expression { .. })
+            )
+        );
+    }
+    {
+        // expressions are kept in the metadata
+        let mut eql = EQLDB::open(path)?;
+        eql.insert(
+            "people",
+            4,
+            &json!({"first": "Max", "last": "Doe", "email": "MAX@example.com", "born": "1990-01-01"}),
+        )?;
+        assert_eq!(vec![json!(4)], lookup(&eql, "email", vec![json!("max@example.com")])?);
+        assert_eq!(vec![json!(4)], lookup(&eql, "year", vec![json!(1990)])?);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_covering_index() -> Result<()> {
+    let path = "test_covering_index.db";
+    let options = IndexOptions {
+        include: vec![String::from("/name"), String::from("/address/city")],
+        ..Default::default()
+    };
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert(
+            "customers",
+            1,
+            &json!({"name": "John", "country": "UK", "address": {"city": "London", "street": "Baker Street"}}),
+        )?;
+        eql.add_index_with("customers", "country", vec!["/country"], options.clone())?;
+        eql.insert("customers", 2, &json!({"name": "Jane", "country": "UK"}))?;
+        eql.insert("customers", 3, &json!({"name": "Max", "country": "DE", "address": {"city": "Berlin"}}))?;
+
+        let recs: Vec<EQLRecord> = eql
+            .execute(index_lookup("customers", "country", vec![json!("UK")]))?
+            .collect();
+        assert_eq!(
+            vec![
+                EQLRecord::new(json!(1), json!({"name": "John", "address": {"city": "London"}})),
+                EQLRecord::new(json!(2), json!({"name": "Jane"})),
+            ],
+            recs
+        );
+        // included values are merged with the named index key parts
+        let recs: Vec<EQLRecord> = eql
+            .execute(index_lookup_keys("customers", "country", vec![json!("DE")], vec!["country"]))?
+            .collect();
+        assert_eq!(
+            vec![EQLRecord::new(
+                json!(3),
+                json!({"country": "DE", "name": "Max", "address": {"city": "Berlin"}})
+            )],
+            recs
+        );
+
+        // included values follow updates
+        eql.insert("customers", 2, &json!({"name": "Jane Doe", "country": "UK"}))?;
+        let names: Vec<Value> = eql
+            .execute(index_lookup("customers", "country", vec![json!("UK")]))?
+            .map(|r| r.value["name"].clone())
+            .collect();
+        assert_eq!(vec![json!("John"), json!("Jane Doe")], names);
+
+        let r = eql.add_index_with(
+            "customers",
+            "bad",
+            vec!["/country"],
+            IndexOptions {
+                include: vec![String::from("rec.value.name")],
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            true,
+            matches!(
+                r.err().and_then(|e| e.downcast::<IndexError>().ok()),
+                Some(IndexError::InvalidInclude { .. })
+            )
+        );
+    }
+    {
+        let eql = EQLDB::open(path)?;
+        let recs: Vec<EQLRecord> = eql
+            .execute(index_lookup("customers", "country", vec![json!("DE")]))?
+            .collect();
+        assert_eq!(
+            vec![EQLRecord::new(json!(3), json!({"name": "Max", "address": {"city": "Berlin"}}))],
+            recs
+        );
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_index_range() -> Result<()> {
+    let path = "test_index_range.db";
+    let people = [
+        (1, json!({"name": "John", "country": "UK", "age": 43})),
+        (2, json!({"name": "Mary", "country": "UK", "age": 34.0})),
+        (3, json!({"name": "Max", "country": "DE", "age": 35})),
+        (4, json!({"name": "Jane", "country": "UK", "age": 40})),
+        (5, json!({"name": "Zoe", "country": "UK", "age": 9})),
+        (6, json!({"name": "Paul", "country": "UK"})),
+        (7, json!({"name": "Anna", "country": "UK", "age": 30})),
+    ];
+    fn keys<'a>(eql: &'a EQLDB, op: Operation<'a>) -> Result<Vec<Value>> {
+        Ok(eql.execute(op)?.map(|r| r.key).collect())
+    }
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        for (k, v) in people.iter() {
+            eql.insert("people", *k, v)?;
+        }
+        eql.add_index("people", "age", vec!["/age"])?;
+        eql.add_index("people", "country_age", vec!["/country", "/age"])?;
+        eql.add_index("people", "name", vec!["/name"])?;
+
+        // numbers sort by value, whatever their type
+        assert_eq!(
+            vec![json!(7), json!(2), json!(3), json!(4)],
+            keys(&eql, index_range("people", "age", vec![], IndexRange::between(json!(30), json!(40))))?
+        );
+        assert_eq!(
+            vec![json!(2), json!(3)],
+            keys(
+                &eql,
+                index_range(
+                    "people",
+                    "age",
+                    vec![],
+                    IndexRange {
+                        lower: Bound::Excluded(json!(30)),
+                        upper: Bound::Excluded(json!(40.0)),
+                    }
+                )
+            )?
+        );
+        // an open lower bound includes records without the value, which are indexed as null
+        assert_eq!(
+            vec![json!(6), json!(5)],
+            keys(&eql, index_range("people", "age", vec![], IndexRange::less_than(json!(30))))?
+        );
+        assert_eq!(
+            vec![json!(2), json!(3), json!(6), json!(5)],
+            keys(&eql, index_range("people", "name", vec![], IndexRange::at_least(json!("M"))))?
+        );
+
+        // ranges after an equality prefix, in descending order, with the index values
+        let recs: Vec<EQLRecord> = eql
+            .execute(index_range_with(
+                "people",
+                "country_age",
+                vec![json!("UK")],
+                vec![IndexRange::greater_than(json!(30))],
+                true,
+                vec!["country", "age"],
+            ))?
+            .collect();
+        assert_eq!(
+            vec![
+                EQLRecord::new(json!(1), json!({"country": "UK", "age": 43})),
+                EQLRecord::new(json!(4), json!({"country": "UK", "age": 40})),
+                EQLRecord::new(json!(2), json!({"country": "UK", "age": 34.0})),
+            ],
+            recs
+        );
+        // the following ranges filter the entries
+        assert_eq!(
+            vec![json!(3), json!(2), json!(4)],
+            keys(
+                &eql,
+                index_range_with(
+                    "people",
+                    "country_age",
+                    vec![],
+                    vec![IndexRange::all(), IndexRange::between(json!(34), json!(40))],
+                    false,
+                    Vec::<String>::new(),
+                )
+            )?
+        );
+
+        eql.insert("people", 5, &json!({"name": "Zoe", "country": "UK", "age": 39}))?;
+    }
+    {
+        let eql = EQLDB::open(path)?;
+        assert_eq!(
+            vec![json!(7), json!(2), json!(3), json!(5), json!(4)],
+            keys(&eql, index_range("people", "age", vec![], IndexRange::between(json!(30), json!(40))))?
+        );
+        let v: Vec<EQLRecord> = eql
+            .execute_script("index_range(people, country_age, [\"UK\"], [(>= 35, < 43)], true, [country, age])")?
+            .collect();
+        assert_eq!(
+            vec![
+                EQLRecord::new(json!(4), json!({"country": "UK", "age": 40})),
+                EQLRecord::new(json!(5), json!({"country": "UK", "age": 39})),
+            ],
+            v
+        );
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_skip_scan() -> Result<()> {
+    let path = "test_skip_scan.db";
+    let people = [
+        (1, json!({"name": "John", "country": "UK", "age": 40})),
+        (2, json!({"name": "Mary", "country": "UK", "age": 34})),
+        (3, json!({"name": "Max", "country": "DE", "age": 40})),
+        (4, json!({"name": "Jane", "country": "FR", "age": 25})),
+        (5, json!({"name": "Paul", "country": "UK"})),
+        (6, json!({"name": "Anna", "age": 40})),
+        (7, json!({"name": "Zoe", "country": "DE", "age": 38})),
+    ];
+    fn keys<'a>(eql: &'a EQLDB, op: Operation<'a>) -> Result<Vec<Value>> {
+        Ok(eql.execute(op)?.map(|r| r.key).collect())
+    }
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        for (k, v) in people.iter() {
+            eql.insert("people", *k, v)?;
+        }
+        eql.add_index("people", "country_age", vec!["/country", "/age"])?;
+
+        // any country, age 40
+        let recs: Vec<EQLRecord> = eql
+            .execute(index_lookup_with(
+                "people",
+                "country_age",
+                vec![IndexValue::Any, IndexValue::Equals(json!(40))],
+                vec!["country", "age"],
+            ))?
+            .collect();
+        assert_eq!(
+            vec![
+                EQLRecord::new(json!(6), json!({"country": null, "age": 40})),
+                EQLRecord::new(json!(3), json!({"country": "DE", "age": 40})),
+                EQLRecord::new(json!(1), json!({"country": "UK", "age": 40})),
+            ],
+            recs
+        );
+        // null is a value, not a wildcard
+        assert_eq!(
+            vec![json!(5)],
+            keys(
+                &eql,
+                index_lookup_with(
+                    "people",
+                    "country_age",
+                    vec![IndexValue::Any, IndexValue::Equals(Value::Null)],
+                    Vec::<String>::new(),
+                )
+            )?
+        );
+        assert_eq!(
+            vec![json!(6)],
+            keys(&eql, index_lookup("people", "country_age", vec![Value::Null]))?
+        );
+        assert_eq!(
+            true,
+            keys(&eql, index_lookup("people", "country_age", vec![Value::Null, json!(38)]))?.is_empty()
+        );
+        // a trailing wildcard is the same as a prefix
+        assert_eq!(
+            vec![json!(7), json!(3)],
+            keys(
+                &eql,
+                index_lookup_with(
+                    "people",
+                    "country_age",
+                    vec![IndexValue::Equals(json!("DE")), IndexValue::Any],
+                    Vec::<String>::new(),
+                )
+            )?
+        );
+
+        // ranges after a wildcard, in both orders
+        let range = |descending| {
+            index_range_with(
+                "people",
+                "country_age",
+                vec![],
+                vec![IndexRange::all(), IndexRange::between(json!(30), json!(38))],
+                descending,
+                Vec::<String>::new(),
+            )
+        };
+        assert_eq!(vec![json!(7), json!(2)], keys(&eql, range(false))?);
+        assert_eq!(vec![json!(2), json!(7)], keys(&eql, range(true))?);
+    }
+    {
+        let mut eql = EQLDB::open(path)?;
+        eql.insert("people", 8, &json!({"name": "Ines", "country": "FR", "age": 40.0}))?;
+        // script numbers are floats
+        let v: Vec<Value> = eql
+            .execute_script("index_lookup(people, country_age, [*, 40])")?
+            .map(|r| r.key)
+            .collect();
+        assert_eq!(vec![json!(8)], v);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_text_search() -> Result<()> {
+    let path = "test_text_search.db";
+    let products = [
+        (1, json!({"name": "Running shoes", "description": "Light shoes for road running"})),
+        (2, json!({"name": "Red socks", "description": "Warm wool socks"})),
+        (3, json!({"name": "Red shoes", "description": "Shoes to go with your red socks"})),
+        (4, json!({"name": "Blue hat", "description": "A hat for the rain"})),
+    ];
+    fn keys(eql: &EQLDB, query: &str) -> Result<Vec<Value>> {
+        Ok(eql.execute(text_search("products", "text", query))?.map(|r| r.key).collect())
+    }
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        eql.insert("products", 1, &products[0].1)?;
+        eql.add_text_index("products", "text", vec!["/name", "/description"], TextOptions::english())?;
+        for (k, v) in products.iter().skip(1) {
+            eql.insert("products", *k, v)?;
+        }
+
+        // stemmed and lowercased words, the most relevant first
+        let recs: Vec<EQLRecord> = eql.execute(text_search("products", "text", "SHOE"))?.collect();
+        assert_eq!(vec![json!(1), json!(3)], recs.iter().map(|r| r.key.clone()).collect::<Vec<_>>());
+        assert_eq!(true, recs[0].value["score"].as_f64().unwrap() > 0.0);
+        assert_eq!(true, recs[0].value["score"] == recs[1].value["score"]);
+        assert_eq!(vec![json!(3), json!(2)], keys(&eql, "red")?);
+        assert_eq!(vec![json!(3)], keys(&eql, "red AND shoes")?);
+        assert_eq!(vec![json!(3)], keys(&eql, "red shoes")?);
+        assert_eq!(4, keys(&eql, "shoes OR socks OR hat")?.len());
+        // blue and hat are rarer than red and shoes, so they weigh more
+        assert_eq!(vec![json!(4), json!(3)], keys(&eql, "(red OR blue) (shoes OR hat)")?);
+        // phrases, with stop words taking their place
+        assert_eq!(vec![json!(2)], keys(&eql, r#""wool socks""#)?);
+        assert_eq!(vec![json!(4)], keys(&eql, r#""hat for a rain""#)?);
+        assert_eq!(true, keys(&eql, r#""socks wool""#)?.is_empty());
+        // a phrase does not span indexed values
+        assert_eq!(true, keys(&eql, r#""running shoes light""#)?.is_empty());
+        assert_eq!(true, keys(&eql, "the")?.is_empty());
+
+        eql.insert("products", 2, &json!({"name": "Green socks", "description": "Warm socks"}))?;
+        eql.delete("products", 4)?;
+        assert_eq!(vec![json!(3)], keys(&eql, "red")?);
+        assert_eq!(true, keys(&eql, "hat")?.is_empty());
+
+        assert_eq!(
+            true,
+            matches!(
+                eql.execute(text_search("products", "text", "red OR")).err().unwrap().downcast_ref::<TextError>(),
+                Some(TextError::Syntax { .. })
+            )
+        );
+        eql.add_index("products", "name", vec!["/name"])?;
+        assert_eq!(
+            true,
+            matches!(
+                eql.execute(text_search("products", "name", "red")).err().unwrap().downcast_ref::<TextError>(),
+                Some(TextError::NotTextIndex { .. })
+            )
+        );
+        let r = eql.add_index_with(
+            "products",
+            "unique_text",
+            vec!["/name"],
+            IndexOptions {
+                unique: true,
+                text: Some(TextOptions::default()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            true,
+            matches!(r.unwrap_err().downcast_ref::<TextError>(), Some(TextError::InvalidIndex { .. }))
+        );
+    }
+    {
+        let eql = EQLDB::open(path)?;
+        let v: Vec<Value> = eql
+            .execute_script(r#"text_search(products, text, "socks OR \"running shoes\"")"#)?
+            .map(|r| r.key)
+            .collect();
+        assert_eq!(3, v.len());
+        assert_eq!(json!(1), v[0]);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}
+
+#[test]
+fn test_geo_index() -> Result<()> {
+    let path = "test_geo_index.db";
+    let stores = [
+        ("covent_garden", json!({"lat": 51.5117, "lon": -0.1240})),
+        ("kings_cross", json!({"lat": 51.5308, "lon": -0.1238})),
+        ("westminster", json!({"lat": 51.4995, "lon": -0.1248})),
+        ("paris", json!({"lat": 48.8566, "lon": 2.3522})),
+        ("fiji", json!({"lat": -17.8, "lon": 178.4})),
+        ("samoa", json!({"lat": -13.8, "lon": -172.1})),
+        ("online", json!({"url": "https://example.com"})),
+        ("unknown", json!({"lat": "51.5", "lon": "-0.12"})),
+    ];
+    fn keys<'a>(eql: &'a EQLDB, op: Operation<'a>) -> Result<Vec<Value>> {
+        Ok(eql.execute(op)?.map(|r| r.key).collect())
+    }
+    fn error<'a>(eql: &'a EQLDB, op: Operation<'a>) -> anyhow::Error {
+        eql.execute(op).err().unwrap()
+    }
+    {
+        let mut eql = EQLDB::open_new(path)?;
+        for (k, v) in stores.iter().take(3) {
+            eql.insert("stores", *k, v)?;
+        }
+        eql.add_geo_index("stores", "location", "/lat", "/lon")?;
+        for (k, v) in stores.iter().skip(3) {
+            eql.insert("stores", *k, v)?;
+        }
+
+        // by increasing distance from Trafalgar Square
+        let recs: Vec<EQLRecord> = eql
+            .execute(geo_within_radius("stores", "location", 51.5080, -0.1281, 2_000.0))?
+            .collect();
+        assert_eq!(
+            vec![json!("covent_garden"), json!("westminster")],
+            recs.iter().map(|r| r.key.clone()).collect::<Vec<_>>()
+        );
+        let d = recs[0].value["distance"].as_f64().unwrap();
+        assert_eq!(true, d > 400.0 && d < 600.0);
+        // by increasing distance from the center of the box
+        assert_eq!(
+            vec![json!("westminster"), json!("covent_garden"), json!("kings_cross")],
+            keys(&eql, geo_within_box("stores", "location", 51.4, -0.3, 51.6, 0.0))?
+        );
+        assert_eq!(
+            vec![json!("paris")],
+            keys(&eql, geo_within_box("stores", "location", 40.0, -10.0, 50.0, 10.0))?
+        );
+        // across the antimeridian
+        assert_eq!(
+            vec![json!("fiji"), json!("samoa")],
+            keys(&eql, geo_within_box("stores", "location", -20.0, 170.0, -10.0, -170.0))?
+        );
+        assert_eq!(
+            vec![json!("fiji")],
+            keys(&eql, geo_within_radius("stores", "location", -16.0, 179.9, 500_000.0))?
+        );
+        assert_eq!(
+            vec![json!("fiji"), json!("samoa")],
+            keys(&eql, geo_within_radius("stores", "location", -16.0, 179.9, 1_000_000.0))?
+        );
+
+        eql.insert("stores", "covent_garden", &json!({"lat": 48.8606, "lon": 2.3376}))?;
+        eql.delete("stores", "westminster")?;
+        assert_eq!(
+            true,
+            keys(&eql, geo_within_radius("stores", "location", 51.5080, -0.1281, 2_000.0))?.is_empty()
+        );
+        assert_eq!(
+            vec![json!("paris"), json!("covent_garden")],
+            keys(&eql, geo_within_radius("stores", "location", 48.8566, 2.3522, 2_000.0))?
+        );
+
+        assert_eq!(
+            true,
+            matches!(
+                error(&eql, geo_within_radius("stores", "location", 100.0, 0.0, 10.0)).downcast_ref::<GeoError>(),
+                Some(GeoError::InvalidCoordinates { .. })
+            )
+        );
+        assert_eq!(
+            true,
+            matches!(
+                error(&eql, geo_within_box("stores", "location", 10.0, 0.0, 0.0, 10.0)).downcast_ref::<GeoError>(),
+                Some(GeoError::InvalidBox { .. })
+            )
+        );
+        assert_eq!(
+            true,
+            matches!(
+                error(&eql, geo_within_radius("stores", "location", 0.0, 0.0, -1.0)).downcast_ref::<GeoError>(),
+                Some(GeoError::InvalidRadius { .. })
+            )
+        );
+        eql.add_index("stores", "lat", vec!["/lat"])?;
+        assert_eq!(
+            true,
+            matches!(
+                error(&eql, geo_within_radius("stores", "lat", 0.0, 0.0, 10.0)).downcast_ref::<GeoError>(),
+                Some(GeoError::NotGeoIndex { .. })
+            )
+        );
+        let r = eql.add_index_with(
+            "stores",
+            "lat_only",
+            vec!["/lat"],
+            IndexOptions {
+                geo: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            true,
+            matches!(r.unwrap_err().downcast_ref::<GeoError>(), Some(GeoError::Components { .. }))
+        );
+        let r = eql.add_index_with(
+            "stores",
+            "unique_location",
+            vec!["/lat", "/lon"],
+            IndexOptions {
+                geo: true,
+                unique: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            true,
+            matches!(r.unwrap_err().downcast_ref::<GeoError>(), Some(GeoError::InvalidIndex { .. }))
+        );
+    }
+    {
+        let eql = EQLDB::open(path)?;
+        let v: Vec<Value> = eql
+            .execute_script("geo_within_radius(stores, location, 48.8566, 2.3522, 2000)")?
+            .map(|r| r.key)
+            .collect();
+        assert_eq!(vec![json!("paris"), json!("covent_garden")], v);
+    }
+    EQLDB::destroy(path)?;
+    Ok(())
+}